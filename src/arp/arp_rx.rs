@@ -2,7 +2,7 @@ use {RxResult, RxError};
 use ethernet::{EthernetListener, EtherType, EtherTypes};
 
 use pnet::packet::Packet;
-use pnet::packet::arp::{ArpPacket, ArpOperations};
+use pnet::packet::arp::{ArpHardwareTypes, ArpPacket, ArpOperations};
 use pnet::packet::ethernet::EthernetPacket;
 use stack::StackInterfaceMsg;
 
@@ -19,6 +19,27 @@ impl ArpRx {
         ArpRx { listener: listener }
     }
 
+    /// Validates that the payload of `eth_pkg` is a complete Arp packet
+    /// mapping Ethernet addresses to IPv4 addresses.
+    fn get_arp_pkg<'a>(eth_pkg: &'a EthernetPacket) -> Result<ArpPacket<'a>, RxError> {
+        let eth_payload = eth_pkg.payload();
+        if eth_payload.len() < ArpPacket::minimum_packet_size() {
+            return Err(RxError::InvalidLength);
+        }
+        let arp_pkg = ArpPacket::new(eth_payload).unwrap();
+        if arp_pkg.get_hardware_type() != ArpHardwareTypes::Ethernet ||
+           arp_pkg.get_protocol_type() != EtherTypes::Ipv4 {
+            Err(RxError::InvalidContent)
+        } else if arp_pkg.get_hw_addr_len() != 6 || arp_pkg.get_proto_addr_len() != 4 {
+            Err(RxError::InvalidLength)
+        } else if arp_pkg.get_sender_hw_addr().0 & 0x01 != 0 {
+            // Sender can't have a multicast or broadcast address
+            Err(RxError::InvalidContent)
+        } else {
+            Ok(arp_pkg)
+        }
+    }
+
     fn handle_request(&mut self, arp_pkg: &ArpPacket) -> RxResult {
         let sender_mac = arp_pkg.get_sender_hw_addr();
         let sender_ip = arp_pkg.get_sender_proto_addr();
@@ -42,8 +63,7 @@ impl ArpRx {
 
 impl EthernetListener for ArpRx {
     fn recv(&mut self, _time: SystemTime, pkg: &EthernetPacket) -> RxResult {
-        let arp_pkg = ArpPacket::new(pkg.payload()).unwrap();
-        // TODO: Check all other fields so they are correct.
+        let arp_pkg = Self::get_arp_pkg(pkg)?;
         match arp_pkg.get_operation() {
             ArpOperations::Request => self.handle_request(&arp_pkg),
             ArpOperations::Reply => self.handle_reply(&arp_pkg),
@@ -55,3 +75,102 @@ impl EthernetListener for ArpRx {
         EtherTypes::Arp
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use RxError;
+    use ethernet::{EthernetListener, EtherTypes, MacAddr};
+    use stack::StackInterfaceMsg;
+
+    use pnet::packet::MutablePacket;
+    use pnet::packet::arp::{ArpHardwareType, ArpHardwareTypes, ArpOperations, ArpPacket,
+                            MutableArpPacket};
+    use pnet::packet::ethernet::{EthernetPacket, MutableEthernetPacket};
+
+    use std::net::Ipv4Addr;
+    use std::sync::mpsc;
+    use std::time::SystemTime;
+
+    #[test]
+    fn recv_request() {
+        let (tx, rx) = mpsc::channel();
+        let mut testee = ArpRx::new(tx);
+        let pkg = create_arp_packet(ArpPacket::minimum_packet_size(), |_| {});
+        testee.recv(SystemTime::now(), &pkg).unwrap();
+        match rx.try_recv().unwrap() {
+            StackInterfaceMsg::ArpRequest(sender_ip, sender_mac, target_ip) => {
+                assert_eq!(Ipv4Addr::new(10, 0, 0, 2), sender_ip);
+                assert_eq!(MacAddr::new(9, 8, 7, 6, 5, 4), sender_mac);
+                assert_eq!(Ipv4Addr::new(10, 0, 0, 1), target_ip);
+            }
+            _ => panic!("Expected ArpRequest"),
+        }
+    }
+
+    #[test]
+    fn recv_too_short() {
+        let (tx, rx) = mpsc::channel();
+        let mut testee = ArpRx::new(tx);
+        let pkg = create_arp_packet(ArpPacket::minimum_packet_size() - 1, |_| {});
+        assert_eq!(Err(RxError::InvalidLength), testee.recv(SystemTime::now(), &pkg));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn recv_invalid_types() {
+        let (tx, rx) = mpsc::channel();
+        let mut testee = ArpRx::new(tx);
+        let pkg = create_arp_packet(ArpPacket::minimum_packet_size(),
+                                    |arp_pkg| arp_pkg.set_hardware_type(ArpHardwareType(6)));
+        assert_eq!(Err(RxError::InvalidContent), testee.recv(SystemTime::now(), &pkg));
+        let pkg = create_arp_packet(ArpPacket::minimum_packet_size(),
+                                    |arp_pkg| arp_pkg.set_protocol_type(EtherTypes::Ipv6));
+        assert_eq!(Err(RxError::InvalidContent), testee.recv(SystemTime::now(), &pkg));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn recv_invalid_address_lengths() {
+        let (tx, rx) = mpsc::channel();
+        let mut testee = ArpRx::new(tx);
+        let pkg = create_arp_packet(ArpPacket::minimum_packet_size(),
+                                    |arp_pkg| arp_pkg.set_hw_addr_len(8));
+        assert_eq!(Err(RxError::InvalidLength), testee.recv(SystemTime::now(), &pkg));
+        let pkg = create_arp_packet(ArpPacket::minimum_packet_size(),
+                                    |arp_pkg| arp_pkg.set_proto_addr_len(16));
+        assert_eq!(Err(RxError::InvalidLength), testee.recv(SystemTime::now(), &pkg));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn recv_broadcast_sender() {
+        let (tx, rx) = mpsc::channel();
+        let mut testee = ArpRx::new(tx);
+        let pkg = create_arp_packet(ArpPacket::minimum_packet_size(), |arp_pkg| {
+            arp_pkg.set_sender_hw_addr(MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff))
+        });
+        assert_eq!(Err(RxError::InvalidContent), testee.recv(SystemTime::now(), &pkg));
+        assert!(rx.try_recv().is_err());
+    }
+
+    fn create_arp_packet<F>(arp_size: usize, f: F) -> EthernetPacket<'static>
+        where F: FnOnce(&mut MutableArpPacket)
+    {
+        let size = EthernetPacket::minimum_packet_size() + arp_size;
+        let mut eth_pkg = MutableEthernetPacket::owned(vec![0; size]).unwrap();
+        eth_pkg.set_ethertype(EtherTypes::Arp);
+        if let Some(mut arp_pkg) = MutableArpPacket::new(eth_pkg.payload_mut()) {
+            arp_pkg.set_hardware_type(ArpHardwareTypes::Ethernet);
+            arp_pkg.set_protocol_type(EtherTypes::Ipv4);
+            arp_pkg.set_hw_addr_len(6);
+            arp_pkg.set_proto_addr_len(4);
+            arp_pkg.set_operation(ArpOperations::Request);
+            arp_pkg.set_sender_hw_addr(MacAddr::new(9, 8, 7, 6, 5, 4));
+            arp_pkg.set_sender_proto_addr(Ipv4Addr::new(10, 0, 0, 2));
+            arp_pkg.set_target_proto_addr(Ipv4Addr::new(10, 0, 0, 1));
+            f(&mut arp_pkg);
+        }
+        eth_pkg.consume_to_immutable()
+    }
+}
@@ -25,6 +25,18 @@ impl ArpPayload {
         }
     }
 
+    /// Creates an Arp probe, as described in RFC 5227. Used to ask if anyone
+    /// else is using `target_ip` before claiming it.
+    pub fn probe(sender_mac: MacAddr, target_ip: Ipv4Addr) -> Self {
+        Self::request(sender_mac, Ipv4Addr::new(0, 0, 0, 0), target_ip)
+    }
+
+    /// Creates a gratuitous Arp announcement, as described in RFC 5227. Used
+    /// to tell everyone on the network that `ip` is claimed by `sender_mac`.
+    pub fn announcement(sender_mac: MacAddr, ip: Ipv4Addr) -> Self {
+        Self::request(sender_mac, ip, ip)
+    }
+
     pub fn reply(sender_mac: MacAddr,
                  sender_ip: Ipv4Addr,
                  target_mac: MacAddr,
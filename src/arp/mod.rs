@@ -1,10 +1,15 @@
 use ethernet::MacAddr;
 
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::mem;
 use std::net::Ipv4Addr;
 use std::sync::{Arc, Mutex};
 
 use std::sync::mpsc::{self, Receiver, Sender};
+use std::time::{Duration, Instant};
+
+use util::CacheMap;
 
 mod arp_rx;
 mod arp_tx;
@@ -12,34 +17,317 @@ mod arp_tx;
 pub use self::arp_rx::ArpRx;
 pub use self::arp_tx::{ArpTx, ArpBuilder, ArpPayload};
 
-#[derive(Default)]
+/// Default number of seconds an entry is considered reachable after it was
+/// last confirmed or used.
+pub const DEFAULT_REACHABLE_TIME_SECS: u64 = 30;
+
+/// Default number of seconds after which an entry that has not been
+/// confirmed or used expires and is removed from the table.
+pub const DEFAULT_EXPIRE_TIME_SECS: u64 = 300;
+
+/// Settings for how Arp requests are retransmitted while an address is being
+/// resolved, and how many outgoing packets may wait for the resolution.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpRequestConfig {
+    /// Time to wait for a reply before the first retransmission. The time is
+    /// doubled for every retransmission after that.
+    pub retransmit_time: Duration,
+
+    /// Maximum number of retransmissions after the first request.
+    pub max_retries: u32,
+
+    /// Total time from the first request until resolution is given up.
+    pub timeout: Duration,
+
+    /// Maximum number of outgoing packets queued while waiting for the
+    /// resolution. The oldest packet is dropped when the queue is full.
+    pub max_queued_packets: usize,
+}
+
+impl Default for ArpRequestConfig {
+    fn default() -> Self {
+        ArpRequestConfig {
+            retransmit_time: Duration::from_millis(500),
+            max_retries: 3,
+            timeout: Duration::from_secs(5),
+            max_queued_packets: 64,
+        }
+    }
+}
+
+impl ArpRequestConfig {
+    /// Returns the time to wait after retransmission number `retries`. It
+    /// doubles for every retry, but never grows past `timeout`.
+    pub fn retransmit_interval(&self, retries: u32) -> Duration {
+        let retransmit_ms = self.retransmit_time.as_secs() * 1000 +
+                            self.retransmit_time.subsec_nanos() as u64 / 1_000_000;
+        1u64.checked_shl(retries)
+            .and_then(|factor| retransmit_ms.checked_mul(factor))
+            .map(Duration::from_millis)
+            .map_or(self.timeout, |interval| cmp::min(interval, self.timeout))
+    }
+}
+
+/// Settings for the probing and announcing of addresses added to an
+/// interface, as described in RFC 5227.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpProbeConfig {
+    /// Maximum of the random time waited before the first probe, so hosts
+    /// starting at the same time don't probe at the same time.
+    pub probe_wait: Duration,
+
+    /// Number of probes sent before the address is claimed.
+    pub probe_num: u32,
+
+    /// Time between probes.
+    pub probe_interval: Duration,
+
+    /// Time to wait for conflicting packets after the last probe before the
+    /// address is claimed.
+    pub announce_wait: Duration,
+
+    /// Number of announcements sent after the address is claimed.
+    pub announce_num: u32,
+
+    /// Time between announcements.
+    pub announce_interval: Duration,
+}
+
+impl Default for ArpProbeConfig {
+    /// The values recommended by RFC 5227.
+    fn default() -> Self {
+        ArpProbeConfig {
+            probe_wait: Duration::from_secs(1),
+            probe_num: 3,
+            probe_interval: Duration::from_secs(1),
+            announce_wait: Duration::from_secs(2),
+            announce_num: 2,
+            announce_interval: Duration::from_secs(2),
+        }
+    }
+}
+
+/// What the `ArpTable` does with a MAC change for an IP that is detected as
+/// flip-flopping between MACs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArpUpdatePolicy {
+    /// Update the entry to the new MAC.
+    Accept,
+    /// Keep the existing entry until it expires.
+    Ignore,
+    /// Keep the existing entry and make it static. It then never expires or
+    /// changes until it is removed.
+    Lock,
+}
+
+/// Settings for detecting IPs whose MAC changes suspiciously often, a sign of
+/// Arp spoofing on the segment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpGuardConfig {
+    /// What to do with MAC changes for an IP that is flip-flopping.
+    pub policy: ArpUpdatePolicy,
+
+    /// Number of MAC changes for one IP within `flip_flop_window` after which
+    /// the IP is considered flip-flopping. A threshold of one applies the
+    /// policy to every change of an existing entry.
+    pub flip_flop_threshold: u32,
+
+    /// The time window in which MAC changes are counted.
+    pub flip_flop_window: Duration,
+}
+
+impl Default for ArpGuardConfig {
+    fn default() -> Self {
+        ArpGuardConfig {
+            policy: ArpUpdatePolicy::Accept,
+            flip_flop_threshold: 3,
+            flip_flop_window: Duration::from_secs(60),
+        }
+    }
+}
+
+/// Events about suspicious updates to an `ArpTable`. Delivered to the
+/// callback set with `ArpTable::set_event_callback`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArpEvent {
+    /// A learned entry changed from `old_mac` to `new_mac`.
+    MacChanged {
+        ip: Ipv4Addr,
+        old_mac: MacAddr,
+        new_mac: MacAddr,
+    },
+    /// The MAC of `ip` has changed at least `flip_flop_threshold` times
+    /// within the window. `new_mac` was handled according to `policy`.
+    FlipFlop {
+        ip: Ipv4Addr,
+        old_mac: MacAddr,
+        new_mac: MacAddr,
+        policy: ArpUpdatePolicy,
+    },
+    /// An Arp packet mapped the IP of a static entry to another MAC. The
+    /// static entry was kept.
+    StaticMismatch {
+        ip: Ipv4Addr,
+        mac: MacAddr,
+        new_mac: MacAddr,
+    },
+}
+
+/// What `ArpTable::insert` did with a mapping.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArpInsertResult {
+    /// The mapping was added, or the entry changed to the new MAC.
+    Changed,
+    /// The entry already had the MAC.
+    Unchanged,
+    /// A static entry or the `ArpGuardConfig` kept the entry at this MAC.
+    Rejected(MacAddr),
+}
+
+/// The state of an entry in the `ArpTable`, decided by how long ago it was
+/// last confirmed or used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArpEntryState {
+    /// The mapping was recently confirmed or used.
+    Reachable,
+    /// The mapping is still used for sending, but has not been confirmed
+    /// or used in a while.
+    Stale,
+    /// The mapping has timed out and will be removed from the table.
+    Expired,
+    /// The mapping is static and never expires.
+    Permanent,
+}
+
+/// A snapshot of one entry in an `ArpTable`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ArpEntry {
+    pub ip: Ipv4Addr,
+    pub mac: MacAddr,
+    pub state: ArpEntryState,
+    /// Time since the entry was added, confirmed or used.
+    pub age: Duration,
+}
+
 pub struct TableData {
-    pub table: HashMap<Ipv4Addr, MacAddr>,
+    pub table: CacheMap<Ipv4Addr, MacAddr>,
+    pub static_table: HashMap<Ipv4Addr, (Instant, MacAddr)>,
     pub listeners: HashMap<Ipv4Addr, Vec<Sender<MacAddr>>>,
+    pub reachable_time: Duration,
+    pub guard_config: ArpGuardConfig,
+    pub mac_changes: HashMap<Ipv4Addr, VecDeque<Instant>>,
+    /// Set when the table changed, until the change callback has been called.
+    pub change_pending: bool,
+    /// Events not yet given to the event callback.
+    pub pending_events: Vec<ArpEvent>,
 }
 
 impl TableData {
     pub fn new() -> Self {
         TableData {
-            table: HashMap::new(),
+            table: CacheMap::new(Duration::from_secs(DEFAULT_EXPIRE_TIME_SECS)),
+            static_table: HashMap::new(),
             listeners: HashMap::new(),
+            reachable_time: Duration::from_secs(DEFAULT_REACHABLE_TIME_SECS),
+            guard_config: ArpGuardConfig::default(),
+            mac_changes: HashMap::new(),
+            change_pending: false,
+            pending_events: vec![],
+        }
+    }
+
+    fn changed(&mut self) {
+        self.change_pending = true;
+    }
+
+    fn event(&mut self, event: ArpEvent) {
+        self.pending_events.push(event);
+    }
+
+    fn notify_listeners(&mut self, ip: Ipv4Addr, mac: MacAddr) {
+        if let Some(listeners) = self.listeners.remove(&ip) {
+            for listener in listeners {
+                listener.send(mac).unwrap_or(());
+            }
         }
     }
 }
 
+impl Default for TableData {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// The callbacks of an `ArpTable`. Kept outside of `TableData` so they can be
+/// called without the table being locked.
+#[derive(Default)]
+struct Callbacks {
+    change: Option<Box<Fn() + Send>>,
+    event: Option<Box<Fn(ArpEvent) + Send>>,
+}
+
 /// The main Arp table struct. Contains the actual data behind a `Mutex` so it
 /// can be shared
 /// with `ArpRx` instances.
+///
+/// Entries are either learned, and then age and expire, or static. Static
+/// entries are added with `insert_static` and stay until they are removed.
 #[derive(Clone)]
 pub struct ArpTable {
     data: Arc<Mutex<TableData>>,
+    callbacks: Arc<Mutex<Callbacks>>,
 }
 
 impl ArpTable {
     /// Creates a new `ArpTable` with no entries in it.
     pub fn new() -> ArpTable {
         let data = Arc::new(Mutex::new(TableData::new()));
-        ArpTable { data: data }
+        ArpTable {
+            data: data,
+            callbacks: Arc::new(Mutex::new(Callbacks::default())),
+        }
+    }
+
+    /// Sets a callback that is called every time a mapping in the table is
+    /// added, changed or removed. Used by the stack to invalidate existing
+    /// tx-objects. The callback is called after the table has been unlocked,
+    /// so it may read from the table, but must not change it.
+    pub fn set_change_callback<F>(&mut self, callback: F)
+        where F: Fn() + Send + 'static
+    {
+        self.callbacks.lock().unwrap().change = Some(Box::new(callback));
+    }
+
+    /// Sets a callback that receives all `ArpEvent`s detected by this table.
+    /// Like the change callback it's called after the table has been
+    /// unlocked.
+    pub fn set_event_callback<F>(&mut self, callback: F)
+        where F: Fn(ArpEvent) + Send + 'static
+    {
+        self.callbacks.lock().unwrap().event = Some(Box::new(callback));
+    }
+
+    pub fn guard_config(&self) -> ArpGuardConfig {
+        self.data.lock().unwrap().guard_config
+    }
+
+    /// Sets how MAC changes of learned entries are monitored and handled.
+    pub fn set_guard_config(&mut self, config: ArpGuardConfig) {
+        self.data.lock().unwrap().guard_config = config;
+    }
+
+    /// Sets for how long entries stay reachable and after how long they
+    /// expire, counted from when they were last confirmed or used.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `reachable_time` is larger than `expire_time`.
+    pub fn set_timeouts(&mut self, reachable_time: Duration, expire_time: Duration) {
+        assert!(reachable_time <= expire_time);
+        let mut data = self.data.lock().unwrap();
+        data.reachable_time = reachable_time;
+        data.table.set_timeout(expire_time);
     }
 
     /// Queries the table for a MAC. If it does not exist a request is sent and
@@ -47,24 +335,250 @@ impl ArpTable {
     /// until a reply has arrived
     pub fn get(&mut self, target_ip: Ipv4Addr) -> Result<MacAddr, Receiver<MacAddr>> {
         let mut data = self.data.lock().unwrap();
-        if let Some(mac) = data.table.get(&target_ip) {
-            return Ok(*mac);
+        if let Some(&(_, mac)) = data.static_table.get(&target_ip) {
+            return Ok(mac);
+        }
+        if let Some(mac) = data.table.get(&target_ip).cloned() {
+            data.table.refresh(&target_ip);
+            return Ok(mac);
         }
         Err(Self::add_listener(&mut data, target_ip))
     }
 
+    /// Returns the MAC for `ip` if it is in the table and has not expired.
+    /// Unlike `get` this does not refresh the entry nor wait for it to be
+    /// resolved.
+    pub fn lookup(&self, ip: Ipv4Addr) -> Option<MacAddr> {
+        let mut data = self.data.lock().unwrap();
+        if let Some(&(_, mac)) = data.static_table.get(&ip) {
+            return Some(mac);
+        }
+        data.table.get(&ip).cloned()
+    }
+
+    /// Returns the state of the entry for `ip`, or `None` if there is no
+    /// entry for it.
+    pub fn state(&self, ip: Ipv4Addr) -> Option<ArpEntryState> {
+        let data = self.data.lock().unwrap();
+        if data.static_table.contains_key(&ip) {
+            return Some(ArpEntryState::Permanent);
+        }
+        data.table.age(&ip).map(|age| Self::state_from_age(&data, age))
+    }
+
+    /// Returns a snapshot of all entries in the table, sorted by IP.
+    pub fn entries(&self) -> Vec<ArpEntry> {
+        let data = self.data.lock().unwrap();
+        let mut entries = data.static_table
+            .iter()
+            .map(|(ip, &(ref added, ref mac))| {
+                ArpEntry {
+                    ip: *ip,
+                    mac: *mac,
+                    state: ArpEntryState::Permanent,
+                    age: added.elapsed(),
+                }
+            })
+            .collect::<Vec<ArpEntry>>();
+        for (ip, age, mac) in data.table.iter() {
+            entries.push(ArpEntry {
+                ip: *ip,
+                mac: *mac,
+                state: Self::state_from_age(&data, age),
+                age: age,
+            });
+        }
+        entries.sort_by_key(|entry| entry.ip);
+        entries
+    }
+
     /// Manually insert an IP -> MAC mapping into this Arp table and notify all
-    /// listeners for that IP. Will return `true` if this insertion changed the
-    /// table.
-    pub fn insert(&mut self, ip: Ipv4Addr, mac: MacAddr) -> bool {
-        let mut data = self.data.lock().expect("Unable to lock Arp::table for writing");
-        let old_mac = data.table.insert(ip, mac);
-        if let Some(listeners) = data.listeners.remove(&ip) {
-            for listener in listeners {
-                listener.send(mac).unwrap_or(());
+    /// listeners for that IP. Does nothing if there is a static entry for
+    /// `ip` with another MAC, or if the `ArpGuardConfig` rejects the change.
+    /// The MAC kept in the table is then returned in
+    /// `ArpInsertResult::Rejected`.
+    pub fn insert(&mut self, ip: Ipv4Addr, mac: MacAddr) -> ArpInsertResult {
+        self.update(|data| {
+            if let Some(&(_, static_mac)) = data.static_table.get(&ip) {
+                if static_mac == mac {
+                    return ArpInsertResult::Unchanged;
+                }
+                data.event(ArpEvent::StaticMismatch {
+                    ip: ip,
+                    mac: static_mac,
+                    new_mac: mac,
+                });
+                return ArpInsertResult::Rejected(static_mac);
+            }
+            if let Some(current_mac) = data.table.get(&ip).cloned() {
+                if current_mac != mac && !Self::allow_change(data, ip, current_mac, mac) {
+                    return ArpInsertResult::Rejected(current_mac);
+                }
+            }
+            let old_mac = data.table.insert(ip, mac);
+            data.notify_listeners(ip, mac);
+            if old_mac != Some(mac) {
+                data.changed();
+                ArpInsertResult::Changed
+            } else {
+                ArpInsertResult::Unchanged
+            }
+        })
+    }
+
+    /// Inserts a static IP -> MAC mapping that never expires and is not
+    /// overwritten by incoming Arp packets. Replaces any learned entry for
+    /// `ip`. Will return `true` if this insertion changed the table.
+    pub fn insert_static(&mut self, ip: Ipv4Addr, mac: MacAddr) -> bool {
+        self.update(|data| {
+            let learned_mac = data.table.remove(&ip);
+            let static_mac =
+                data.static_table.insert(ip, (Instant::now(), mac)).map(|(_, mac)| mac);
+            data.notify_listeners(ip, mac);
+            let changed = learned_mac.or(static_mac) != Some(mac);
+            if changed {
+                data.changed();
+            }
+            changed
+        })
+    }
+
+    /// Removes the entry for `ip`, static or learned. Returns the MAC it
+    /// mapped to if there was an entry.
+    pub fn remove(&mut self, ip: Ipv4Addr) -> Option<MacAddr> {
+        self.update(|data| {
+            let static_mac = data.static_table.remove(&ip).map(|(_, mac)| mac);
+            let learned_mac = data.table.remove(&ip);
+            let old_mac = static_mac.or(learned_mac);
+            if old_mac.is_some() {
+                data.changed();
+            }
+            old_mac
+        })
+    }
+
+    /// Removes all learned entries from the table. Static entries are kept.
+    pub fn flush(&mut self) {
+        self.update(|data| {
+            if !data.table.is_empty() {
+                data.table.clear();
+                data.changed();
+            }
+        })
+    }
+
+    /// Returns `true` if anyone is waiting for `ip` to be resolved.
+    pub fn is_requested(&self, ip: Ipv4Addr) -> bool {
+        self.data.lock().unwrap().listeners.contains_key(&ip)
+    }
+
+    /// Gives up resolving `ip`. All listeners waiting for `ip` are dropped,
+    /// making their receivers return an error.
+    pub fn abort_request(&mut self, ip: Ipv4Addr) {
+        self.data.lock().unwrap().listeners.remove(&ip);
+    }
+
+    /// Removes all learned entries that have expired and returns their IPs.
+    pub fn expire(&mut self) -> Vec<Ipv4Addr> {
+        self.update(|data| {
+            let window = data.guard_config.flip_flop_window;
+            let old_changes = data.mac_changes
+                .iter()
+                .filter(|&(_, changes)| changes.back().map_or(true, |t| t.elapsed() > window))
+                .map(|(ip, _)| *ip)
+                .collect::<Vec<Ipv4Addr>>();
+            for ip in old_changes {
+                data.mac_changes.remove(&ip);
+            }
+            let expired = data.table.expire();
+            if !expired.is_empty() {
+                data.changed();
+            }
+            expired
+        })
+    }
+
+    /// Runs `f` with the table locked, then calls the change and event
+    /// callbacks about what `f` did after the table is unlocked again.
+    fn update<T, F>(&self, f: F) -> T
+        where F: FnOnce(&mut TableData) -> T
+    {
+        let (result, changed, events) = {
+            let mut data = self.data.lock().unwrap();
+            let result = f(&mut data);
+            let changed = mem::replace(&mut data.change_pending, false);
+            let events = mem::replace(&mut data.pending_events, vec![]);
+            (result, changed, events)
+        };
+        let callbacks = self.callbacks.lock().unwrap();
+        if let Some(ref callback) = callbacks.event {
+            for event in events {
+                callback(event);
+            }
+        }
+        if changed {
+            if let Some(ref callback) = callbacks.change {
+                callback();
+            }
+        }
+        result
+    }
+
+    /// Records a MAC change of the learned entry for `ip` and decides if it
+    /// should be applied, according to the `ArpGuardConfig`.
+    fn allow_change(data: &mut TableData,
+                    ip: Ipv4Addr,
+                    old_mac: MacAddr,
+                    new_mac: MacAddr)
+                    -> bool {
+        let config = data.guard_config;
+        let now = Instant::now();
+        let change_count = {
+            let changes = data.mac_changes.entry(ip).or_insert_with(VecDeque::new);
+            changes.push_back(now);
+            while changes.front().map_or(false, |t| now - *t > config.flip_flop_window) {
+                changes.pop_front();
+            }
+            changes.len()
+        };
+        if change_count < config.flip_flop_threshold as usize {
+            data.event(ArpEvent::MacChanged {
+                ip: ip,
+                old_mac: old_mac,
+                new_mac: new_mac,
+            });
+            return true;
+        }
+        warn!("{} is flip-flopping between {} and {}, possible Arp spoofing",
+              ip,
+              old_mac,
+              new_mac);
+        data.event(ArpEvent::FlipFlop {
+            ip: ip,
+            old_mac: old_mac,
+            new_mac: new_mac,
+            policy: config.policy,
+        });
+        match config.policy {
+            ArpUpdatePolicy::Accept => true,
+            ArpUpdatePolicy::Ignore => false,
+            ArpUpdatePolicy::Lock => {
+                data.table.remove(&ip);
+                data.mac_changes.remove(&ip);
+                data.static_table.insert(ip, (now, old_mac));
+                false
             }
         }
-        old_mac.is_none() || old_mac != Some(mac)
+    }
+
+    fn state_from_age(data: &TableData, age: Duration) -> ArpEntryState {
+        if age < data.reachable_time {
+            ArpEntryState::Reachable
+        } else if age < data.table.timeout() {
+            ArpEntryState::Stale
+        } else {
+            ArpEntryState::Expired
+        }
     }
 
     fn add_listener(data: &mut TableData, ip: Ipv4Addr) -> Receiver<MacAddr> {
@@ -79,3 +593,231 @@ impl Default for ArpTable {
         Self::new()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ethernet::MacAddr;
+
+    use std::net::Ipv4Addr;
+    use std::sync::mpsc;
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    lazy_static! {
+        static ref IP: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+        static ref MAC: MacAddr = MacAddr::new(1, 2, 3, 4, 5, 6);
+    }
+
+    #[test]
+    fn retransmit_interval() {
+        let config = ArpRequestConfig::default();
+        assert_eq!(Duration::from_millis(500), config.retransmit_interval(0));
+        assert_eq!(Duration::from_millis(2000), config.retransmit_interval(2));
+        assert_eq!(config.timeout, config.retransmit_interval(4));
+        assert_eq!(config.timeout, config.retransmit_interval(40));
+    }
+
+    #[test]
+    fn insert_changed() {
+        let mut testee = ArpTable::new();
+        assert_eq!(ArpInsertResult::Changed, testee.insert(*IP, *MAC));
+        assert_eq!(ArpInsertResult::Unchanged, testee.insert(*IP, *MAC));
+        assert_eq!(ArpInsertResult::Changed,
+                   testee.insert(*IP, MacAddr::new(0, 0, 0, 0, 0, 0)));
+    }
+
+    #[test]
+    fn states() {
+        let mut testee = ArpTable::new();
+        testee.set_timeouts(Duration::from_millis(50), Duration::from_millis(100));
+        assert_eq!(None, testee.state(*IP));
+
+        testee.insert(*IP, *MAC);
+        assert_eq!(Some(ArpEntryState::Reachable), testee.state(*IP));
+
+        sleep(Duration::from_millis(60));
+        assert_eq!(Some(ArpEntryState::Stale), testee.state(*IP));
+        assert_eq!(Ok(*MAC), testee.get(*IP).map_err(|_| ()));
+        assert_eq!(Some(ArpEntryState::Reachable), testee.state(*IP));
+
+        sleep(Duration::from_millis(110));
+        assert_eq!(Some(ArpEntryState::Expired), testee.state(*IP));
+        assert!(testee.get(*IP).is_err());
+        assert_eq!(vec![*IP], testee.expire());
+        assert_eq!(None, testee.state(*IP));
+    }
+
+    #[test]
+    fn abort_request() {
+        let mut testee = ArpTable::new();
+        let rx = testee.get(*IP).unwrap_err();
+        assert!(testee.is_requested(*IP));
+
+        testee.abort_request(*IP);
+        assert!(!testee.is_requested(*IP));
+        assert!(rx.recv().is_err());
+    }
+
+    #[test]
+    fn static_entry() {
+        let mut testee = ArpTable::new();
+        testee.set_timeouts(Duration::from_millis(10), Duration::from_millis(10));
+        testee.insert(*IP, MacAddr::new(0, 0, 0, 0, 0, 0));
+        assert!(testee.insert_static(*IP, *MAC));
+        assert_eq!(ArpInsertResult::Rejected(*MAC),
+                   testee.insert(*IP, MacAddr::new(0, 0, 0, 0, 0, 0)));
+        assert_eq!(ArpInsertResult::Unchanged, testee.insert(*IP, *MAC));
+        assert_eq!(Some(ArpEntryState::Permanent), testee.state(*IP));
+
+        sleep(Duration::from_millis(20));
+        assert!(testee.expire().is_empty());
+        assert_eq!(Ok(*MAC), testee.get(*IP).map_err(|_| ()));
+
+        testee.flush();
+        assert_eq!(Some(*MAC), testee.lookup(*IP));
+        assert_eq!(Some(*MAC), testee.remove(*IP));
+        assert_eq!(None, testee.state(*IP));
+    }
+
+    #[test]
+    fn entries() {
+        let ip2 = Ipv4Addr::new(10, 0, 0, 2);
+        let mut testee = ArpTable::new();
+        testee.insert(ip2, *MAC);
+        testee.insert_static(*IP, *MAC);
+
+        let entries = testee.entries();
+        assert_eq!(2, entries.len());
+        assert_eq!(*IP, entries[0].ip);
+        assert_eq!(ArpEntryState::Permanent, entries[0].state);
+        assert_eq!(ip2, entries[1].ip);
+        assert_eq!(*MAC, entries[1].mac);
+        assert_eq!(ArpEntryState::Reachable, entries[1].state);
+
+        testee.flush();
+        assert_eq!(1, testee.entries().len());
+    }
+
+    #[test]
+    fn change_callback() {
+        let (tx, rx) = mpsc::channel();
+        let mut testee = ArpTable::new();
+        // The table is unlocked while the callback runs
+        let table = testee.clone();
+        testee.set_change_callback(move || tx.send(table.lookup(*IP)).unwrap());
+
+        testee.insert(*IP, *MAC);
+        assert_eq!(Some(*MAC), rx.try_recv().unwrap());
+        testee.insert(*IP, *MAC);
+        assert!(rx.try_recv().is_err());
+
+        testee.insert(*IP, MacAddr::new(0, 0, 0, 0, 0, 0));
+        assert!(rx.try_recv().is_ok());
+        testee.remove(*IP);
+        assert!(rx.try_recv().is_ok());
+        assert!(testee.remove(*IP).is_none());
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn mac_changed_event() {
+        let (tx, rx) = mpsc::channel();
+        let mut testee = ArpTable::new();
+        testee.set_event_callback(move |event| tx.send(event).unwrap());
+        let mac2 = MacAddr::new(0, 0, 0, 0, 0, 1);
+
+        testee.insert(*IP, *MAC);
+        testee.insert(*IP, *MAC);
+        assert!(rx.try_recv().is_err());
+        assert_eq!(ArpInsertResult::Changed, testee.insert(*IP, mac2));
+        assert_eq!(ArpEvent::MacChanged {
+                       ip: *IP,
+                       old_mac: *MAC,
+                       new_mac: mac2,
+                   },
+                   rx.try_recv().unwrap());
+    }
+
+    #[test]
+    fn flip_flop_accept() {
+        let (tx, rx) = mpsc::channel();
+        let mut testee = ArpTable::new();
+        testee.set_event_callback(move |event| tx.send(event).unwrap());
+        testee.set_guard_config(guard_config(ArpUpdatePolicy::Accept));
+        let mac2 = MacAddr::new(0, 0, 0, 0, 0, 1);
+
+        testee.insert(*IP, *MAC);
+        assert_eq!(ArpInsertResult::Changed, testee.insert(*IP, mac2));
+        assert_matches!(rx.try_recv(), Ok(ArpEvent::MacChanged { .. }));
+        assert_eq!(ArpInsertResult::Changed, testee.insert(*IP, *MAC));
+        assert_eq!(ArpEvent::FlipFlop {
+                       ip: *IP,
+                       old_mac: mac2,
+                       new_mac: *MAC,
+                       policy: ArpUpdatePolicy::Accept,
+                   },
+                   rx.try_recv().unwrap());
+        assert_eq!(Some(*MAC), testee.lookup(*IP));
+    }
+
+    #[test]
+    fn flip_flop_ignore() {
+        let mut testee = ArpTable::new();
+        testee.set_guard_config(guard_config(ArpUpdatePolicy::Ignore));
+        let mac2 = MacAddr::new(0, 0, 0, 0, 0, 1);
+
+        testee.insert(*IP, *MAC);
+        assert_eq!(ArpInsertResult::Changed, testee.insert(*IP, mac2));
+        assert_eq!(ArpInsertResult::Rejected(mac2), testee.insert(*IP, *MAC));
+        assert_eq!(Some(mac2), testee.lookup(*IP));
+        assert_eq!(Some(ArpEntryState::Reachable), testee.state(*IP));
+    }
+
+    #[test]
+    fn flip_flop_lock() {
+        let (tx, rx) = mpsc::channel();
+        let mut testee = ArpTable::new();
+        testee.set_event_callback(move |event| tx.send(event).unwrap());
+        testee.set_guard_config(guard_config(ArpUpdatePolicy::Lock));
+        let mac2 = MacAddr::new(0, 0, 0, 0, 0, 1);
+
+        testee.insert(*IP, *MAC);
+        assert_eq!(ArpInsertResult::Changed, testee.insert(*IP, mac2));
+        assert_eq!(ArpInsertResult::Rejected(mac2), testee.insert(*IP, *MAC));
+        assert_eq!(Some(mac2), testee.lookup(*IP));
+        assert_eq!(Some(ArpEntryState::Permanent), testee.state(*IP));
+
+        assert_eq!(ArpInsertResult::Rejected(mac2), testee.insert(*IP, *MAC));
+        assert_matches!(rx.try_recv(), Ok(ArpEvent::MacChanged { .. }));
+        assert_matches!(rx.try_recv(), Ok(ArpEvent::FlipFlop { .. }));
+        assert_eq!(ArpEvent::StaticMismatch {
+                       ip: *IP,
+                       mac: mac2,
+                       new_mac: *MAC,
+                   },
+                   rx.try_recv().unwrap());
+    }
+
+    #[test]
+    fn flip_flop_window() {
+        let mut testee = ArpTable::new();
+        let mut config = guard_config(ArpUpdatePolicy::Ignore);
+        config.flip_flop_window = Duration::from_millis(10);
+        testee.set_guard_config(config);
+        let mac2 = MacAddr::new(0, 0, 0, 0, 0, 1);
+
+        testee.insert(*IP, *MAC);
+        assert_eq!(ArpInsertResult::Changed, testee.insert(*IP, mac2));
+        sleep(Duration::from_millis(20));
+        assert_eq!(ArpInsertResult::Changed, testee.insert(*IP, *MAC));
+    }
+
+    fn guard_config(policy: ArpUpdatePolicy) -> ArpGuardConfig {
+        ArpGuardConfig {
+            policy: policy,
+            flip_flop_threshold: 2,
+            flip_flop_window: Duration::from_secs(60),
+        }
+    }
+}
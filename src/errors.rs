@@ -86,6 +86,18 @@ pub enum RxError {
     /// When other packet content is invalid.
     InvalidContent,
 
+    /// When the version field of a packet is not the one of the protocol
+    /// parsing it.
+    InvalidVersion,
+
+    /// When the header length field of a packet is too small, or does not
+    /// fit within the packet.
+    InvalidHeaderLength,
+
+    /// When the source address of a packet is one no valid sender can have,
+    /// such as a multicast or broadcast address, or one of our own.
+    InvalidSourceAddress,
+
     /// Some error that was not covered by the more specific errors in this
     /// enum.
     Other(String),
@@ -111,6 +123,9 @@ impl Error for RxError {
             InvalidChecksum => "Invalid checksum in packet",
             InvalidLength => "Invalid length field in packet",
             InvalidContent => "Invalid content in packet",
+            InvalidVersion => "Invalid version in packet",
+            InvalidHeaderLength => "Invalid header length in packet",
+            InvalidSourceAddress => "Invalid source address in packet",
             Other(..) => "Other error",
         }
     }
@@ -124,6 +139,9 @@ impl Error for RxError {
 pub enum StackError {
     IllegalArgument,
     NoRouteToHost,
+    /// Returned when the link layer address of the destination, or the
+    /// gateway leading to it, could not be resolved in time.
+    HostUnreachable,
     InvalidInterface,
     TxError(TxError),
     IoError(io::Error),
@@ -147,6 +165,7 @@ impl From<StackError> for io::Error {
         match e {
             StackError::IllegalArgument => other("Illegal argument".to_owned()),
             StackError::NoRouteToHost => other("No route to host".to_owned()),
+            StackError::HostUnreachable => other("Host unreachable".to_owned()),
             StackError::InvalidInterface => other("Invalid interface".to_owned()),
             StackError::IoError(io_e) => io_e,
             StackError::TxError(txe) => txe.into(),
@@ -172,6 +191,7 @@ impl Error for StackError {
         match *self {
             IllegalArgument => "Illegal argument",
             NoRouteToHost => "No route to host",
+            HostUnreachable => "Host unreachable",
             InvalidInterface => "Invalid interface",
             TxError(..) => "Transmission error",
             IoError(..) => "IO error",
@@ -9,3 +9,30 @@ pub use self::ethernet_rx::{BasicEthernetListener, EthernetListener, EthernetRx}
 pub use self::ethernet_tx::{EthernetBuilder, EthernetFields, EthernetTx};
 pub use pnet::packet::ethernet::{EtherType, EtherTypes};
 pub use pnet::util::MacAddr;
+
+use std::net::Ipv4Addr;
+
+/// Returns the MAC address that IPv4 packets to the multicast group `group`
+/// are sent to. The lower 23 bits of the group are placed in the lower 23
+/// bits of 01:00:5e:00:00:00, RFC 1112 section 6.4.
+pub fn multicast_mac(group: Ipv4Addr) -> MacAddr {
+    let octets = group.octets();
+    MacAddr::new(0x01, 0x00, 0x5e, octets[1] & 0x7f, octets[2], octets[3])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn multicast_mac_mapping() {
+        assert_eq!(MacAddr::new(0x01, 0x00, 0x5e, 0x00, 0x00, 0xfb),
+                   multicast_mac(Ipv4Addr::new(224, 0, 0, 251)));
+        assert_eq!(MacAddr::new(0x01, 0x00, 0x5e, 0x7f, 0xff, 0xfa),
+                   multicast_mac(Ipv4Addr::new(239, 255, 255, 250)));
+        assert_eq!(multicast_mac(Ipv4Addr::new(224, 1, 2, 3)),
+                   multicast_mac(Ipv4Addr::new(225, 129, 2, 3)));
+    }
+}
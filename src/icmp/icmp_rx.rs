@@ -1,11 +1,13 @@
 use {RxError, RxResult};
-use ipv4::Ipv4Listener;
+use icmp::DestinationUnreachableCodes;
+use ipv4::{Ipv4Listener, PathMtuCache, plateau_below};
 
 use pnet::packet::Packet;
-use pnet::packet::icmp::{IcmpPacket, IcmpType};
+use pnet::packet::icmp::{IcmpPacket, IcmpType, IcmpTypes};
 use pnet::packet::ipv4::Ipv4Packet;
 
 use std::collections::HashMap;
+use std::net::Ipv4Addr;
 use std::sync::{Arc, Mutex};
 use std::time::SystemTime;
 
@@ -14,6 +16,12 @@ use std::time::SystemTime;
 pub trait IcmpListener: Send {
     /// Called by `IcmpRx` when there is a incoming packet for this listener
     fn recv(&mut self, time: SystemTime, packet: &Ipv4Packet);
+
+    /// Returns `false` when this listener no longer wants packets, making
+    /// `IcmpRx` remove it. Listeners are kept forever by default.
+    fn is_open(&self) -> bool {
+        true
+    }
 }
 
 /// Type binding for how the listeners in `IcmpRx` are structured.
@@ -22,31 +30,92 @@ pub type IcmpListenerLookup = HashMap<IcmpType, Vec<Box<IcmpListener>>>;
 /// Listener and parser of Icmp packets.
 pub struct IcmpRx {
     listeners: Arc<Mutex<IcmpListenerLookup>>,
+    path_mtu: Option<PathMtuCache>,
 }
 
 impl IcmpRx {
     /// Constructs a new `IcmpRx` with the given listeners.
     /// Casted before return to make it easy to add to the desired `Ipv4Rx`.
     pub fn new(listeners: Arc<Mutex<IcmpListenerLookup>>) -> IcmpRx {
-        IcmpRx { listeners: listeners }
+        IcmpRx {
+            listeners: listeners,
+            path_mtu: None,
+        }
+    }
+
+    /// Constructs a new `IcmpRx` that also lowers the path MTUs in
+    /// `path_mtu` when "fragmentation needed" messages arrive.
+    pub fn with_path_mtu_cache(listeners: Arc<Mutex<IcmpListenerLookup>>,
+                               path_mtu: PathMtuCache)
+                               -> IcmpRx {
+        IcmpRx {
+            listeners: listeners,
+            path_mtu: Some(path_mtu),
+        }
+    }
+
+    /// Reads the next hop MTU and the original destination from an Icmp
+    /// "fragmentation needed" message, as described in RFC 1191. Messages
+    /// about packets not sent from `local_ip` are ignored.
+    fn fragmentation_needed(local_ip: Ipv4Addr,
+                            icmp_pkg: &IcmpPacket)
+                            -> Option<(Ipv4Addr, usize)> {
+        let data = icmp_pkg.packet();
+        if data.len() < 8 + Ipv4Packet::minimum_packet_size() {
+            return None;
+        }
+        let next_hop_mtu = ((data[6] as usize) << 8) | data[7] as usize;
+        let orig_pkg = Ipv4Packet::new(&data[8..]).unwrap();
+        if orig_pkg.get_source() != local_ip {
+            return None;
+        }
+        let orig_length = orig_pkg.get_total_length() as usize;
+        let mtu = if next_hop_mtu == 0 || next_hop_mtu >= orig_length {
+            plateau_below(orig_length)
+        } else {
+            next_hop_mtu
+        };
+        Some((orig_pkg.get_destination(), mtu))
     }
 }
 
 impl Ipv4Listener for IcmpRx {
     fn recv(&mut self, time: SystemTime, ip_pkg: Ipv4Packet) -> RxResult {
-        let (icmp_type, _icmp_code) = {
+        if ip_pkg.payload().len() < IcmpPacket::minimum_packet_size() {
+            return Err(RxError::InvalidLength);
+        }
+        let (icmp_type, icmp_code) = {
             let icmp_pkg = IcmpPacket::new(ip_pkg.payload()).unwrap();
             (icmp_pkg.get_icmp_type(), icmp_pkg.get_icmp_code())
         };
+        if icmp_type == IcmpTypes::DestinationUnreachable &&
+           icmp_code == DestinationUnreachableCodes::FragmentationRequiredAndDFFlagSet {
+            if let Some(ref mut path_mtu) = self.path_mtu {
+                let icmp_pkg = IcmpPacket::new(ip_pkg.payload()).unwrap();
+                let local_ip = ip_pkg.get_destination();
+                if let Some((dst, mtu)) = Self::fragmentation_needed(local_ip, &icmp_pkg) {
+                    debug!("Path MTU to {} reported as {}", dst, mtu);
+                    path_mtu.update(dst, mtu);
+                }
+            }
+        }
         trace!("Icmp got a packet with {} bytes!", ip_pkg.payload().len());
         let mut listeners = self.listeners.lock().unwrap();
-        if let Some(type_listeners) = listeners.get_mut(&icmp_type) {
-            for listener in type_listeners {
-                listener.recv(time, &ip_pkg);
+        let closed = match listeners.get_mut(&icmp_type) {
+            Some(type_listeners) => {
+                type_listeners.retain(|listener| listener.is_open());
+                for listener in type_listeners.iter_mut() {
+                    listener.recv(time, &ip_pkg);
+                }
+                type_listeners.is_empty()
             }
-            Ok(())
-        } else {
+            None => true,
+        };
+        if closed {
+            listeners.remove(&icmp_type);
             Err(RxError::NoListener(format!("Icmp, {:?}", icmp_type)))
+        } else {
+            Ok(())
         }
     }
 }
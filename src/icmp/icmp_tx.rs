@@ -1,4 +1,5 @@
 use {Tx, Payload, CustomPayload, TxResult};
+use icmp::DestinationUnreachableCodes;
 use ipv4::{Ipv4Fields, IpNextHeaderProtocols};
 
 use pnet::packet::MutablePacket;
@@ -24,6 +25,50 @@ impl IcmpFields {
             build_header: build_header,
         }
     }
+
+    /// Fields for a Time Exceeded message. The payload should be the header
+    /// and first 8 bytes of payload of the packet it concerns.
+    pub fn time_exceeded(code: IcmpCode) -> Self {
+        Self::with_unused_header(IcmpTypes::TimeExceeded, code)
+    }
+
+    /// Fields for a Destination Unreachable message. The payload should be
+    /// the header and first 8 bytes of payload of the packet it concerns.
+    pub fn destination_unreachable(code: IcmpCode) -> Self {
+        Self::with_unused_header(IcmpTypes::DestinationUnreachable, code)
+    }
+
+    /// Fields for a "fragmentation needed" Destination Unreachable message,
+    /// carrying the MTU of the next hop as described in RFC 1191.
+    pub fn fragmentation_needed(next_hop_mtu: u16) -> Self {
+        let build_header = Box::new(move |pkg: &mut MutableIcmpPacket| {
+            let rest = pkg.payload_mut();
+            rest[0] = 0;
+            rest[1] = 0;
+            rest[2] = (next_hop_mtu >> 8) as u8;
+            rest[3] = next_hop_mtu as u8;
+        });
+        IcmpFields {
+            icmp_type: IcmpTypes::DestinationUnreachable,
+            icmp_code: DestinationUnreachableCodes::FragmentationRequiredAndDFFlagSet,
+            build_header: build_header,
+        }
+    }
+
+    /// Fields for a message type where the rest of the header is unused and
+    /// must be zero.
+    fn with_unused_header(icmp_type: IcmpType, code: IcmpCode) -> Self {
+        let build_header = Box::new(|pkg: &mut MutableIcmpPacket| {
+            for byte in pkg.payload_mut() {
+                *byte = 0;
+            }
+        });
+        IcmpFields {
+            icmp_type: icmp_type,
+            icmp_code: code,
+            build_header: build_header,
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -96,9 +141,11 @@ impl<'p, P: Payload<IcmpFields>> Payload<Ipv4Fields> for IcmpBuilder<'p, P> {
 mod tests {
 
     use super::*;
-    use icmp::{IcmpTypes, EchoCodes};
+    use {CustomPayload, Tx};
+    use icmp::{IcmpTypes, EchoCodes, DestinationUnreachableCodes, TimeExceededCodes};
 
     use pnet::packet::Packet;
+    use pnet::packet::icmp::IcmpPacket;
     use pnet::packet::icmp::echo_request::EchoRequestPacket;
 
     use testing::MockTx;
@@ -118,4 +165,43 @@ mod tests {
         assert_eq!(61128, echo_pkg.get_checksum()); // For ident&seq == 0
         assert_eq!([9, 55], echo_pkg.payload());
     }
+
+    fn send_fields(fields: IcmpFields, payload: &[u8]) -> Vec<u8> {
+        let (tx, read_handle) = MockTx::new();
+        let mut testee = IcmpTx::new(tx);
+        let mut payload = CustomPayload::new(fields, payload);
+        assert!(testee.send(&mut payload).unwrap().is_ok());
+        read_handle.try_recv().expect("Expected icmp packet")
+    }
+
+    #[test]
+    fn test_time_exceeded() {
+        let fields = IcmpFields::time_exceeded(TimeExceededCodes::FragmentReasemblyTimeExceeded);
+        let data = send_fields(fields, &[1, 2, 3]);
+        let icmp_pkg = IcmpPacket::new(&data).unwrap();
+        assert_eq!(IcmpTypes::TimeExceeded, icmp_pkg.get_icmp_type());
+        assert_eq!(TimeExceededCodes::FragmentReasemblyTimeExceeded,
+                   icmp_pkg.get_icmp_code());
+        assert_eq!([0, 0, 0, 0, 1, 2, 3], icmp_pkg.payload());
+    }
+
+    #[test]
+    fn test_destination_unreachable() {
+        let code = DestinationUnreachableCodes::DestinationPortUnreachable;
+        let data = send_fields(IcmpFields::destination_unreachable(code), &[1, 2, 3]);
+        let icmp_pkg = IcmpPacket::new(&data).unwrap();
+        assert_eq!(IcmpTypes::DestinationUnreachable, icmp_pkg.get_icmp_type());
+        assert_eq!(code, icmp_pkg.get_icmp_code());
+        assert_eq!([0, 0, 0, 0, 1, 2, 3], icmp_pkg.payload());
+    }
+
+    #[test]
+    fn test_fragmentation_needed() {
+        let data = send_fields(IcmpFields::fragmentation_needed(1492), &[1, 2, 3]);
+        let icmp_pkg = IcmpPacket::new(&data).unwrap();
+        assert_eq!(IcmpTypes::DestinationUnreachable, icmp_pkg.get_icmp_type());
+        assert_eq!(DestinationUnreachableCodes::FragmentationRequiredAndDFFlagSet,
+                   icmp_pkg.get_icmp_code());
+        assert_eq!([0, 0, 0x05, 0xd4, 1, 2, 3], icmp_pkg.payload());
+    }
 }
@@ -0,0 +1,150 @@
+use super::{IgmpVersion, MulticastMemberships};
+use super::{MEMBERSHIP_QUERY, V1_MEMBERSHIP_REPORT, V2_MEMBERSHIP_REPORT};
+use {RxError, RxResult};
+use ipv4::Ipv4Listener;
+
+use pnet::packet::Packet;
+use pnet::packet::ipv4::Ipv4Packet;
+use pnet::util::checksum;
+
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime};
+
+/// The max response time of IGMPv1 queries, that don't carry one.
+const V1_MAX_RESPONSE_TIME_SECS: u64 = 10;
+
+/// Listener and parser of IGMP packets. Updates the memberships of an
+/// interface with the queries and reports heard on the network.
+pub struct IgmpRx {
+    memberships: Arc<Mutex<MulticastMemberships>>,
+}
+
+impl IgmpRx {
+    pub fn new(memberships: Arc<Mutex<MulticastMemberships>>) -> IgmpRx {
+        IgmpRx { memberships: memberships }
+    }
+
+    /// Decodes the Max Resp Code of an IGMPv3 query, RFC 3376 section 4.1.1.
+    fn v3_max_response_time(code: u8) -> Duration {
+        let tenths = if code < 128 {
+            code as u64
+        } else {
+            let mantissa = (code & 0x0f) as u64;
+            let exponent = ((code >> 4) & 0x07) as u64;
+            (mantissa | 0x10) << (exponent + 3)
+        };
+        Duration::from_millis(tenths * 100)
+    }
+
+    /// Returns the version and max response time of a query. IGMPv3 queries
+    /// are at least 12 bytes and IGMPv1 queries have a max response code of
+    /// zero, RFC 3376 section 7.1.
+    fn query_version(data: &[u8]) -> (IgmpVersion, Duration) {
+        if data.len() >= 12 {
+            (IgmpVersion::V3, Self::v3_max_response_time(data[1]))
+        } else if data[1] == 0 {
+            (IgmpVersion::V1, Duration::from_secs(V1_MAX_RESPONSE_TIME_SECS))
+        } else {
+            (IgmpVersion::V2, Duration::from_millis(data[1] as u64 * 100))
+        }
+    }
+}
+
+impl Ipv4Listener for IgmpRx {
+    fn recv(&mut self, _time: SystemTime, ip_pkg: Ipv4Packet) -> RxResult {
+        let data = ip_pkg.payload();
+        if data.len() < 8 {
+            return Err(RxError::InvalidLength);
+        }
+        let csum = ((data[2] as u16) << 8) | data[3] as u16;
+        if checksum(data, 1) != csum {
+            return Err(RxError::InvalidChecksum);
+        }
+        let group = Ipv4Addr::new(data[4], data[5], data[6], data[7]);
+        let mut memberships = self.memberships.lock().unwrap();
+        match data[0] {
+            MEMBERSHIP_QUERY => {
+                let (version, max_response) = Self::query_version(data);
+                let group = if group == Ipv4Addr::new(0, 0, 0, 0) {
+                    None
+                } else {
+                    Some(group)
+                };
+                memberships.query(version, group, max_response);
+            }
+            V1_MEMBERSHIP_REPORT | V2_MEMBERSHIP_REPORT => memberships.report_heard(group),
+            _ => (),
+        }
+        Ok(())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use RxError;
+    use igmp::{IgmpMessage, IgmpVersion, MulticastMemberships};
+    use ipv4::Ipv4Listener;
+
+    use pnet::packet::MutablePacket;
+    use pnet::packet::ip::IpNextHeaderProtocols;
+    use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet};
+    use pnet::util::checksum;
+
+    use std::net::Ipv4Addr;
+    use std::sync::{Arc, Mutex};
+    use std::thread::sleep;
+    use std::time::{Duration, SystemTime};
+
+    fn igmp_packet(igmp: &[u8]) -> Vec<u8> {
+        let mut buffer = vec![0; 20 + igmp.len()];
+        {
+            let mut ip_pkg = MutableIpv4Packet::new(&mut buffer).unwrap();
+            ip_pkg.set_header_length(5);
+            ip_pkg.set_total_length(20 + igmp.len() as u16);
+            ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Igmp);
+            let payload = ip_pkg.payload_mut();
+            payload.copy_from_slice(igmp);
+            let csum = checksum(payload, 1);
+            payload[2] = (csum >> 8) as u8;
+            payload[3] = csum as u8;
+        }
+        buffer
+    }
+
+    #[test]
+    fn max_response_time() {
+        assert_eq!(Duration::from_millis(100), IgmpRx::v3_max_response_time(1));
+        assert_eq!(Duration::from_millis(12700), IgmpRx::v3_max_response_time(127));
+        // Mantissa 0, exponent 0: 0x10 << 3 tenths of a second
+        assert_eq!(Duration::from_millis(12800), IgmpRx::v3_max_response_time(0x80));
+    }
+
+    #[test]
+    fn v2_query() {
+        let group = Ipv4Addr::new(239, 1, 2, 3);
+        let memberships = Arc::new(Mutex::new(MulticastMemberships::new()));
+        memberships.lock().unwrap().join(group);
+        let mut testee = IgmpRx::new(memberships.clone());
+
+        // Max response time of 100 ms
+        let buffer = igmp_packet(&[0x11, 1, 0, 0, 0, 0, 0, 0]);
+        testee.recv(SystemTime::now(), Ipv4Packet::new(&buffer).unwrap()).unwrap();
+        sleep(Duration::from_millis(150));
+        let mut memberships = memberships.lock().unwrap();
+        assert_eq!(IgmpVersion::V2, memberships.version());
+        assert_eq!(vec![IgmpMessage::V2Report(group)], memberships.expire());
+    }
+
+    #[test]
+    fn invalid_checksum() {
+        let memberships = Arc::new(Mutex::new(MulticastMemberships::new()));
+        let mut testee = IgmpRx::new(memberships);
+        let mut buffer = igmp_packet(&[0x11, 0, 0, 0, 0, 0, 0, 0]);
+        buffer[22] ^= 0xff;
+        let result = testee.recv(SystemTime::now(), Ipv4Packet::new(&buffer).unwrap());
+        assert_matches!(result, Err(RxError::InvalidChecksum));
+    }
+}
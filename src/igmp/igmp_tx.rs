@@ -0,0 +1,120 @@
+use super::{LEAVE_GROUP, V1_MEMBERSHIP_REPORT, V2_MEMBERSHIP_REPORT, V3_MEMBERSHIP_REPORT};
+use super::{all_igmpv3_routers, all_routers};
+
+use pnet::util::checksum;
+
+use std::net::Ipv4Addr;
+
+/// Group record type reporting the current membership of a group, in
+/// response to a query. RFC 3376 section 4.2.12.
+pub const MODE_IS_EXCLUDE: u8 = 2;
+/// Group record type reporting that a group was left.
+pub const CHANGE_TO_INCLUDE_MODE: u8 = 3;
+/// Group record type reporting that a group was joined.
+pub const CHANGE_TO_EXCLUDE_MODE: u8 = 4;
+
+/// One group record in an IGMPv3 Membership Report. Only any-source multicast
+/// is supported, so the records never list any sources.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct GroupRecord {
+    pub record_type: u8,
+    pub group: Ipv4Addr,
+}
+
+/// An IGMP message sent by a host.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum IgmpMessage {
+    V1Report(Ipv4Addr),
+    V2Report(Ipv4Addr),
+    Leave(Ipv4Addr),
+    V3Report(Vec<GroupRecord>),
+}
+
+impl IgmpMessage {
+    /// Returns the address this message should be sent to, RFC 2236 and
+    /// RFC 3376.
+    pub fn destination(&self) -> Ipv4Addr {
+        match *self {
+            IgmpMessage::V1Report(group) |
+            IgmpMessage::V2Report(group) => group,
+            IgmpMessage::Leave(_) => all_routers(),
+            IgmpMessage::V3Report(_) => all_igmpv3_routers(),
+        }
+    }
+
+    /// Builds the message, checksum included.
+    pub fn build(&self) -> Vec<u8> {
+        let mut buffer = match *self {
+            IgmpMessage::V1Report(group) => Self::build_v2(V1_MEMBERSHIP_REPORT, group),
+            IgmpMessage::V2Report(group) => Self::build_v2(V2_MEMBERSHIP_REPORT, group),
+            IgmpMessage::Leave(group) => Self::build_v2(LEAVE_GROUP, group),
+            IgmpMessage::V3Report(ref records) => {
+                let mut buffer = vec![0; 8 + 8 * records.len()];
+                buffer[0] = V3_MEMBERSHIP_REPORT;
+                buffer[6] = (records.len() >> 8) as u8;
+                buffer[7] = records.len() as u8;
+                for (record, chunk) in records.iter().zip(buffer[8..].chunks_mut(8)) {
+                    chunk[0] = record.record_type;
+                    chunk[4..8].copy_from_slice(&record.group.octets());
+                }
+                buffer
+            }
+        };
+        let csum = checksum(&buffer, 1);
+        buffer[2] = (csum >> 8) as u8;
+        buffer[3] = csum as u8;
+        buffer
+    }
+
+    fn build_v2(igmp_type: u8, group: Ipv4Addr) -> Vec<u8> {
+        let mut buffer = vec![0; 8];
+        buffer[0] = igmp_type;
+        buffer[4..8].copy_from_slice(&group.octets());
+        buffer
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use igmp::{LEAVE_GROUP, V3_MEMBERSHIP_REPORT};
+
+    use pnet::util::checksum;
+
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn build_leave() {
+        let group = Ipv4Addr::new(239, 1, 2, 3);
+        let message = IgmpMessage::Leave(group);
+        assert_eq!(Ipv4Addr::new(224, 0, 0, 2), message.destination());
+        let buffer = message.build();
+        assert_eq!(&[LEAVE_GROUP, 0], &buffer[..2]);
+        assert_eq!(&[239, 1, 2, 3], &buffer[4..8]);
+        let csum = ((buffer[2] as u16) << 8) | buffer[3] as u16;
+        assert_eq!(checksum(&buffer, 1), csum);
+    }
+
+    #[test]
+    fn build_v3_report() {
+        let records = vec![GroupRecord {
+                               record_type: CHANGE_TO_EXCLUDE_MODE,
+                               group: Ipv4Addr::new(239, 1, 2, 3),
+                           },
+                           GroupRecord {
+                               record_type: MODE_IS_EXCLUDE,
+                               group: Ipv4Addr::new(224, 0, 0, 251),
+                           }];
+        let message = IgmpMessage::V3Report(records);
+        assert_eq!(Ipv4Addr::new(224, 0, 0, 22), message.destination());
+        let buffer = message.build();
+        assert_eq!(24, buffer.len());
+        assert_eq!(V3_MEMBERSHIP_REPORT, buffer[0]);
+        assert_eq!(&[0, 2], &buffer[6..8]);
+        assert_eq!(&[CHANGE_TO_EXCLUDE_MODE, 0, 0, 0, 239, 1, 2, 3], &buffer[8..16]);
+        assert_eq!(&[MODE_IS_EXCLUDE, 0, 0, 0, 224, 0, 0, 251], &buffer[16..24]);
+        let csum = ((buffer[2] as u16) << 8) | buffer[3] as u16;
+        assert_eq!(checksum(&buffer, 1), csum);
+    }
+}
@@ -0,0 +1,380 @@
+use super::{CHANGE_TO_EXCLUDE_MODE, CHANGE_TO_INCLUDE_MODE, GroupRecord, IgmpMessage,
+            MODE_IS_EXCLUDE, all_systems};
+
+use rand::{self, Rng};
+
+use std::collections::HashMap;
+use std::net::Ipv4Addr;
+use std::time::{Duration, Instant};
+
+/// How long an older version querier is assumed to be present after its last
+/// query. The Older Version Querier Present Timeout of RFC 3376 with the
+/// default robustness variable and query interval.
+const OLDER_QUERIER_TIMEOUT_SECS: u64 = 400;
+
+/// The default Robustness Variable of RFC 2236 and RFC 3376. Unsolicited
+/// reports and leaves are sent this many times in total.
+const ROBUSTNESS_VARIABLE: usize = 2;
+
+/// The Unsolicited Report Interval of IGMPv1 and IGMPv2, RFC 2236 section 8.10.
+const V2_UNSOLICITED_REPORT_INTERVAL_SECS: u64 = 10;
+
+/// The Unsolicited Report Interval of IGMPv3, RFC 3376 section 8.11.
+const V3_UNSOLICITED_REPORT_INTERVAL_SECS: u64 = 1;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum IgmpVersion {
+    V1,
+    V2,
+    V3,
+}
+
+struct Membership {
+    /// Number of joins not yet matched by a leave.
+    users: usize,
+    /// When a report is due as a response to a query.
+    report_at: Option<Instant>,
+}
+
+/// An unsolicited report or leave of a group that remains to be
+/// retransmitted.
+struct Retransmission {
+    message: IgmpMessage,
+    at: Instant,
+    remaining: usize,
+}
+
+/// The multicast groups joined on one interface and the state of the IGMP
+/// host protocol for them, RFC 2236 and RFC 3376. Shared between the stack,
+/// that joins and leaves groups, `IgmpRx`, that handles incoming queries, and
+/// the interface thread, that sends the reports when they are due.
+pub struct MulticastMemberships {
+    groups: HashMap<Ipv4Addr, Membership>,
+    older_querier: Option<(IgmpVersion, Instant)>,
+    /// When a report of all groups is due as a response to an IGMPv3 general
+    /// query.
+    general_report_at: Option<Instant>,
+    /// Retransmissions of the last unsolicited message sent about each group.
+    retransmissions: HashMap<Ipv4Addr, Retransmission>,
+}
+
+impl MulticastMemberships {
+    pub fn new() -> Self {
+        MulticastMemberships {
+            groups: HashMap::new(),
+            older_querier: None,
+            general_report_at: None,
+            retransmissions: HashMap::new(),
+        }
+    }
+
+    /// Returns the IGMP version reports are sent with. IGMPv3 unless a
+    /// query from an older querier was heard recently, RFC 3376 section 7.2.1.
+    pub fn version(&self) -> IgmpVersion {
+        match self.older_querier {
+            Some((version, until)) if Instant::now() < until => version,
+            _ => IgmpVersion::V3,
+        }
+    }
+
+    /// Returns `true` if packets to `group` should be received.
+    pub fn is_member(&self, group: Ipv4Addr) -> bool {
+        group == all_systems() || self.groups.contains_key(&group)
+    }
+
+    /// Returns the joined groups.
+    pub fn groups(&self) -> Vec<Ipv4Addr> {
+        self.groups.keys().cloned().collect()
+    }
+
+    /// Joins `group`. Every join must be matched by a call to `leave`.
+    /// Returns the report to send if the group was not joined before. The
+    /// report is retransmitted by `expire`. Membership of the all-systems
+    /// group is counted like others, but never reported.
+    pub fn join(&mut self, group: Ipv4Addr) -> Option<IgmpMessage> {
+        let version = self.version();
+        let first_join = {
+            let membership = self.groups.entry(group).or_insert(Membership {
+                users: 0,
+                report_at: None,
+            });
+            membership.users += 1;
+            membership.users == 1
+        };
+        if first_join && group != all_systems() {
+            let message = report(version, group, CHANGE_TO_EXCLUDE_MODE);
+            self.retransmit(group, &message);
+            Some(message)
+        } else {
+            None
+        }
+    }
+
+    /// Leaves `group`. Returns the message to send if this was the last
+    /// user of the group. The message is retransmitted by `expire`. IGMPv1
+    /// has no leave message.
+    pub fn leave(&mut self, group: Ipv4Addr) -> Option<IgmpMessage> {
+        let left = match self.groups.get_mut(&group) {
+            Some(membership) => {
+                membership.users -= 1;
+                membership.users == 0
+            }
+            None => return None,
+        };
+        if !left {
+            return None;
+        }
+        self.groups.remove(&group);
+        self.retransmissions.remove(&group);
+        if group == all_systems() {
+            return None;
+        }
+        let message = match self.version() {
+            IgmpVersion::V1 => None,
+            IgmpVersion::V2 => Some(IgmpMessage::Leave(group)),
+            IgmpVersion::V3 => Some(report(IgmpVersion::V3, group, CHANGE_TO_INCLUDE_MODE)),
+        };
+        if let Some(ref message) = message {
+            self.retransmit(group, message);
+        }
+        message
+    }
+
+    /// Handles a Membership Query of the given version. `group` is `None`
+    /// for general queries. Reports are scheduled at a random time within
+    /// `max_response`, unless one is already due sooner.
+    pub fn query(&mut self,
+                 version: IgmpVersion,
+                 group: Option<Ipv4Addr>,
+                 max_response: Duration) {
+        let now = Instant::now();
+        if version != IgmpVersion::V3 {
+            let until = now + Duration::from_secs(OLDER_QUERIER_TIMEOUT_SECS);
+            self.older_querier = Some((version, until));
+        }
+        let report_at = now + random_delay(max_response);
+        if group.is_none() && self.version() == IgmpVersion::V3 {
+            self.general_report_at = Some(earliest(self.general_report_at, report_at));
+            return;
+        }
+        for (joined_group, membership) in &mut self.groups {
+            if *joined_group == all_systems() {
+                continue;
+            }
+            if group.map_or(true, |group| group == *joined_group) {
+                membership.report_at = Some(earliest(membership.report_at, report_at));
+            }
+        }
+    }
+
+    /// Handles a report for `group` sent by another host. IGMPv1 and IGMPv2
+    /// hosts don't send their own pending report then, RFC 2236 section 3.
+    pub fn report_heard(&mut self, group: Ipv4Addr) {
+        if self.version() == IgmpVersion::V3 {
+            return;
+        }
+        if let Some(membership) = self.groups.get_mut(&group) {
+            membership.report_at = None;
+        }
+    }
+
+    /// Returns the reports and retransmissions that are due.
+    pub fn expire(&mut self) -> Vec<IgmpMessage> {
+        let now = Instant::now();
+        let version = self.version();
+        let mut messages = vec![];
+        let interval = unsolicited_report_interval(version);
+        let mut done = vec![];
+        for (&group, retransmission) in &mut self.retransmissions {
+            if retransmission.at <= now {
+                messages.push(retransmission.message.clone());
+                retransmission.remaining -= 1;
+                retransmission.at = now + random_delay(interval);
+                if retransmission.remaining == 0 {
+                    done.push(group);
+                }
+            }
+        }
+        for group in done {
+            self.retransmissions.remove(&group);
+        }
+        if self.general_report_at.map_or(false, |report_at| report_at <= now) {
+            self.general_report_at = None;
+            let records: Vec<GroupRecord> = self.groups
+                .keys()
+                .filter(|&&group| group != all_systems())
+                .map(|&group| {
+                    GroupRecord {
+                        record_type: MODE_IS_EXCLUDE,
+                        group: group,
+                    }
+                })
+                .collect();
+            if !records.is_empty() {
+                messages.push(IgmpMessage::V3Report(records));
+            }
+        }
+        for (&group, membership) in &mut self.groups {
+            if membership.report_at.map_or(false, |report_at| report_at <= now) {
+                membership.report_at = None;
+                messages.push(report(version, group, MODE_IS_EXCLUDE));
+            }
+        }
+        messages
+    }
+
+    /// Schedules the retransmissions of an unsolicited `message` about
+    /// `group`, replacing those of earlier messages about it. RFC 2236
+    /// section 3 and RFC 3376 section 5.1.
+    fn retransmit(&mut self, group: Ipv4Addr, message: &IgmpMessage) {
+        let interval = unsolicited_report_interval(self.version());
+        self.retransmissions.insert(group,
+                                    Retransmission {
+                                        message: message.clone(),
+                                        at: Instant::now() + random_delay(interval),
+                                        remaining: ROBUSTNESS_VARIABLE - 1,
+                                    });
+    }
+}
+
+impl Default for MulticastMemberships {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Creates a report of `group` in the given version. `record_type` is only
+/// used by IGMPv3.
+fn report(version: IgmpVersion, group: Ipv4Addr, record_type: u8) -> IgmpMessage {
+    match version {
+        IgmpVersion::V1 => IgmpMessage::V1Report(group),
+        IgmpVersion::V2 => IgmpMessage::V2Report(group),
+        IgmpVersion::V3 => {
+            IgmpMessage::V3Report(vec![GroupRecord {
+                                           record_type: record_type,
+                                           group: group,
+                                       }])
+        }
+    }
+}
+
+fn unsolicited_report_interval(version: IgmpVersion) -> Duration {
+    match version {
+        IgmpVersion::V3 => Duration::from_secs(V3_UNSOLICITED_REPORT_INTERVAL_SECS),
+        _ => Duration::from_secs(V2_UNSOLICITED_REPORT_INTERVAL_SECS),
+    }
+}
+
+fn random_delay(max: Duration) -> Duration {
+    let max_ms = max.as_secs() * 1000 + max.subsec_nanos() as u64 / 1_000_000;
+    if max_ms == 0 {
+        Duration::from_millis(0)
+    } else {
+        Duration::from_millis(rand::thread_rng().gen_range(0, max_ms))
+    }
+}
+
+fn earliest(current: Option<Instant>, new: Instant) -> Instant {
+    match current {
+        Some(current) if current < new => current,
+        _ => new,
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use igmp::{CHANGE_TO_EXCLUDE_MODE, CHANGE_TO_INCLUDE_MODE, GroupRecord, IgmpMessage,
+               MODE_IS_EXCLUDE};
+
+    use std::net::Ipv4Addr;
+    use std::time::{Duration, Instant};
+
+    lazy_static! {
+        static ref GROUP: Ipv4Addr = Ipv4Addr::new(239, 1, 2, 3);
+    }
+
+    fn v3_report(record_type: u8) -> IgmpMessage {
+        IgmpMessage::V3Report(vec![GroupRecord {
+                                       record_type: record_type,
+                                       group: *GROUP,
+                                   }])
+    }
+
+    #[test]
+    fn join_leave() {
+        let mut testee = MulticastMemberships::new();
+        assert!(!testee.is_member(*GROUP));
+        assert!(testee.is_member(Ipv4Addr::new(224, 0, 0, 1)));
+        assert_eq!(Some(v3_report(CHANGE_TO_EXCLUDE_MODE)), testee.join(*GROUP));
+        assert_eq!(None, testee.join(*GROUP));
+        assert!(testee.is_member(*GROUP));
+        assert_eq!(None, testee.leave(*GROUP));
+        assert_eq!(Some(v3_report(CHANGE_TO_INCLUDE_MODE)), testee.leave(*GROUP));
+        assert!(!testee.is_member(*GROUP));
+        assert_eq!(None, testee.leave(*GROUP));
+    }
+
+    #[test]
+    fn join_leave_all_systems() {
+        let all_systems = Ipv4Addr::new(224, 0, 0, 1);
+        let mut testee = MulticastMemberships::new();
+        assert_eq!(None, testee.join(all_systems));
+        assert_eq!(vec![all_systems], testee.groups());
+        testee.query(IgmpVersion::V3, None, Duration::from_millis(0));
+        testee.query(IgmpVersion::V2, Some(all_systems), Duration::from_millis(0));
+        assert!(testee.expire().is_empty());
+        assert_eq!(None, testee.leave(all_systems));
+        assert!(testee.groups().is_empty());
+        assert!(testee.is_member(all_systems));
+    }
+
+    #[test]
+    fn retransmit_unsolicited() {
+        let mut testee = MulticastMemberships::new();
+        let report = testee.join(*GROUP).unwrap();
+        testee.retransmissions.get_mut(&*GROUP).unwrap().at = Instant::now();
+        assert_eq!(vec![report], testee.expire());
+        assert!(testee.retransmissions.is_empty());
+
+        let leave = testee.leave(*GROUP).unwrap();
+        testee.retransmissions.get_mut(&*GROUP).unwrap().at = Instant::now();
+        assert_eq!(vec![leave], testee.expire());
+        assert!(testee.retransmissions.is_empty());
+
+        // Joining again replaces the retransmissions of the leave
+        testee.join(*GROUP);
+        testee.leave(*GROUP);
+        testee.join(*GROUP);
+        assert_eq!(1, testee.retransmissions.len());
+        let retransmission = &testee.retransmissions[&*GROUP];
+        assert_eq!(v3_report(CHANGE_TO_EXCLUDE_MODE), retransmission.message);
+    }
+
+    #[test]
+    fn general_query_v3() {
+        let mut testee = MulticastMemberships::new();
+        testee.join(*GROUP);
+        testee.retransmissions.clear();
+        testee.query(IgmpVersion::V3, None, Duration::from_millis(0));
+        assert_eq!(vec![v3_report(MODE_IS_EXCLUDE)], testee.expire());
+        assert!(testee.expire().is_empty());
+    }
+
+    #[test]
+    fn query_v2() {
+        let mut testee = MulticastMemberships::new();
+        testee.join(*GROUP);
+        testee.retransmissions.clear();
+        testee.query(IgmpVersion::V2, None, Duration::from_millis(0));
+        assert_eq!(IgmpVersion::V2, testee.version());
+        assert_eq!(vec![IgmpMessage::V2Report(*GROUP)], testee.expire());
+
+        testee.query(IgmpVersion::V2, Some(*GROUP), Duration::from_millis(0));
+        testee.report_heard(*GROUP);
+        assert!(testee.expire().is_empty());
+
+        assert_eq!(Some(IgmpMessage::Leave(*GROUP)), testee.leave(*GROUP));
+    }
+}
@@ -0,0 +1,32 @@
+use std::net::Ipv4Addr;
+
+mod igmp_rx;
+mod igmp_tx;
+mod memberships;
+
+pub use self::igmp_rx::IgmpRx;
+pub use self::igmp_tx::{CHANGE_TO_EXCLUDE_MODE, CHANGE_TO_INCLUDE_MODE, GroupRecord, IgmpMessage,
+                        MODE_IS_EXCLUDE};
+pub use self::memberships::{IgmpVersion, MulticastMemberships};
+
+pub const MEMBERSHIP_QUERY: u8 = 0x11;
+pub const V1_MEMBERSHIP_REPORT: u8 = 0x12;
+pub const V2_MEMBERSHIP_REPORT: u8 = 0x16;
+pub const LEAVE_GROUP: u8 = 0x17;
+pub const V3_MEMBERSHIP_REPORT: u8 = 0x22;
+
+/// The group all multicast capable hosts are members of. Membership of it is
+/// never reported.
+pub fn all_systems() -> Ipv4Addr {
+    Ipv4Addr::new(224, 0, 0, 1)
+}
+
+/// The group IGMPv2 Leave Group messages are sent to.
+pub fn all_routers() -> Ipv4Addr {
+    Ipv4Addr::new(224, 0, 0, 2)
+}
+
+/// The group IGMPv3 Membership Reports are sent to.
+pub fn all_igmpv3_routers() -> Ipv4Addr {
+    Ipv4Addr::new(224, 0, 0, 22)
+}
@@ -0,0 +1,181 @@
+use super::IpNextHeaderProtocol;
+
+use rand::{thread_rng, Rng};
+
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use util::CacheMap;
+
+/// Number of seconds the counter of a destination is kept after it was last
+/// used by `PerDestinationCounter`. Longer than any datagram is expected to
+/// stay in the network, so identifications are not reused while a datagram
+/// with the same identification can still be reassembled.
+pub const DEFAULT_IDENTIFICATION_TIMEOUT_SECS: u64 = 120;
+
+/// A strategy for picking the identification field of outgoing datagrams.
+/// All fragments of one datagram share the identification returned for it.
+pub trait IdentificationGenerator: Send {
+    /// Returns the identification of the next datagram from `src` to `dst`
+    /// carrying `protocol`.
+    fn next(&mut self, src: Ipv4Addr, dst: Ipv4Addr, protocol: IpNextHeaderProtocol) -> u16;
+}
+
+/// Picks a random identification for every datagram.
+pub struct RandomIdentification;
+
+impl IdentificationGenerator for RandomIdentification {
+    fn next(&mut self, _src: Ipv4Addr, _dst: Ipv4Addr, _protocol: IpNextHeaderProtocol) -> u16 {
+        thread_rng().gen()
+    }
+}
+
+/// A single counter shared by all destinations. Deterministic if given a
+/// start value, which makes it useful in tests.
+pub struct GlobalCounter {
+    next: u16,
+}
+
+impl GlobalCounter {
+    /// Creates a counter whose first identification is `start`.
+    pub fn new(start: u16) -> Self {
+        GlobalCounter { next: start }
+    }
+}
+
+impl Default for GlobalCounter {
+    /// Creates a counter starting at a random value.
+    fn default() -> Self {
+        Self::new(thread_rng().gen())
+    }
+}
+
+impl IdentificationGenerator for GlobalCounter {
+    fn next(&mut self, _src: Ipv4Addr, _dst: Ipv4Addr, _protocol: IpNextHeaderProtocol) -> u16 {
+        let identification = self.next;
+        self.next = self.next.wrapping_add(1);
+        identification
+    }
+}
+
+/// One counter per source, destination and protocol, as suggested by RFC 791
+/// and RFC 6864. Each counter starts at a random value and is forgotten when
+/// it has not been used for a while.
+pub struct PerDestinationCounter {
+    counters: CacheMap<(Ipv4Addr, Ipv4Addr, IpNextHeaderProtocol), u16>,
+}
+
+impl PerDestinationCounter {
+    pub fn new(timeout: Duration) -> Self {
+        PerDestinationCounter { counters: CacheMap::new(timeout) }
+    }
+}
+
+impl Default for PerDestinationCounter {
+    fn default() -> Self {
+        Self::new(Duration::from_secs(DEFAULT_IDENTIFICATION_TIMEOUT_SECS))
+    }
+}
+
+impl IdentificationGenerator for PerDestinationCounter {
+    fn next(&mut self, src: Ipv4Addr, dst: Ipv4Addr, protocol: IpNextHeaderProtocol) -> u16 {
+        let key = (src, dst, protocol);
+        let identification = self.counters.get_mut(&key).map(|counter| {
+            *counter = counter.wrapping_add(1);
+            *counter
+        });
+        match identification {
+            Some(identification) => {
+                self.counters.refresh(&key);
+                identification
+            }
+            None => {
+                self.counters.expire();
+                let identification = thread_rng().gen();
+                self.counters.insert(key, identification);
+                identification
+            }
+        }
+    }
+}
+
+/// Shared handle to an `IdentificationGenerator`. Owned by the stack and
+/// given to every `Ipv4Tx` it creates, so the generator outlives the
+/// tx-objects, that are recreated every time something in the stack changes.
+#[derive(Clone)]
+pub struct IdentificationSource {
+    generator: Arc<Mutex<Box<IdentificationGenerator>>>,
+}
+
+impl IdentificationSource {
+    pub fn new<G>(generator: G) -> Self
+        where G: IdentificationGenerator + 'static
+    {
+        IdentificationSource { generator: Arc::new(Mutex::new(Box::new(generator))) }
+    }
+
+    /// Replaces the generator. Affects everyone sharing this source.
+    pub fn set_generator<G>(&self, generator: G)
+        where G: IdentificationGenerator + 'static
+    {
+        *self.generator.lock().unwrap() = Box::new(generator);
+    }
+
+    pub fn next(&self, src: Ipv4Addr, dst: Ipv4Addr, protocol: IpNextHeaderProtocol) -> u16 {
+        self.generator.lock().unwrap().next(src, dst, protocol)
+    }
+}
+
+impl Default for IdentificationSource {
+    /// Creates a source with a `PerDestinationCounter`.
+    fn default() -> Self {
+        Self::new(PerDestinationCounter::default())
+    }
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ipv4::IpNextHeaderProtocols;
+
+    use std::net::Ipv4Addr;
+
+    lazy_static! {
+        static ref SRC: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 2);
+        static ref DST1: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 3);
+        static ref DST2: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 4);
+    }
+
+    #[test]
+    fn global_counter() {
+        let mut testee = GlobalCounter::new(::std::u16::MAX);
+        let udp = IpNextHeaderProtocols::Udp;
+        assert_eq!(::std::u16::MAX, testee.next(*SRC, *DST1, udp));
+        assert_eq!(0, testee.next(*SRC, *DST2, udp));
+        assert_eq!(1, testee.next(*SRC, *DST1, IpNextHeaderProtocols::Icmp));
+    }
+
+    #[test]
+    fn per_destination_counter() {
+        let mut testee = PerDestinationCounter::default();
+        let udp = IpNextHeaderProtocols::Udp;
+        let first1 = testee.next(*SRC, *DST1, udp);
+        let first2 = testee.next(*SRC, *DST2, udp);
+        assert_eq!(first1.wrapping_add(1), testee.next(*SRC, *DST1, udp));
+        assert_eq!(first2.wrapping_add(1), testee.next(*SRC, *DST2, udp));
+        assert_eq!(first1.wrapping_add(2), testee.next(*SRC, *DST1, udp));
+    }
+
+    #[test]
+    fn shared_source() {
+        let testee = IdentificationSource::new(GlobalCounter::new(5));
+        let clone = testee.clone();
+        let udp = IpNextHeaderProtocols::Udp;
+        assert_eq!(5, testee.next(*SRC, *DST1, udp));
+        assert_eq!(6, clone.next(*SRC, *DST1, udp));
+        clone.set_generator(GlobalCounter::new(100));
+        assert_eq!(100, testee.next(*SRC, *DST1, udp));
+    }
+}
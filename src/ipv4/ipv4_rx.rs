@@ -1,24 +1,28 @@
 
 
-use super::{MORE_FRAGMENTS, NO_FLAGS, IpNextHeaderProtocol};
+use super::{MORE_FRAGMENTS, NO_FLAGS, IpNextHeaderProtocol, Ipv4Network, is_directed_broadcast};
+use super::options;
 use {RxError, RxResult};
-use ethernet::{EthernetListener, EtherType, EtherTypes};
+use ethernet::{EthernetListener, EtherType, EtherTypes, MacAddr};
 
 use pnet::packet::Packet;
 use pnet::packet::ethernet::EthernetPacket;
 use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet, checksum};
 
-use std::collections::HashMap;
+use std::cmp;
+use std::collections::{HashMap, HashSet};
 use std::net::Ipv4Addr;
 use std::sync::{Arc, Mutex};
 use std::sync::mpsc::Sender;
-use std::time::SystemTime;
+use std::time::{Duration, Instant, SystemTime};
 
-use util::Buffer;
+use util::{Buffer, OverlapPolicy};
 
 /// Anyone interested in receiving IPv4 packets from `Ipv4` must implement this.
 pub trait Ipv4Listener: Send {
     /// Called by the library to deliver an `Ipv4Packet` to a listener.
+    /// The header options have been validated, use `parse_options` to read
+    /// them.
     fn recv(&mut self, time: SystemTime, packet: Ipv4Packet) -> RxResult;
 }
 
@@ -42,19 +46,319 @@ impl Ipv4Listener for BasicIpv4Listener {
     }
 }
 
-/// Type binding for how the listeners in `Ipv4Rx` are structured.
+/// Type binding for how the listeners in `Ipv4Rx` are structured. Listeners
+/// of the unspecified address 0.0.0.0 receive packets to any local
+/// destination that no listener of the destination itself accepted.
 pub type IpListenerLookup = HashMap<Ipv4Addr, HashMap<IpNextHeaderProtocol, Box<Ipv4Listener>>>;
 
+/// Destinations other than the addresses in the `IpListenerLookup` that
+/// `Ipv4Rx` receives packets to.
+#[derive(Debug, Clone, Default)]
+pub struct LocalAddresses {
+    /// Packets to the broadcast address of any of these networks are given to
+    /// the listeners of the local address in that network.
+    pub networks: Vec<Ipv4Network>,
+    /// Packets to any of these multicast groups are given to the listeners of
+    /// all local addresses.
+    pub multicast_groups: HashSet<Ipv4Addr>,
+    /// Local addresses that are not claimed yet, because they are being
+    /// probed for or another host was found using them. Packets to them are
+    /// dropped.
+    pub tentative: HashSet<Ipv4Addr>,
+}
+
+impl LocalAddresses {
+    fn is_directed_broadcast(&self, dst: Ipv4Addr) -> bool {
+        self.networks.iter().any(|net| is_directed_broadcast(net, dst))
+    }
+}
+
+/// Counters for incoming packets that were dropped because they failed the
+/// validation of RFC 1122 section 3.2.1.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Ipv4RxStats {
+    /// Packets with a version other than 4.
+    pub invalid_version: usize,
+    /// Packets with a header shorter than 20 bytes or longer than the packet.
+    pub invalid_header_length: usize,
+    /// Packets with a total length that does not fit the frame.
+    pub invalid_length: usize,
+    pub invalid_checksum: usize,
+    /// Packets with malformed header options.
+    pub invalid_options: usize,
+    /// Packets from an address no valid sender can have.
+    pub invalid_source: usize,
+}
+
 // Header fields that are used to identify fragments as belonging to the same
 // packet
 type FragmentIdent = (Ipv4Addr, Ipv4Addr, u16);
 
+/// Overlapping fragments with conflicting content are a known way to evade
+/// firewalls and intrusion detection. Such datagrams are dropped, while exact
+/// duplicates are accepted.
+const FRAGMENT_OVERLAP_POLICY: OverlapPolicy = OverlapPolicy::Reject;
+
+/// Settings for the reassembly of fragmented packets.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ReassemblyConfig {
+    /// Time from the first received fragment of a packet until its
+    /// reassembly is given up.
+    pub timeout: Duration,
+
+    /// Maximum number of bytes buffered for all incomplete packets together.
+    /// The oldest packets are dropped to stay below the limit.
+    pub max_memory: usize,
+
+    /// If an Icmp Time Exceeded message should be sent to the source of a
+    /// packet whose reassembly timed out. Only sent if the first fragment
+    /// was received, as described in RFC 792.
+    pub send_time_exceeded: bool,
+}
+
+impl Default for ReassemblyConfig {
+    fn default() -> Self {
+        ReassemblyConfig {
+            timeout: Duration::from_secs(30),
+            max_memory: 4 * 1024 * 1024,
+            send_time_exceeded: false,
+        }
+    }
+}
+
+/// Counters for fragmented packets that were dropped before they could be
+/// reassembled.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ReassemblyStats {
+    /// Packets dropped because not all fragments arrived in time.
+    pub timeouts: usize,
+    /// Packets dropped to stay below the memory limit.
+    pub evictions: usize,
+    /// Packets dropped because of invalid or conflicting fragments.
+    pub invalid: usize,
+}
+
+/// A packet whose reassembly timed out after its first fragment arrived.
+/// Holds what is needed to send an Icmp Time Exceeded message back to its
+/// source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReassemblyTimeout {
+    /// The MAC the first fragment was sent from.
+    pub src_mac: MacAddr,
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+    /// The header and first 8 bytes of payload of the first fragment.
+    pub data: Vec<u8>,
+}
+
+/// The parts of a fragmented packet received so far.
+struct FragmentBuffer {
+    started: Instant,
+    src_mac: MacAddr,
+    /// The header of the first fragment, once it has arrived.
+    header: Option<Vec<u8>>,
+    payload: Buffer,
+}
+
+impl FragmentBuffer {
+    fn new(src_mac: MacAddr) -> Self {
+        let max_payload = ::std::u16::MAX as usize - Ipv4Packet::minimum_packet_size();
+        FragmentBuffer {
+            started: Instant::now(),
+            src_mac: src_mac,
+            header: None,
+            payload: Buffer::new(max_payload, FRAGMENT_OVERLAP_POLICY),
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        self.header.is_some() && self.payload.is_complete()
+    }
+
+    fn memory(&self) -> usize {
+        self.header.as_ref().map_or(0, |header| header.len()) + self.payload.allocated()
+    }
+}
+
+/// Holds the fragments of packets being reassembled. Shared between the
+/// `Ipv4Rx` receiving the fragments and whoever is timing them out by
+/// calling `expire` periodically.
+pub struct Reassembler {
+    buffers: HashMap<FragmentIdent, FragmentBuffer>,
+    config: ReassemblyConfig,
+    stats: ReassemblyStats,
+    memory: usize,
+}
+
+impl Reassembler {
+    pub fn new(config: ReassemblyConfig) -> Self {
+        Reassembler {
+            buffers: HashMap::new(),
+            config: config,
+            stats: ReassemblyStats::default(),
+            memory: 0,
+        }
+    }
+
+    pub fn config(&self) -> ReassemblyConfig {
+        self.config
+    }
+
+    /// Changes the settings. Packets are dropped right away if the new
+    /// memory limit is exceeded.
+    pub fn set_config(&mut self, config: ReassemblyConfig) {
+        self.config = config;
+        self.evict(None);
+    }
+
+    pub fn stats(&self) -> ReassemblyStats {
+        self.stats
+    }
+
+    /// Returns the number of bytes currently buffered for incomplete packets.
+    pub fn memory_usage(&self) -> usize {
+        self.memory
+    }
+
+    /// Saves a packet fragment to a buffer for reassembly. Fragments can
+    /// arrive in any order. If the Ipv4Packet becomes complete with the
+    /// addition of `ip_pkg` then the complete reassembled packet is returned.
+    pub fn save_fragment(&mut self,
+                         src_mac: MacAddr,
+                         ip_pkg: Ipv4Packet)
+                         -> Result<Option<Ipv4Packet<'static>>, RxError> {
+        let ident = Self::get_fragment_identification(&ip_pkg);
+        let offset = ip_pkg.get_fragment_offset() as usize * 8;
+        let last = (ip_pkg.get_flags() & MORE_FRAGMENTS) == 0;
+        let (result, added_memory) = {
+            let fragments = self.buffers
+                .entry(ident)
+                .or_insert_with(|| FragmentBuffer::new(src_mac));
+            let memory_before = fragments.memory();
+            if offset == 0 && fragments.header.is_none() {
+                let header_length = ip_pkg.get_header_length() as usize * 4;
+                fragments.header = Some(ip_pkg.packet()[..header_length].to_vec());
+            }
+            let result = fragments.payload.push(offset, ip_pkg.payload(), last);
+            (result, fragments.memory() - memory_before)
+        };
+        self.memory += added_memory;
+        if let Err(e) = result {
+            debug!("Dropping fragmented Ipv4 packet {:?}: {:?}", ident, e);
+            self.remove(&ident);
+            self.stats.invalid += 1;
+            return Err(RxError::InvalidContent);
+        }
+        self.evict(Some(ident));
+        let complete = self.buffers.get(&ident).map_or(false, |f| f.is_complete());
+        if complete {
+            let fragments = self.remove(&ident).unwrap();
+            Self::reassemble(fragments).map(Some)
+        } else {
+            Ok(None)
+        }
+    }
+
+    /// Drops all packets whose reassembly has timed out. Returns the ones
+    /// that an Icmp Time Exceeded message should be sent for, according to
+    /// the `ReassemblyConfig`.
+    pub fn expire(&mut self) -> Vec<ReassemblyTimeout> {
+        let timeout = self.config.timeout;
+        let expired = self.buffers
+            .iter()
+            .filter(|&(_, fragments)| fragments.started.elapsed() >= timeout)
+            .map(|(ident, _)| *ident)
+            .collect::<Vec<FragmentIdent>>();
+        let mut timeouts = vec![];
+        for ident in expired {
+            debug!("Reassembly of Ipv4 packet {:?} timed out", ident);
+            let fragments = self.remove(&ident).unwrap();
+            self.stats.timeouts += 1;
+            if !self.config.send_time_exceeded {
+                continue;
+            }
+            if let Some(mut data) = fragments.header {
+                let payload_len = cmp::min(8, fragments.payload.len());
+                data.extend_from_slice(&fragments.payload[..payload_len]);
+                timeouts.push(ReassemblyTimeout {
+                    src_mac: fragments.src_mac,
+                    src: ident.0,
+                    dst: ident.1,
+                    data: data,
+                });
+            }
+        }
+        timeouts
+    }
+
+    /// Drops the oldest packets until the memory limit is no longer
+    /// exceeded. The packet `current` is dropped last.
+    fn evict(&mut self, current: Option<FragmentIdent>) {
+        while self.memory > self.config.max_memory {
+            let oldest = self.buffers
+                .iter()
+                .filter(|&(ident, _)| Some(*ident) != current)
+                .min_by_key(|&(_, fragments)| fragments.started)
+                .map(|(ident, _)| *ident)
+                .or(current);
+            match oldest.and_then(|ident| self.remove(&ident).map(|_| ident)) {
+                Some(ident) => {
+                    debug!("Evicting fragmented Ipv4 packet {:?}", ident);
+                    self.stats.evictions += 1;
+                }
+                None => break,
+            }
+        }
+    }
+
+    fn remove(&mut self, ident: &FragmentIdent) -> Option<FragmentBuffer> {
+        let fragments = self.buffers.remove(ident);
+        if let Some(ref fragments) = fragments {
+            self.memory -= fragments.memory();
+        }
+        fragments
+    }
+
+    /// Builds the complete packet from the header of the first fragment and
+    /// the reassembled payload.
+    fn reassemble(fragments: FragmentBuffer) -> Result<Ipv4Packet<'static>, RxError> {
+        let mut data = fragments.header.unwrap();
+        data.extend_from_slice(&fragments.payload.into_vec());
+        let len = data.len();
+        if len > ::std::u16::MAX as usize {
+            return Err(RxError::InvalidLength);
+        }
+        let mut ip_pkg = MutableIpv4Packet::owned(data).unwrap();
+        ip_pkg.set_flags(NO_FLAGS);
+        ip_pkg.set_total_length(len as u16);
+        let csum = checksum(&ip_pkg.to_immutable());
+        ip_pkg.set_checksum(csum);
+        Ok(ip_pkg.consume_to_immutable())
+    }
+
+    fn get_fragment_identification(ip_pkg: &Ipv4Packet) -> FragmentIdent {
+        let src = ip_pkg.get_source();
+        let dst = ip_pkg.get_destination();
+        let ident = ip_pkg.get_identification();
+        (src, dst, ident)
+    }
+}
+
 /// Listener and parser for IPv4 packets. Receives ethernet frames from the
 /// `EthernetRx` it's owned by and forwards them to the correct `Ipv4Listener`.
 /// Will cache and reassemble fragmented packets before forwarding them.
 pub struct Ipv4Rx {
     listeners: Arc<Mutex<IpListenerLookup>>,
-    buffers: HashMap<FragmentIdent, (Buffer, usize)>,
+    reassembler: Arc<Mutex<Reassembler>>,
+    /// Set when the reassembler is private to this `Ipv4Rx`. Timed out
+    /// fragments are then expired when new fragments arrive, since nothing
+    /// else does it.
+    expire_fragments: bool,
+    local_addresses: Arc<Mutex<LocalAddresses>>,
+    stats: Arc<Mutex<Ipv4RxStats>>,
+    /// Where to give packets that are not local, and the MAC of the interface
+    /// they must be sent to for being forwarded.
+    forwarder: Option<(MacAddr, Box<Ipv4Listener>)>,
 }
 
 impl Ipv4Rx {
@@ -62,34 +366,147 @@ impl Ipv4Rx {
     /// changed later. Returns the instance casted for easy addition to
     /// the `EthernetRx` listener `Vec`.
     pub fn new(listeners: Arc<Mutex<IpListenerLookup>>) -> Box<EthernetListener> {
+        let reassembler = Reassembler::new(ReassemblyConfig::default());
+        Self::create(listeners, Arc::new(Mutex::new(reassembler)), true)
+    }
+
+    /// Creates a new `Ipv4Rx` that reassembles fragmented packets in
+    /// `reassembler`. The caller is responsible for calling
+    /// `Reassembler::expire` regularly.
+    pub fn with_reassembler(listeners: Arc<Mutex<IpListenerLookup>>,
+                            reassembler: Arc<Mutex<Reassembler>>)
+                            -> Box<EthernetListener> {
+        Self::create(listeners, reassembler, false)
+    }
+
+    fn create(listeners: Arc<Mutex<IpListenerLookup>>,
+              reassembler: Arc<Mutex<Reassembler>>,
+              expire_fragments: bool)
+              -> Box<EthernetListener> {
         let this = Ipv4Rx {
             listeners: listeners,
-            buffers: HashMap::new(),
+            reassembler: reassembler,
+            expire_fragments: expire_fragments,
+            local_addresses: Arc::new(Mutex::new(LocalAddresses::default())),
+            stats: Arc::new(Mutex::new(Ipv4RxStats::default())),
+            forwarder: None,
         };
         Box::new(this) as Box<EthernetListener>
     }
 
-    /// Returns the Ipv4Packet contained in this EthernetPacket if it looks
-    /// valid
+    /// Creates a new `Ipv4Rx` that gives packets to destinations without
+    /// listeners to `forwarder`, instead of dropping them. Only packets in
+    /// frames sent to `mac`, the MAC of the interface, are forwarded and they
+    /// are not reassembled. Broadcasts and multicasts are received as
+    /// described by `local_addresses`. Dropped invalid packets are counted in
+    /// `stats`.
+    pub fn with_forwarder(listeners: Arc<Mutex<IpListenerLookup>>,
+                          reassembler: Arc<Mutex<Reassembler>>,
+                          local_addresses: Arc<Mutex<LocalAddresses>>,
+                          stats: Arc<Mutex<Ipv4RxStats>>,
+                          mac: MacAddr,
+                          forwarder: Box<Ipv4Listener>)
+                          -> Box<EthernetListener> {
+        let this = Ipv4Rx {
+            listeners: listeners,
+            reassembler: reassembler,
+            expire_fragments: false,
+            local_addresses: local_addresses,
+            stats: stats,
+            forwarder: Some((mac, forwarder)),
+        };
+        Box::new(this) as Box<EthernetListener>
+    }
+
+    /// Returns the Ipv4Packet contained in this EthernetPacket if its header
+    /// is valid
     fn get_ipv4_pkg<'a>(eth_pkg: &'a EthernetPacket) -> Result<Ipv4Packet<'a>, RxError> {
         let eth_payload = eth_pkg.payload();
         if eth_payload.len() < Ipv4Packet::minimum_packet_size() {
             return Err(RxError::InvalidLength);
         }
-        let total_length = {
+        let (version, header_length, total_length) = {
             let ip_pkg = Ipv4Packet::new(eth_payload).unwrap();
-            ip_pkg.get_total_length() as usize
+            (ip_pkg.get_version(),
+             ip_pkg.get_header_length() as usize * 4,
+             ip_pkg.get_total_length() as usize)
         };
+        if version != 4 {
+            return Err(RxError::InvalidVersion);
+        }
         if total_length > eth_payload.len() || total_length < Ipv4Packet::minimum_packet_size() {
-            Err(RxError::InvalidLength)
+            return Err(RxError::InvalidLength);
+        }
+        if header_length < Ipv4Packet::minimum_packet_size() || header_length > total_length {
+            return Err(RxError::InvalidHeaderLength);
+        }
+        let ip_pkg = Ipv4Packet::new(&eth_payload[..total_length]).unwrap();
+        if ip_pkg.get_checksum() != checksum(&ip_pkg) {
+            Err(RxError::InvalidChecksum)
         } else {
-            let ip_pkg = Ipv4Packet::new(&eth_payload[..total_length]).unwrap();
-            if ip_pkg.get_checksum() != checksum(&ip_pkg) {
-                Err(RxError::InvalidChecksum)
-            } else {
-                Ok(ip_pkg)
+            Ok(ip_pkg)
+        }
+    }
+
+    /// Returns the Ipv4Packet contained in this EthernetPacket if it passes
+    /// the ingress validation of RFC 1122 section 3.2.1. Dropped packets are
+    /// counted in the stats.
+    fn validate<'a>(&self, eth_pkg: &'a EthernetPacket) -> Result<Ipv4Packet<'a>, RxError> {
+        let ip_pkg = match Self::get_ipv4_pkg(eth_pkg) {
+            Ok(ip_pkg) => ip_pkg,
+            Err(e) => {
+                let mut stats = self.stats.lock().unwrap();
+                match e {
+                    RxError::InvalidVersion => stats.invalid_version += 1,
+                    RxError::InvalidHeaderLength => stats.invalid_header_length += 1,
+                    RxError::InvalidChecksum => stats.invalid_checksum += 1,
+                    _ => stats.invalid_length += 1,
+                }
+                return Err(e);
             }
+        };
+        if let Err(e) = options::parse_options(&ip_pkg) {
+            self.stats.lock().unwrap().invalid_options += 1;
+            return Err(e);
+        }
+        let src = ip_pkg.get_source();
+        if !self.is_valid_source(src) {
+            debug!("Dropping Ipv4 packet from invalid source {}", src);
+            self.stats.lock().unwrap().invalid_source += 1;
+            return Err(RxError::InvalidSourceAddress);
+        }
+        Ok(ip_pkg)
+    }
+
+    /// Returns `false` if `src` can't be the source of a packet arriving from
+    /// the network, RFC 1122 section 3.2.1.3. That is multicast, broadcast,
+    /// loopback and reserved addresses, and our own addresses. The
+    /// unspecified address is valid, it's used by hosts without an address.
+    fn is_valid_source(&self, src: Ipv4Addr) -> bool {
+        if src.is_multicast() || src.is_loopback() || src.octets()[0] >= 240 {
+            return false;
+        }
+        if src != unspecified() && self.listeners.lock().unwrap().contains_key(&src) {
+            return false;
+        }
+        !self.local_addresses.lock().unwrap().is_directed_broadcast(src)
+    }
+
+    /// Returns `true` if `dst` is a local address, a broadcast address of
+    /// one of the local networks or a joined multicast group.
+    fn is_local(&self, dst: Ipv4Addr) -> bool {
+        if dst == unspecified() {
+            return false;
+        }
+        let local_addresses = self.local_addresses.lock().unwrap();
+        if local_addresses.tentative.contains(&dst) {
+            return false;
+        }
+        if dst.is_broadcast() || self.listeners.lock().unwrap().contains_key(&dst) {
+            return true;
         }
+        local_addresses.is_directed_broadcast(dst) ||
+        local_addresses.multicast_groups.contains(&dst)
     }
 
     fn is_fragment(ip_pkg: &Ipv4Packet) -> bool {
@@ -98,92 +515,111 @@ impl Ipv4Rx {
         mf || offset
     }
 
-    /// Saves a packet fragment to a buffer for reassembly. If the Ipv4Packet
-    /// becomes complete with the addition of `ip_pkg` then the complete
-    /// reassembled packet is returned in a Buffer.
-    fn save_fragment(&mut self,
-                     ip_pkg: Ipv4Packet)
-                     -> Result<Option<Ipv4Packet<'static>>, RxError> {
-        let ident = Self::get_fragment_identification(&ip_pkg);
-        if !self.buffers.contains_key(&ident) {
-            try!(self.start_new_fragment(ip_pkg, ident));
-            Ok(None)
-        } else {
-            let pkg_done = {
-                let &mut (ref mut buffer, ref mut total_length) =
-                    self.buffers.get_mut(&ident).unwrap();
-                let offset = Ipv4Packet::minimum_packet_size() +
-                             ip_pkg.get_fragment_offset() as usize * 8;
-                // Check if this is the last fragment
-                if (ip_pkg.get_flags() & MORE_FRAGMENTS) == 0 {
-                    if *total_length != 0 {
-                        return Err(RxError::InvalidContent);
-                    } else {
-                        *total_length = offset + ip_pkg.payload().len();
-                    }
-                }
-                match buffer.push(offset, ip_pkg.payload()) {
-                    Ok(i) => i == *total_length,
-                    Err(_) => {
-                        return Err(RxError::InvalidContent);
-                    }
-                }
-            };
-            if pkg_done {
-                let (buffer, len) = self.buffers.remove(&ident).unwrap();
-                let mut ip_pkg = MutableIpv4Packet::owned(buffer.into_vec()).unwrap();
-                ip_pkg.set_flags(NO_FLAGS);
-                ip_pkg.set_total_length(len as u16);
-                let csum = checksum(&ip_pkg.to_immutable());
-                ip_pkg.set_checksum(csum);
-                Ok(Some(ip_pkg.consume_to_immutable()))
-            } else {
-                Ok(None)
-            }
-        }
-    }
-
-    fn start_new_fragment(&mut self, ip_pkg: Ipv4Packet, ident: FragmentIdent) -> RxResult {
-        if ip_pkg.get_fragment_offset() == 0 {
-            let mut buffer = Buffer::new(::std::u16::MAX as usize);
-            buffer.push(0, ip_pkg.packet()).unwrap();
-            self.buffers.insert(ident, (buffer, 0));
-            Ok(())
+    /// Returns the local addresses that should receive a packet to `dst`.
+    /// Packets to the limited broadcast address and joined multicast groups
+    /// are received by all local addresses, and subnet-directed broadcasts by
+    /// the address in that subnet. Tentative addresses receive nothing.
+    fn local_destinations(&self, dst: Ipv4Addr) -> Vec<Ipv4Addr> {
+        let local_addresses = self.local_addresses.lock().unwrap();
+        let to_all = dst.is_broadcast() || local_addresses.multicast_groups.contains(&dst);
+        let mut destinations: Vec<Ipv4Addr> = if to_all {
+            self.listeners
+                .lock()
+                .unwrap()
+                .keys()
+                .cloned()
+                .filter(|ip| *ip != unspecified())
+                .collect()
         } else {
-            Err(RxError::InvalidContent)
+            local_addresses.networks
+                .iter()
+                .filter(|net| is_directed_broadcast(net, dst))
+                .map(|net| net.ip())
+                .collect()
+        };
+        if destinations.is_empty() && !to_all {
+            destinations.push(dst);
         }
+        destinations.retain(|ip| !local_addresses.tentative.contains(ip));
+        destinations
     }
 
-    fn get_fragment_identification(ip_pkg: &Ipv4Packet) -> FragmentIdent {
-        let src = ip_pkg.get_source();
-        let dst = ip_pkg.get_destination();
-        let ident = ip_pkg.get_identification();
-        (src, dst, ident)
-    }
-
-    /// Forwards a complete packet to its listener
+    /// Forwards a complete packet to its listeners. Packets to local
+    /// destinations are also given to the listener of 0.0.0.0. Protocols
+    /// where only one listener may take a packet, like Udp, never have the
+    /// same port bound both there and on an address.
     fn forward(&self, time: SystemTime, ip_pkg: Ipv4Packet) -> RxResult {
         let dest_ip = ip_pkg.get_destination();
         let next_level_protocol = ip_pkg.get_next_level_protocol();
         trace!("Ipv4 got a packet to {}!", dest_ip);
+        let destinations = self.local_destinations(dest_ip);
+        let local = self.is_local(dest_ip);
         let mut listeners = self.listeners.lock().unwrap();
-        if let Some(mut listeners) = listeners.get_mut(&dest_ip) {
-            if let Some(mut listener) = listeners.get_mut(&next_level_protocol) {
-                listener.recv(time, ip_pkg)
-            } else {
-                Err(RxError::NoListener(format!("Ipv4 {:?}", next_level_protocol)))
+        let mut result = Err(RxError::NoListener(format!("Ipv4 {}", dest_ip)));
+        for destination in destinations {
+            if let Some(mut proto_listeners) = listeners.get_mut(&destination) {
+                if let Some(mut listener) = proto_listeners.get_mut(&next_level_protocol) {
+                    let pkg = Ipv4Packet::new(ip_pkg.packet()).unwrap();
+                    let listener_result = listener.recv(time, pkg);
+                    if result.is_err() {
+                        result = listener_result;
+                    }
+                } else if result.is_err() {
+                    result = Err(RxError::NoListener(format!("Ipv4 {:?}", next_level_protocol)));
+                }
+            }
+        }
+        if local {
+            let wildcard = listeners.get_mut(&unspecified())
+                .and_then(|proto_listeners| proto_listeners.get_mut(&next_level_protocol));
+            if let Some(listener) = wildcard {
+                let pkg = Ipv4Packet::new(ip_pkg.packet()).unwrap();
+                let wildcard_result = listener.recv(time, pkg);
+                let no_listener = match result {
+                    Err(RxError::NoListener(_)) => true,
+                    _ => false,
+                };
+                if no_listener || wildcard_result.is_ok() {
+                    result = wildcard_result;
+                }
             }
-        } else {
-            Err(RxError::NoListener(format!("Ipv4 {}", dest_ip)))
         }
+        result
     }
 }
 
+fn unspecified() -> Ipv4Addr {
+    Ipv4Addr::new(0, 0, 0, 0)
+}
+
 impl EthernetListener for Ipv4Rx {
     fn recv(&mut self, time: SystemTime, eth_pkg: &EthernetPacket) -> RxResult {
-        let ip_pkg = try!(Self::get_ipv4_pkg(eth_pkg));
+        let ip_pkg = try!(self.validate(eth_pkg));
+        let dst = ip_pkg.get_destination();
+        if self.local_addresses.lock().unwrap().tentative.contains(&dst) {
+            return Err(RxError::NoListener(format!("Ipv4 {} is tentative", dst)));
+        }
+        if self.forwarder.is_some() && !self.is_local(dst) {
+            let &mut (mac, ref mut forwarder) = self.forwarder.as_mut().unwrap();
+            // Packets in link layer broadcasts and multicasts, or to other
+            // hosts, must not be forwarded. RFC 1812 section 5.3.4
+            if eth_pkg.get_destination() != mac {
+                return Err(RxError::NoListener(format!("Ipv4, not forwarding {} sent to {}",
+                                                       dst,
+                                                       eth_pkg.get_destination())));
+            }
+            return forwarder.recv(time, ip_pkg);
+        }
         if Self::is_fragment(&ip_pkg) {
-            if let Some(reassembled_pkg) = try!(self.save_fragment(ip_pkg)) {
+            let src_mac = eth_pkg.get_source();
+            let reassembled = {
+                let mut reassembler = self.reassembler.lock().unwrap();
+                if self.expire_fragments {
+                    reassembler.expire();
+                }
+                reassembler.save_fragment(src_mac, ip_pkg)
+            };
+            if let Some(reassembled_pkg) = try!(reassembled) {
                 self.forward(time, reassembled_pkg)
             } else {
                 Ok(())
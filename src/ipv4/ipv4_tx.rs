@@ -1,7 +1,9 @@
 
 
-use super::{MORE_FRAGMENTS, NO_FLAGS, IpNextHeaderProtocol};
-use {Payload, TxResult, Tx};
+use super::{DEFAULT_TTL, DONT_FRAGMENT, MORE_FRAGMENTS, NO_FLAGS, IpNextHeaderProtocol};
+use super::identification::IdentificationSource;
+use super::options::{self, Ipv4Option, MAX_OPTIONS_LENGTH};
+use {Payload, TxError, TxResult, Tx};
 use ethernet::{EthernetFields, EtherTypes};
 
 use pnet::packet::{MutablePacket, Packet};
@@ -22,6 +24,12 @@ pub struct Ipv4Tx<T> {
     src: Ipv4Addr,
     dst: Ipv4Addr,
     mtu: usize,
+    options: Vec<Ipv4Option>,
+    ttl: u8,
+    dscp: u8,
+    ecn: u8,
+    dont_fragment: bool,
+    identification_source: Option<IdentificationSource>,
 }
 
 impl<T> Ipv4Tx<T> {
@@ -37,9 +45,91 @@ impl<T> Ipv4Tx<T> {
             src: src,
             dst: dst,
             mtu: mtu,
+            options: vec![],
+            ttl: DEFAULT_TTL,
+            dscp: 0,
+            ecn: 0,
+            dont_fragment: false,
+            identification_source: None,
         }
     }
 
+    /// Sets where the identification of outgoing datagrams is taken from.
+    /// Without a source every datagram gets a random identification.
+    pub fn set_identification_source(&mut self, source: IdentificationSource) {
+        self.identification_source = Some(source);
+    }
+
+    /// Sets if packets that fit in the MTU should be sent with the
+    /// Don't-Fragment flag, as done for path MTU discovery. Packets larger
+    /// than the MTU are still fragmented locally and sent without the flag.
+    pub fn set_dont_fragment(&mut self, dont_fragment: bool) {
+        self.dont_fragment = dont_fragment;
+    }
+
+    pub fn dont_fragment(&self) -> bool {
+        self.dont_fragment
+    }
+
+    /// Sets the time to live of all packets sent with this `Ipv4Tx`.
+    pub fn set_ttl(&mut self, ttl: u8) {
+        self.ttl = ttl;
+    }
+
+    pub fn ttl(&self) -> u8 {
+        self.ttl
+    }
+
+    /// Sets the differentiated services code point of all packets sent with
+    /// this `Ipv4Tx`. See RFC 2474.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `dscp` does not fit in six bits.
+    pub fn set_dscp(&mut self, dscp: u8) {
+        assert!(dscp <= 0b11_1111);
+        self.dscp = dscp;
+    }
+
+    pub fn dscp(&self) -> u8 {
+        self.dscp
+    }
+
+    /// Sets the explicit congestion notification bits of all packets sent
+    /// with this `Ipv4Tx`. See RFC 3168.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `ecn` does not fit in two bits.
+    pub fn set_ecn(&mut self, ecn: u8) {
+        assert!(ecn <= 0b11);
+        self.ecn = ecn;
+    }
+
+    pub fn ecn(&self) -> u8 {
+        self.ecn
+    }
+
+    /// Sets the options to include in the header of all packets sent with
+    /// this `Ipv4Tx`. Options that should not be copied into every fragment
+    /// are only included in the first fragment of fragmented packets.
+    ///
+    /// # Panics
+    ///
+    /// Panics if the options don't fit in the header, if the header with the
+    /// options does not fit in the MTU or if any option is not valid.
+    pub fn set_options(&mut self, options: Vec<Ipv4Option>) {
+        assert!(options.iter().all(Ipv4Option::is_valid));
+        let options_length = options::options_length(&options);
+        assert!(options_length <= MAX_OPTIONS_LENGTH);
+        assert!(self.mtu >= Ipv4Packet::minimum_packet_size() + options_length);
+        self.options = options;
+    }
+
+    pub fn options(&self) -> &[Ipv4Option] {
+        &self.options
+    }
+
     pub fn src(&self) -> Ipv4Addr {
         self.src
     }
@@ -53,7 +143,13 @@ impl<T: Tx<EthernetFields>> Tx<Ipv4Fields> for Ipv4Tx<T> {
     fn send<'p, P>(&mut self, payload: &'p mut P) -> Option<TxResult<()>>
         where P: Payload<Ipv4Fields>
     {
-        let mut builder = Ipv4Builder::new(self.src, self.dst, self.mtu, payload);
+        let mut builder =
+            Ipv4Builder::with_options(self.src, self.dst, self.mtu, &self.options, payload);
+        builder.ttl = self.ttl;
+        builder.dscp = self.dscp;
+        builder.ecn = self.ecn;
+        builder.dont_fragment = self.dont_fragment;
+        builder.identification_source = self.identification_source.as_ref();
         self.tx.send(&mut builder)
     }
 }
@@ -63,26 +159,66 @@ pub struct Ipv4Builder<'p, P: Payload<Ipv4Fields> + 'p> {
     src: Ipv4Addr,
     dst: Ipv4Addr,
     mtu: usize,
-    identification: u16,
+    options: &'p [Ipv4Option],
+    header_length: usize,
+    ttl: u8,
+    dscp: u8,
+    ecn: u8,
+    dont_fragment: bool,
+    identification_source: Option<&'p IdentificationSource>,
+    identification: Option<u16>,
     offset: usize,
     payload: &'p mut P,
 }
 
 impl<'p, P: Payload<Ipv4Fields>> Ipv4Builder<'p, P> {
     pub fn new(src: Ipv4Addr, dst: Ipv4Addr, mtu: usize, payload: &'p mut P) -> Self {
-        assert!(mtu >= Ipv4Packet::minimum_packet_size());
+        Self::with_options(src, dst, mtu, &[], payload)
+    }
+
+    /// Creates a builder that includes `options` in the headers it builds.
+    pub fn with_options(src: Ipv4Addr,
+                        dst: Ipv4Addr,
+                        mtu: usize,
+                        options: &'p [Ipv4Option],
+                        payload: &'p mut P)
+                        -> Self {
+        let header_length = Ipv4Packet::minimum_packet_size() + options::options_length(options);
+        assert!(header_length <= Ipv4Packet::minimum_packet_size() + MAX_OPTIONS_LENGTH);
+        assert!(mtu >= header_length);
         Ipv4Builder {
             src: src,
             dst: dst,
             mtu: mtu,
-            identification: thread_rng().gen(),
+            options: options,
+            header_length: header_length,
+            ttl: DEFAULT_TTL,
+            dscp: 0,
+            ecn: 0,
+            dont_fragment: false,
+            identification_source: None,
+            identification: None,
             offset: 0,
             payload: payload,
         }
     }
 
+    /// Returns the identification of the datagram being built, picking one
+    /// when its first fragment is built.
+    fn identification(&mut self) -> u16 {
+        if let Some(identification) = self.identification {
+            return identification;
+        }
+        let identification = match self.identification_source {
+            Some(source) => source.next(self.src, self.dst, self.payload.fields().0),
+            None => thread_rng().gen(),
+        };
+        self.identification = Some(identification);
+        identification
+    }
+
     fn max_payload_per_fragment(&self) -> usize {
-        (self.mtu - Ipv4Packet::minimum_packet_size()) & !0b111
+        (self.mtu - self.header_length) & !0b111
     }
 
     fn ip_packets_per_payload_packet(&self) -> usize {
@@ -113,21 +249,25 @@ impl<'p, P: Payload<Ipv4Fields>> Payload<EthernetFields> for Ipv4Builder<'p, P>
 
     fn packet_size(&self) -> usize {
         if self.ip_packets_per_payload_packet() > 1 {
-            Ipv4Packet::minimum_packet_size() + self.evenly_distributed_payload_per_fragment()
+            self.header_length + self.evenly_distributed_payload_per_fragment()
         } else {
-            Ipv4Packet::minimum_packet_size() + self.payload.packet_size()
+            self.header_length + self.payload.packet_size()
         }
     }
 
     fn build(&mut self, buffer: &mut [u8]) {
+        let identification = self.identification();
         let mut pkg = MutableIpv4Packet::new(buffer).expect("Too small buffer given");
         pkg.set_version(4);
-        pkg.set_dscp(0); // https://en.wikipedia.org/wiki/Differentiated_services
-        pkg.set_ecn(0); // https://en.wikipedia.org/wiki/Explicit_Congestion_Notification
-        pkg.set_ttl(40);
-        // ip_pkg.set_options(vec![]); // We currently don't support options
-        pkg.set_header_length(5); // 5 is for no option fields
-        pkg.set_identification(self.identification);
+        pkg.set_dscp(self.dscp); // https://en.wikipedia.org/wiki/Differentiated_services
+        pkg.set_ecn(self.ecn); // https://en.wikipedia.org/wiki/Explicit_Congestion_Notification
+        pkg.set_ttl(self.ttl);
+        pkg.set_header_length((self.header_length / 4) as u8);
+        options::write_options(self.options,
+                               &mut pkg.packet_mut()[Ipv4Packet::minimum_packet_size()..
+                                                     self.header_length],
+                               self.offset == 0);
+        pkg.set_identification(identification);
         pkg.set_source(self.src);
         pkg.set_destination(self.dst);
         pkg.set_fragment_offset((self.offset / 8) as u16);
@@ -135,13 +275,17 @@ impl<'p, P: Payload<Ipv4Fields>> Payload<EthernetFields> for Ipv4Builder<'p, P>
         let bytes_remaining = self.payload.packet_size() - self.offset;
         let bytes_max = pkg.payload().len();
         let payload_size = if bytes_remaining <= bytes_max {
-            pkg.set_flags(NO_FLAGS);
+            if self.offset == 0 && self.dont_fragment {
+                pkg.set_flags(DONT_FRAGMENT);
+            } else {
+                pkg.set_flags(NO_FLAGS);
+            }
             bytes_remaining
         } else {
             pkg.set_flags(MORE_FRAGMENTS);
             bytes_max & !0b111 // Round down to divisable by 8
         };
-        let total_length = Ipv4Packet::minimum_packet_size() + payload_size;
+        let total_length = self.header_length + payload_size;
         pkg.set_total_length(total_length as u16);
 
         pkg.set_next_level_protocol(self.payload.fields().0);
@@ -152,22 +296,75 @@ impl<'p, P: Payload<Ipv4Fields>> Payload<EthernetFields> for Ipv4Builder<'p, P>
 
         self.offset += payload_size;
         if self.offset >= self.payload.packet_size() {
-            self.identification = thread_rng().gen();
+            self.identification = None;
             self.offset = 0;
         }
     }
 }
 
 
+/// Splits an already built packet into fragments that fit in `mtu`, as done
+/// by routers forwarding onto a link with a smaller MTU. Options that should
+/// not be copied into every fragment are replaced with `NoOperation` in all
+/// fragments but the first. Ignores the Don't-Fragment flag, checking it is
+/// up to the caller. Returns an error if the header of `ip_pkg` and eight
+/// bytes of payload don't fit in `mtu`.
+pub fn fragment_packet(ip_pkg: &Ipv4Packet, mtu: usize) -> TxResult<Vec<Vec<u8>>> {
+    let header_length = ip_pkg.get_header_length() as usize * 4;
+    let total_length = ip_pkg.get_total_length() as usize;
+    if mtu < header_length + 8 {
+        return Err(TxError::Other(format!("Mtu {} too small to fragment into", mtu)));
+    }
+    let max_payload_per_fragment = (mtu - header_length) & !0b111;
+    let header = &ip_pkg.packet()[..header_length];
+    let payload = &ip_pkg.packet()[header_length..total_length];
+    let ip_options = options::parse_options(ip_pkg).unwrap_or_else(|_| vec![]);
+    let first_offset = ip_pkg.get_fragment_offset() as usize * 8;
+    let more_fragments = ip_pkg.get_flags() & MORE_FRAGMENTS != 0;
+
+    let mut fragments = vec![];
+    for (i, chunk) in payload.chunks(max_payload_per_fragment).enumerate() {
+        let mut buffer = vec![0; header_length + chunk.len()];
+        buffer[..header_length].copy_from_slice(header);
+        buffer[header_length..].copy_from_slice(chunk);
+        {
+            let mut pkg = MutableIpv4Packet::new(&mut buffer).unwrap();
+            let offset = first_offset + i * max_payload_per_fragment;
+            let last = (i + 1) * max_payload_per_fragment >= payload.len();
+            if offset != 0 {
+                options::write_options(&ip_options,
+                                       &mut pkg.packet_mut()[Ipv4Packet::minimum_packet_size()..
+                                                             header_length],
+                                       false);
+            }
+            pkg.set_fragment_offset((offset / 8) as u16);
+            pkg.set_flags(if last && !more_fragments {
+                NO_FLAGS
+            } else {
+                MORE_FRAGMENTS
+            });
+            pkg.set_total_length((header_length + chunk.len()) as u16);
+            let checksum = checksum(&pkg.to_immutable());
+            pkg.set_checksum(checksum);
+        }
+        fragments.push(buffer);
+    }
+    Ok(fragments)
+}
+
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
-    use super::super::{MORE_FRAGMENTS, IpNextHeaderProtocols};
+    use super::super::{DEFAULT_TTL, DONT_FRAGMENT, MORE_FRAGMENTS, NO_FLAGS,
+                       IpNextHeaderProtocols};
+    use super::super::identification::{GlobalCounter, IdentificationSource};
+    use super::super::options::{Ipv4Option, parse_options};
     use {Tx, CustomPayload, Payload};
 
     use pnet::packet::Packet;
-    use pnet::packet::ipv4::Ipv4Packet;
+    use pnet::packet::ipv4::{Ipv4Packet, checksum};
 
     use std::net::Ipv4Addr;
     use testing::MockTx;
@@ -374,6 +571,152 @@ mod tests {
         check_pkg(&pkg, *SRC, *DST, false, 0, &data);
     }
 
+    #[test]
+    fn tx_fragmented_options() {
+        let (tx, rx) = MockTx::new();
+        let data = &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9];
+        let mut payload = CustomPayload::new(*FIELDS, data);
+        let options = vec![Ipv4Option::RouterAlert(0),
+                           Ipv4Option::RecordRoute {
+                               pointer: 4,
+                               route: vec![Ipv4Addr::new(0, 0, 0, 0)],
+                           }];
+
+        let mut testee = Ipv4Tx::new(tx, *SRC, *DST, 20 + 12 + 8);
+        testee.set_options(options.clone());
+        testee.send(&mut payload).unwrap().unwrap();
+
+        let pkg1 = rx.try_recv().unwrap();
+        let pkg2 = rx.try_recv().unwrap();
+        assert!(rx.try_recv().is_err());
+
+        let ip_pkg1 = Ipv4Packet::new(&pkg1).unwrap();
+        assert_eq!(8, ip_pkg1.get_header_length());
+        assert_eq!(20 + 12 + 8, ip_pkg1.get_total_length());
+        assert_eq!(options, parse_options(&ip_pkg1).unwrap());
+        assert_eq!(&data[..8], &ip_pkg1.payload()[..8]);
+
+        let ip_pkg2 = Ipv4Packet::new(&pkg2).unwrap();
+        assert_eq!(8, ip_pkg2.get_header_length());
+        assert_eq!(20 + 12 + 2, ip_pkg2.get_total_length());
+        let mut expected = vec![Ipv4Option::RouterAlert(0)];
+        expected.extend(vec![Ipv4Option::NoOperation; 7]);
+        assert_eq!(expected, parse_options(&ip_pkg2).unwrap());
+        assert_eq!(&data[8..], &ip_pkg2.payload()[..2]);
+    }
+
+    #[test]
+    fn tx_ttl_dscp_ecn() {
+        let (tx, rx) = MockTx::new();
+        let mut testee = Ipv4Tx::new(tx, *SRC, *DST, 1500);
+        assert_eq!(DEFAULT_TTL, testee.ttl());
+        testee.set_ttl(1);
+        testee.set_dscp(46);
+        testee.set_ecn(0b10);
+
+        let mut payload = CustomPayload::new(*FIELDS, &[1, 2, 3]);
+        testee.send(&mut payload).unwrap().unwrap();
+
+        let pkg = rx.try_recv().unwrap();
+        let ip_pkg = Ipv4Packet::new(&pkg).unwrap();
+        assert_eq!(1, ip_pkg.get_ttl());
+        assert_eq!(46, ip_pkg.get_dscp());
+        assert_eq!(0b10, ip_pkg.get_ecn());
+    }
+
+    #[test]
+    fn tx_identification_source() {
+        let (tx, rx) = MockTx::new();
+        let mut testee = Ipv4Tx::new(tx, *SRC, *DST, 20 + 8);
+        testee.set_identification_source(IdentificationSource::new(GlobalCounter::new(7)));
+
+        let mut payload = CustomPayload::new(*FIELDS, &[0, 1, 2, 3, 4, 5, 6, 7, 8]);
+        testee.send(&mut payload).unwrap().unwrap();
+        let mut payload = CustomPayload::new(*FIELDS, &[0, 1]);
+        testee.send(&mut payload).unwrap().unwrap();
+
+        // Both fragments of the first datagram share the identification
+        for &expected in &[7, 7, 8] {
+            let pkg = rx.try_recv().unwrap();
+            assert_eq!(expected, Ipv4Packet::new(&pkg).unwrap().get_identification());
+        }
+    }
+
+    #[test]
+    fn tx_dont_fragment() {
+        let (tx, rx) = MockTx::new();
+        let mut testee = Ipv4Tx::new(tx, *SRC, *DST, 20 + 8);
+        testee.set_dont_fragment(true);
+
+        let mut payload = CustomPayload::new(*FIELDS, &[0, 1, 2, 3, 4, 5, 6, 7]);
+        testee.send(&mut payload).unwrap().unwrap();
+        let pkg = rx.try_recv().unwrap();
+        assert_eq!(DONT_FRAGMENT, Ipv4Packet::new(&pkg).unwrap().get_flags());
+
+        // Too large packets are fragmented without the flag
+        let mut payload = CustomPayload::new(*FIELDS, &[0, 1, 2, 3, 4, 5, 6, 7, 8]);
+        testee.send(&mut payload).unwrap().unwrap();
+        let pkg1 = rx.try_recv().unwrap();
+        let pkg2 = rx.try_recv().unwrap();
+        assert_eq!(MORE_FRAGMENTS, Ipv4Packet::new(&pkg1).unwrap().get_flags());
+        assert_eq!(NO_FLAGS, Ipv4Packet::new(&pkg2).unwrap().get_flags());
+    }
+
+    #[test]
+    #[should_panic]
+    fn dscp_too_large() {
+        let (tx, _) = MockTx::new();
+        let mut testee = Ipv4Tx::new(tx, *SRC, *DST, 1500);
+        testee.set_dscp(64);
+    }
+
+    #[test]
+    #[should_panic]
+    fn options_too_long() {
+        let (tx, _) = MockTx::new();
+        let mut testee = Ipv4Tx::new(tx, *SRC, *DST, 1500);
+        testee.set_options(vec![Ipv4Option::Unknown(30, vec![0; 40])]);
+    }
+
+    #[test]
+    fn fragment_existing_packet() {
+        let (tx, rx) = MockTx::new();
+        let data = (0..20).collect::<Vec<u8>>();
+        let mut payload = CustomPayload::new(*FIELDS, &data);
+        let mut testee = Ipv4Tx::new(tx, *SRC, *DST, 1500);
+        testee.set_options(vec![Ipv4Option::RouterAlert(0),
+                                Ipv4Option::RecordRoute {
+                                    pointer: 4,
+                                    route: vec![Ipv4Addr::new(0, 0, 0, 0)],
+                                }]);
+        testee.send(&mut payload).unwrap().unwrap();
+        let pkg = rx.try_recv().unwrap();
+
+        assert!(fragment_packet(&Ipv4Packet::new(&pkg).unwrap(), 32 + 7).is_err());
+        let fragments = fragment_packet(&Ipv4Packet::new(&pkg).unwrap(), 32 + 8).unwrap();
+        assert_eq!(3, fragments.len());
+        let id = check_fragment(&fragments[0], true, 0, &data[..8]);
+        assert_eq!(id, check_fragment(&fragments[1], true, 8, &data[8..16]));
+        assert_eq!(id, check_fragment(&fragments[2], false, 16, &data[16..]));
+
+        let first = Ipv4Packet::new(&fragments[0]).unwrap();
+        assert_eq!(2, parse_options(&first).unwrap().len());
+        let last = Ipv4Packet::new(&fragments[2]).unwrap();
+        let mut expected = vec![Ipv4Option::RouterAlert(0)];
+        expected.extend(vec![Ipv4Option::NoOperation; 7]);
+        assert_eq!(expected, parse_options(&last).unwrap());
+    }
+
+    fn check_fragment(pkg_buffer: &[u8], is_fragment: bool, offset: u16, payload: &[u8]) -> u16 {
+        let pkg = Ipv4Packet::new(pkg_buffer).unwrap();
+        assert_eq!(is_fragment, pkg.get_flags() == MORE_FRAGMENTS);
+        assert_eq!(offset, pkg.get_fragment_offset() * 8);
+        assert_eq!(32 + payload.len(), pkg.get_total_length() as usize);
+        assert_eq!(payload, pkg.payload());
+        assert_eq!(checksum(&pkg), pkg.get_checksum());
+        pkg.get_identification()
+    }
+
     fn check_pkg(pkg_buffer: &[u8],
                  src: Ipv4Addr,
                  dst: Ipv4Addr,
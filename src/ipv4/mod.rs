@@ -1,32 +1,57 @@
 pub use pnet::packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 pub use ipnetwork::Ipv4Network;
 
+use std::net::Ipv4Addr;
+
+mod identification;
 mod ipv4_rx;
 mod ipv4_tx;
+mod options;
+mod path_mtu;
+mod raw_socket;
+
+pub use self::identification::{DEFAULT_IDENTIFICATION_TIMEOUT_SECS, GlobalCounter,
+                                IdentificationGenerator, IdentificationSource,
+                                PerDestinationCounter, RandomIdentification};
+pub use self::ipv4_rx::{BasicIpv4Listener, IpListenerLookup, Ipv4Listener, Ipv4Rx, Ipv4RxStats,
+                        LocalAddresses, Reassembler, ReassemblyConfig, ReassemblyStats,
+                        ReassemblyTimeout};
+pub use self::ipv4_tx::{Ipv4Fields, Ipv4Builder, Ipv4Tx, fragment_packet};
+pub use self::options::{Ipv4Option, MAX_OPTIONS_LENGTH, parse_options};
+pub use self::path_mtu::{DEFAULT_PATH_MTU_TIMEOUT_SECS, MIN_PATH_MTU, PathMtuCache,
+                         plateau_below};
+pub use self::raw_socket::RawIpv4Socket;
 
-pub use self::ipv4_rx::{BasicIpv4Listener, IpListenerLookup, Ipv4Listener, Ipv4Rx};
-pub use self::ipv4_tx::{Ipv4Fields, Ipv4Builder, Ipv4Tx};
+/// The time to live of outgoing packets unless something else is configured.
+pub const DEFAULT_TTL: u8 = 40;
 
 pub const MORE_FRAGMENTS: u8 = 0b001;
 pub const DONT_FRAGMENT: u8 = 0b010;
 pub const NO_FLAGS: u8 = 0b000;
 
+/// Returns `true` if `ip` is the subnet-directed broadcast address of `net`.
+/// Networks with a prefix of 31 or 32 have no broadcast address, RFC 3021.
+pub fn is_directed_broadcast(net: &Ipv4Network, ip: Ipv4Addr) -> bool {
+    net.prefix() < 31 && net.broadcast() == ip
+}
+
 #[cfg(test)]
 mod tests {
 
     use super::*;
     use RxError;
-    use ethernet::EthernetListener;
+    use ethernet::{EthernetListener, MacAddr};
 
-    use pnet::packet::MutablePacket;
-    use pnet::packet::ethernet::MutableEthernetPacket;
+    use pnet::packet::{MutablePacket, Packet};
+    use pnet::packet::ethernet::{EthernetPacket, MutableEthernetPacket};
     use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet, checksum};
 
     use std::collections::HashMap;
     use std::net::Ipv4Addr;
     use std::sync::{Arc, Mutex};
     use std::sync::mpsc::{self, Receiver};
-    use std::time::SystemTime;
+    use std::thread::sleep;
+    use std::time::{Duration, SystemTime};
 
     #[test]
     fn rx_not_fragmented() {
@@ -40,6 +65,7 @@ mod tests {
             ip_pkg.set_destination(dst);
             ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
             ip_pkg.set_flags(DONT_FRAGMENT);
+            ip_pkg.set_version(4);
             ip_pkg.set_header_length(5); // No options
             ip_pkg.set_total_length(20 + 15);
             let csum = checksum(&ip_pkg.to_immutable());
@@ -73,6 +99,7 @@ mod tests {
             ip_pkg.set_flags(MORE_FRAGMENTS);
             ip_pkg.set_fragment_offset(0);
             ip_pkg.set_identification(137);
+            ip_pkg.set_version(4);
             ip_pkg.set_header_length(5); // No options
             ip_pkg.set_total_length(20 + 16);
             let csum = checksum(&ip_pkg.to_immutable());
@@ -93,8 +120,7 @@ mod tests {
             let csum = checksum(&ip_pkg.to_immutable());
             ip_pkg.set_checksum(csum);
         }
-        assert_eq!(ipv4_rx.recv(SystemTime::now(), &pkg.to_immutable()),
-                   Err(RxError::InvalidContent));
+        ipv4_rx.recv(SystemTime::now(), &pkg.to_immutable()).unwrap();
         assert!(rx.try_recv().is_err());
 
         // Send final part of fragmented packet
@@ -114,6 +140,323 @@ mod tests {
         assert!(rx.try_recv().is_err());
     }
 
+    #[test]
+    fn rx_fragmented_out_of_order() {
+        let dst = Ipv4Addr::new(127, 0, 0, 1);
+        let (mut ipv4_rx, rx) = setup_rx(dst);
+
+        let last = create_fragment(dst, 16, NO_FLAGS, &[3; 8]);
+        let middle = create_fragment(dst, 8, MORE_FRAGMENTS, &[2; 8]);
+        let first = create_fragment(dst, 0, MORE_FRAGMENTS, &[1; 8]);
+        for buffer in &[last, middle.clone(), middle] {
+            let pkg = EthernetPacket::new(&buffer[..]).unwrap();
+            ipv4_rx.recv(SystemTime::now(), &pkg).unwrap();
+            assert!(rx.try_recv().is_err());
+        }
+        ipv4_rx.recv(SystemTime::now(), &EthernetPacket::new(&first[..]).unwrap()).unwrap();
+
+        let (_, rx_ip_pkg) = rx.try_recv().expect("Expected a reassembled packet");
+        assert_eq!(rx_ip_pkg.get_flags(), NO_FLAGS);
+        assert_eq!(rx_ip_pkg.get_total_length(), 20 + 24);
+        assert_eq!(rx_ip_pkg.get_checksum(), checksum(&rx_ip_pkg));
+        let mut expected = vec![1; 8];
+        expected.extend_from_slice(&[2; 8]);
+        expected.extend_from_slice(&[3; 8]);
+        assert_eq!(&expected[..], rx_ip_pkg.payload());
+    }
+
+    #[test]
+    fn rx_fragmented_overlap() {
+        let dst = Ipv4Addr::new(127, 0, 0, 1);
+        let (mut ipv4_rx, rx) = setup_rx(dst);
+
+        let first = create_fragment(dst, 0, MORE_FRAGMENTS, &[1; 16]);
+        let overlapping = create_fragment(dst, 8, MORE_FRAGMENTS, &[9; 16]);
+        let last = create_fragment(dst, 16, NO_FLAGS, &[3; 8]);
+        ipv4_rx.recv(SystemTime::now(), &EthernetPacket::new(&first[..]).unwrap()).unwrap();
+        assert_eq!(Err(RxError::InvalidContent),
+                   ipv4_rx.recv(SystemTime::now(),
+                                &EthernetPacket::new(&overlapping[..]).unwrap()));
+        // The whole datagram was dropped, so the last fragment does not complete it
+        ipv4_rx.recv(SystemTime::now(), &EthernetPacket::new(&last[..]).unwrap()).unwrap();
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn reassembly_timeout() {
+        let dst = Ipv4Addr::new(127, 0, 0, 1);
+        let mac = MacAddr::new(1, 2, 3, 4, 5, 6);
+        let mut testee = Reassembler::new(ReassemblyConfig {
+            timeout: Duration::from_millis(10),
+            max_memory: 1024,
+            send_time_exceeded: true,
+        });
+
+        let first = create_fragment(dst, 0, MORE_FRAGMENTS, &[1; 16]);
+        let ip_pkg = Ipv4Packet::new(&first[EthernetPacket::minimum_packet_size()..]).unwrap();
+        assert!(testee.save_fragment(mac, ip_pkg).unwrap().is_none());
+        assert_eq!(20 + 16, testee.memory_usage());
+        assert!(testee.expire().is_empty());
+
+        sleep(Duration::from_millis(20));
+        let timeouts = testee.expire();
+        assert_eq!(1, timeouts.len());
+        assert_eq!(mac, timeouts[0].src_mac);
+        assert_eq!(dst, timeouts[0].dst);
+        assert_eq!(20 + 8, timeouts[0].data.len());
+        assert_eq!(0, testee.memory_usage());
+        assert_eq!(1, testee.stats().timeouts);
+    }
+
+    #[test]
+    fn reassembly_memory_limit() {
+        let dst = Ipv4Addr::new(127, 0, 0, 1);
+        let mac = MacAddr::new(1, 2, 3, 4, 5, 6);
+        let mut testee = Reassembler::new(ReassemblyConfig {
+            timeout: Duration::from_secs(10),
+            max_memory: 100,
+            send_time_exceeded: false,
+        });
+
+        let first = create_fragment(dst, 0, MORE_FRAGMENTS, &[1; 40]);
+        let mut second = first.clone();
+        {
+            let mut ip_pkg =
+                MutableIpv4Packet::new(&mut second[EthernetPacket::minimum_packet_size()..])
+                    .unwrap();
+            ip_pkg.set_identification(138);
+        }
+        for buffer in &[first, second] {
+            let ip_pkg = Ipv4Packet::new(&buffer[EthernetPacket::minimum_packet_size()..])
+                .unwrap();
+            testee.save_fragment(mac, ip_pkg).unwrap();
+            sleep(Duration::from_millis(1));
+        }
+        // The oldest packet was evicted to make room for the second one
+        assert_eq!(20 + 40, testee.memory_usage());
+        assert_eq!(1, testee.stats().evictions);
+
+        // Fragments that would not fit alone are dropped as well
+        let large = create_fragment(dst, 200, MORE_FRAGMENTS, &[1; 8]);
+        let ip_pkg = Ipv4Packet::new(&large[EthernetPacket::minimum_packet_size()..]).unwrap();
+        assert!(testee.save_fragment(mac, ip_pkg).unwrap().is_none());
+        assert_eq!(0, testee.memory_usage());
+        assert_eq!(3, testee.stats().evictions);
+    }
+
+    #[test]
+    fn rx_options() {
+        let dst = Ipv4Addr::new(127, 0, 0, 1);
+        let (mut ipv4_rx, rx) = setup_rx(dst);
+
+        let mut buffer = vec![0; EthernetPacket::minimum_packet_size() + 24 + 8];
+        let mut pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+        {
+            let mut ip_pkg = MutableIpv4Packet::new(pkg.payload_mut()).unwrap();
+            ip_pkg.set_destination(dst);
+            ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+            ip_pkg.set_version(4);
+            ip_pkg.set_header_length(6);
+            ip_pkg.set_total_length(24 + 8);
+            ip_pkg.packet_mut()[20..24].copy_from_slice(&[148, 4, 0, 0]);
+            let csum = checksum(&ip_pkg.to_immutable());
+            ip_pkg.set_checksum(csum);
+        }
+        ipv4_rx.recv(SystemTime::now(), &pkg.to_immutable()).unwrap();
+        let (_, rx_ip_pkg) = rx.try_recv().unwrap();
+        assert_eq!(vec![Ipv4Option::RouterAlert(0)],
+                   parse_options(&rx_ip_pkg).unwrap());
+
+        // Option length pointing past the end of the header
+        {
+            let mut ip_pkg = MutableIpv4Packet::new(pkg.payload_mut()).unwrap();
+            ip_pkg.packet_mut()[21] = 8;
+            let csum = checksum(&ip_pkg.to_immutable());
+            ip_pkg.set_checksum(csum);
+        }
+        assert_eq!(Err(RxError::InvalidLength),
+                   ipv4_rx.recv(SystemTime::now(), &pkg.to_immutable()));
+        assert!(rx.try_recv().is_err());
+    }
+
+    fn create_fragment(dst: Ipv4Addr, offset: u16, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let ip_size = Ipv4Packet::minimum_packet_size() + payload.len();
+        let mut buffer = vec![0; EthernetPacket::minimum_packet_size() + ip_size];
+        {
+            let mut pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+            let mut ip_pkg = MutableIpv4Packet::new(pkg.payload_mut()).unwrap();
+            ip_pkg.set_destination(dst);
+            ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+            ip_pkg.set_flags(flags);
+            ip_pkg.set_fragment_offset(offset / 8);
+            ip_pkg.set_identification(137);
+            ip_pkg.set_version(4);
+            ip_pkg.set_header_length(5); // No options
+            ip_pkg.set_total_length(ip_size as u16);
+            ip_pkg.set_payload(payload);
+            let csum = checksum(&ip_pkg.to_immutable());
+            ip_pkg.set_checksum(csum);
+        }
+        buffer
+    }
+
+    #[test]
+    fn rx_broadcast_multicast() {
+        let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let (tx, rx) = mpsc::channel();
+        let mut ip_listeners = HashMap::new();
+        ip_listeners.insert(IpNextHeaderProtocols::Icmp, BasicIpv4Listener::new(tx));
+        let mut listeners = HashMap::new();
+        listeners.insert(local_ip, ip_listeners);
+        let listeners = Arc::new(Mutex::new(listeners));
+        let reassembler = Arc::new(Mutex::new(Reassembler::new(ReassemblyConfig::default())));
+        let mut local_addresses = LocalAddresses::default();
+        local_addresses.networks.push(Ipv4Network::new(local_ip, 24).unwrap());
+        local_addresses.multicast_groups.insert(Ipv4Addr::new(239, 1, 2, 3));
+        let (forward_tx, forward_rx) = mpsc::channel();
+        let mac = MacAddr::new(1, 2, 3, 4, 5, 6);
+        let mut ipv4_rx = Ipv4Rx::with_forwarder(listeners,
+                                                 reassembler,
+                                                 Arc::new(Mutex::new(local_addresses)),
+                                                 Arc::new(Mutex::new(Ipv4RxStats::default())),
+                                                 mac,
+                                                 BasicIpv4Listener::new(forward_tx));
+
+        let broadcast_mac = MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff);
+        let destinations = [(Ipv4Addr::new(255, 255, 255, 255), broadcast_mac),
+                            (Ipv4Addr::new(10, 0, 0, 255), broadcast_mac),
+                            (Ipv4Addr::new(239, 1, 2, 3), MacAddr::new(1, 0, 0x5e, 1, 2, 3)),
+                            (Ipv4Addr::new(10, 0, 1, 255), mac),
+                            (Ipv4Addr::new(10, 0, 1, 255), broadcast_mac),
+                            (Ipv4Addr::new(10, 0, 1, 7), MacAddr::new(1, 2, 3, 4, 5, 7))];
+        for (i, &(dst, dst_mac)) in destinations.iter().enumerate() {
+            let mut buffer = vec![0; 100];
+            let mut pkg = MutableEthernetPacket::new(&mut buffer).unwrap();
+            pkg.set_destination(dst_mac);
+            {
+                let mut ip_pkg = MutableIpv4Packet::new(pkg.payload_mut()).unwrap();
+                ip_pkg.set_destination(dst);
+                ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+                ip_pkg.set_ttl(64);
+                ip_pkg.set_version(4);
+                ip_pkg.set_header_length(5);
+                ip_pkg.set_total_length(20 + 15);
+                let csum = checksum(&ip_pkg.to_immutable());
+                ip_pkg.set_checksum(csum);
+            }
+            let result = ipv4_rx.recv(SystemTime::now(), &pkg.to_immutable());
+            assert_eq!(i < 4, result.is_ok());
+        }
+        assert_eq!(destinations[0].0, rx.try_recv().unwrap().1.get_destination());
+        assert_eq!(destinations[1].0, rx.try_recv().unwrap().1.get_destination());
+        assert_eq!(destinations[2].0, rx.try_recv().unwrap().1.get_destination());
+        assert!(rx.try_recv().is_err());
+        // Only the broadcast of a network that is not local is forwarded, and
+        // only when sent to the MAC of the interface
+        assert_eq!(destinations[3].0, forward_rx.try_recv().unwrap().1.get_destination());
+        assert!(forward_rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn rx_invalid_header() {
+        let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let (mut ipv4_rx, rx, stats) = setup_validating_rx(local_ip);
+
+        let valid = create_packet(Ipv4Addr::new(10, 0, 0, 3), local_ip);
+        let packets = [modify_packet(&valid, |ip_pkg| ip_pkg.set_version(6)),
+                       modify_packet(&valid, |ip_pkg| ip_pkg.set_header_length(4)),
+                       modify_packet(&valid, |ip_pkg| ip_pkg.set_header_length(8)),
+                       modify_packet(&valid, |ip_pkg| ip_pkg.set_total_length(19)),
+                       modify_packet(&valid, |ip_pkg| ip_pkg.set_checksum(0))];
+        let errors = [RxError::InvalidVersion,
+                      RxError::InvalidHeaderLength,
+                      RxError::InvalidHeaderLength,
+                      RxError::InvalidLength,
+                      RxError::InvalidChecksum];
+        for (buffer, error) in packets.iter().zip(errors.iter()) {
+            let pkg = EthernetPacket::new(&buffer[..]).unwrap();
+            assert_eq!(Some(error), ipv4_rx.recv(SystemTime::now(), &pkg).err().as_ref());
+        }
+        assert!(rx.try_recv().is_err());
+        assert_eq!(Ipv4RxStats {
+                       invalid_version: 1,
+                       invalid_header_length: 2,
+                       invalid_length: 1,
+                       invalid_checksum: 1,
+                       ..Ipv4RxStats::default()
+                   },
+                   *stats.lock().unwrap());
+
+        ipv4_rx.recv(SystemTime::now(), &EthernetPacket::new(&valid[..]).unwrap()).unwrap();
+        assert!(rx.try_recv().is_ok());
+    }
+
+    #[test]
+    fn rx_invalid_source() {
+        let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+        let (mut ipv4_rx, rx, stats) = setup_validating_rx(local_ip);
+
+        let sources = [local_ip,
+                       Ipv4Addr::new(10, 0, 0, 255),
+                       Ipv4Addr::new(255, 255, 255, 255),
+                       Ipv4Addr::new(224, 0, 0, 1),
+                       Ipv4Addr::new(127, 0, 0, 1),
+                       Ipv4Addr::new(240, 0, 0, 1)];
+        for &src in &sources {
+            let buffer = create_packet(src, local_ip);
+            let pkg = EthernetPacket::new(&buffer[..]).unwrap();
+            assert_eq!(Err(RxError::InvalidSourceAddress),
+                       ipv4_rx.recv(SystemTime::now(), &pkg));
+        }
+        assert!(rx.try_recv().is_err());
+        assert_eq!(sources.len(), stats.lock().unwrap().invalid_source);
+
+        // Hosts without an address yet send from the unspecified address
+        let buffer = create_packet(Ipv4Addr::new(0, 0, 0, 0), local_ip);
+        ipv4_rx.recv(SystemTime::now(), &EthernetPacket::new(&buffer[..]).unwrap()).unwrap();
+        assert!(rx.try_recv().is_ok());
+    }
+
+    fn create_packet(src: Ipv4Addr, dst: Ipv4Addr) -> Vec<u8> {
+        let mut buffer = vec![0; EthernetPacket::minimum_packet_size() + 20 + 8];
+        {
+            let mut pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+            let mut ip_pkg = MutableIpv4Packet::new(pkg.payload_mut()).unwrap();
+            ip_pkg.set_version(4);
+            ip_pkg.set_header_length(5);
+            ip_pkg.set_total_length(20 + 8);
+            ip_pkg.set_source(src);
+            ip_pkg.set_destination(dst);
+            ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+            let csum = checksum(&ip_pkg.to_immutable());
+            ip_pkg.set_checksum(csum);
+        }
+        buffer
+    }
+
+    /// Returns a copy of `buffer` with the Ipv4 header changed by `f`. The
+    /// checksum is not updated.
+    fn modify_packet<F>(buffer: &[u8], f: F) -> Vec<u8>
+        where F: FnOnce(&mut MutableIpv4Packet)
+    {
+        let mut buffer = buffer.to_vec();
+        {
+            let mut pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+            let mut ip_pkg = MutableIpv4Packet::new(pkg.payload_mut()).unwrap();
+            f(&mut ip_pkg);
+        }
+        buffer
+    }
+
+    #[test]
+    fn directed_broadcast() {
+        let net = Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 2), 24).unwrap();
+        assert!(is_directed_broadcast(&net, Ipv4Addr::new(10, 0, 0, 255)));
+        assert!(!is_directed_broadcast(&net, Ipv4Addr::new(10, 0, 0, 254)));
+        let net = Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 2), 31).unwrap();
+        assert!(!is_directed_broadcast(&net, Ipv4Addr::new(10, 0, 0, 3)));
+    }
+
     fn setup_rx(dst: Ipv4Addr)
                 -> (Box<EthernetListener>, Receiver<(SystemTime, Ipv4Packet<'static>)>) {
         let (tx, rx) = mpsc::channel();
@@ -129,4 +472,27 @@ mod tests {
         let ipv4_rx = Ipv4Rx::new(listeners);
         (ipv4_rx, rx)
     }
+
+    fn setup_validating_rx(local_ip: Ipv4Addr)
+                           -> (Box<EthernetListener>,
+                               Receiver<(SystemTime, Ipv4Packet<'static>)>,
+                               Arc<Mutex<Ipv4RxStats>>) {
+        let (tx, rx) = mpsc::channel();
+        let mut ip_listeners = HashMap::new();
+        ip_listeners.insert(IpNextHeaderProtocols::Icmp, BasicIpv4Listener::new(tx));
+        let mut listeners = HashMap::new();
+        listeners.insert(local_ip, ip_listeners);
+        let reassembler = Reassembler::new(ReassemblyConfig::default());
+        let mut local_addresses = LocalAddresses::default();
+        local_addresses.networks.push(Ipv4Network::new(local_ip, 24).unwrap());
+        let stats = Arc::new(Mutex::new(Ipv4RxStats::default()));
+        let (forward_tx, _) = mpsc::channel();
+        let ipv4_rx = Ipv4Rx::with_forwarder(Arc::new(Mutex::new(listeners)),
+                                             Arc::new(Mutex::new(reassembler)),
+                                             Arc::new(Mutex::new(local_addresses)),
+                                             stats.clone(),
+                                             MacAddr::new(1, 2, 3, 4, 5, 6),
+                                             BasicIpv4Listener::new(forward_tx));
+        (ipv4_rx, rx, stats)
+    }
 }
@@ -0,0 +1,464 @@
+use RxError;
+
+use pnet::packet::Packet;
+use pnet::packet::ipv4::Ipv4Packet;
+
+use std::net::Ipv4Addr;
+
+/// The maximum number of bytes the options can occupy in an IPv4 header.
+pub const MAX_OPTIONS_LENGTH: usize = 40;
+
+const END_OF_LIST: u8 = 0;
+const NO_OPERATION: u8 = 1;
+const RECORD_ROUTE: u8 = 7;
+const TIMESTAMP: u8 = 68;
+const LOOSE_SOURCE_ROUTE: u8 = 131;
+const STRICT_SOURCE_ROUTE: u8 = 137;
+const ROUTER_ALERT: u8 = 148;
+
+/// Set in the option type of options that must be copied into every fragment
+/// of a fragmented packet.
+const COPIED_FLAG: u8 = 0b1000_0000;
+
+/// An option in an IPv4 header, as described in RFC 791 and RFC 2113.
+///
+/// The `pointer` of the route and timestamp options is the one based offset,
+/// counted from the start of the option, to the next free address or
+/// timestamp slot.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Ipv4Option {
+    /// Marks the end of the options. Only needed if the options don't fill
+    /// the header to its end.
+    EndOfList,
+    /// Does nothing. Used to align options.
+    NoOperation,
+    /// Record Route. `route` contains both recorded addresses and the empty
+    /// slots left for routers to fill in.
+    RecordRoute { pointer: u8, route: Vec<Ipv4Addr> },
+    /// Internet Timestamp. `flag` decides if the entries hold only
+    /// timestamps (0), or addresses with timestamps (1 and 3). With flag 0
+    /// every address is `None`, otherwise `None` is an address slot not
+    /// filled in yet, written as 0.0.0.0.
+    Timestamp {
+        pointer: u8,
+        overflow: u8,
+        flag: u8,
+        entries: Vec<(Option<Ipv4Addr>, u32)>,
+    },
+    /// Loose Source and Record Route.
+    LooseSourceRoute { pointer: u8, route: Vec<Ipv4Addr> },
+    /// Strict Source and Record Route.
+    StrictSourceRoute { pointer: u8, route: Vec<Ipv4Addr> },
+    /// Router Alert, RFC 2113. A value of zero means the router should
+    /// examine the packet.
+    RouterAlert(u16),
+    /// Any other option. Holds the option type and the data following the
+    /// length field.
+    Unknown(u8, Vec<u8>),
+}
+
+impl Ipv4Option {
+    /// Returns the option type, the first byte of the option.
+    pub fn option_type(&self) -> u8 {
+        use self::Ipv4Option::*;
+        match *self {
+            EndOfList => END_OF_LIST,
+            NoOperation => NO_OPERATION,
+            RecordRoute { .. } => RECORD_ROUTE,
+            Timestamp { .. } => TIMESTAMP,
+            LooseSourceRoute { .. } => LOOSE_SOURCE_ROUTE,
+            StrictSourceRoute { .. } => STRICT_SOURCE_ROUTE,
+            RouterAlert(..) => ROUTER_ALERT,
+            Unknown(option_type, _) => option_type,
+        }
+    }
+
+    /// Returns `true` if this option must be present in every fragment of a
+    /// fragmented packet, and not only in the first one.
+    pub fn is_copied(&self) -> bool {
+        self.option_type() & COPIED_FLAG != 0
+    }
+
+    /// Returns the number of bytes this option occupies in the header.
+    pub fn packet_size(&self) -> usize {
+        use self::Ipv4Option::*;
+        match *self {
+            EndOfList | NoOperation => 1,
+            RecordRoute { ref route, .. } |
+            LooseSourceRoute { ref route, .. } |
+            StrictSourceRoute { ref route, .. } => 3 + 4 * route.len(),
+            Timestamp { flag, ref entries, .. } => {
+                4 + Self::timestamp_entry_len(flag) * entries.len()
+            }
+            RouterAlert(..) => 4,
+            Unknown(_, ref data) => 2 + data.len(),
+        }
+    }
+
+    /// Returns `false` if the option can't be written as it is, which is
+    /// the case for Timestamp options with a flag other than 0, 1 and 3, and
+    /// for Timestamp options with flag 0 and entries with addresses.
+    pub fn is_valid(&self) -> bool {
+        match *self {
+            Ipv4Option::Timestamp { flag: 0, ref entries, .. } => {
+                entries.iter().all(|&(ip, _)| ip.is_none())
+            }
+            Ipv4Option::Timestamp { flag, .. } => flag == 1 || flag == 3,
+            _ => true,
+        }
+    }
+
+    /// Writes this option to the start of `buffer`.
+    ///
+    /// # Panics
+    ///
+    /// Panics if `buffer` is shorter than `packet_size()` or if the option is not
+    /// valid.
+    pub fn write(&self, buffer: &mut [u8]) {
+        use self::Ipv4Option::*;
+        assert!(self.is_valid());
+        buffer[0] = self.option_type();
+        match *self {
+            EndOfList | NoOperation => return,
+            _ => (),
+        }
+        buffer[1] = self.packet_size() as u8;
+        match *self {
+            RecordRoute { pointer, ref route } |
+            LooseSourceRoute { pointer, ref route } |
+            StrictSourceRoute { pointer, ref route } => {
+                buffer[2] = pointer;
+                for (i, ip) in route.iter().enumerate() {
+                    buffer[3 + i * 4..7 + i * 4].copy_from_slice(&ip.octets());
+                }
+            }
+            Timestamp { pointer, overflow, flag, ref entries } => {
+                buffer[2] = pointer;
+                buffer[3] = (overflow << 4) | (flag & 0x0f);
+                let mut i = 4;
+                for &(ip, timestamp) in entries {
+                    if flag != 0 {
+                        let ip = ip.unwrap_or_else(|| Ipv4Addr::new(0, 0, 0, 0));
+                        buffer[i..i + 4].copy_from_slice(&ip.octets());
+                        i += 4;
+                    }
+                    write_u32(&mut buffer[i..i + 4], timestamp);
+                    i += 4;
+                }
+            }
+            RouterAlert(value) => {
+                buffer[2] = (value >> 8) as u8;
+                buffer[3] = value as u8;
+            }
+            Unknown(_, ref data) => buffer[2..2 + data.len()].copy_from_slice(data),
+            EndOfList | NoOperation => unreachable!(),
+        }
+    }
+
+    fn timestamp_entry_len(flag: u8) -> usize {
+        if flag == 0 { 4 } else { 8 }
+    }
+
+    /// Parses one option from the start of `data`. Returns the option and
+    /// the number of bytes it occupied.
+    fn parse(data: &[u8]) -> Result<(Ipv4Option, usize), RxError> {
+        match data[0] {
+            END_OF_LIST => return Ok((Ipv4Option::EndOfList, 1)),
+            NO_OPERATION => return Ok((Ipv4Option::NoOperation, 1)),
+            _ => (),
+        }
+        if data.len() < 2 {
+            return Err(RxError::InvalidLength);
+        }
+        let len = data[1] as usize;
+        if len < 2 || len > data.len() {
+            return Err(RxError::InvalidLength);
+        }
+        let body = &data[2..len];
+        let option = match data[0] {
+            RECORD_ROUTE | LOOSE_SOURCE_ROUTE | STRICT_SOURCE_ROUTE => {
+                if body.is_empty() || (body.len() - 1) % 4 != 0 {
+                    return Err(RxError::InvalidLength);
+                }
+                let pointer = body[0];
+                if pointer < 4 {
+                    return Err(RxError::InvalidContent);
+                }
+                let route = body[1..].chunks(4).map(read_ip).collect();
+                match data[0] {
+                    RECORD_ROUTE => Ipv4Option::RecordRoute { pointer: pointer, route: route },
+                    LOOSE_SOURCE_ROUTE => {
+                        Ipv4Option::LooseSourceRoute {
+                            pointer: pointer,
+                            route: route,
+                        }
+                    }
+                    _ => {
+                        Ipv4Option::StrictSourceRoute {
+                            pointer: pointer,
+                            route: route,
+                        }
+                    }
+                }
+            }
+            TIMESTAMP => {
+                if body.len() < 2 {
+                    return Err(RxError::InvalidLength);
+                }
+                let pointer = body[0];
+                let flag = body[1] & 0x0f;
+                if pointer < 5 || !(flag == 0 || flag == 1 || flag == 3) {
+                    return Err(RxError::InvalidContent);
+                }
+                let entry_len = Self::timestamp_entry_len(flag);
+                if (body.len() - 2) % entry_len != 0 {
+                    return Err(RxError::InvalidLength);
+                }
+                let entries = body[2..]
+                    .chunks(entry_len)
+                    .map(|entry| if flag == 0 {
+                        (None, read_u32(entry))
+                    } else {
+                        let ip = read_ip(&entry[..4]);
+                        let ip = if ip == Ipv4Addr::new(0, 0, 0, 0) { None } else { Some(ip) };
+                        (ip, read_u32(&entry[4..]))
+                    })
+                    .collect();
+                Ipv4Option::Timestamp {
+                    pointer: pointer,
+                    overflow: body[1] >> 4,
+                    flag: flag,
+                    entries: entries,
+                }
+            }
+            ROUTER_ALERT => {
+                if body.len() != 2 {
+                    return Err(RxError::InvalidLength);
+                }
+                Ipv4Option::RouterAlert(((body[0] as u16) << 8) | body[1] as u16)
+            }
+            option_type => Ipv4Option::Unknown(option_type, body.to_vec()),
+        };
+        Ok((option, len))
+    }
+}
+
+/// Parses the options in the header of `ip_pkg`. Packets delivered to an
+/// `Ipv4Listener` have already had their options validated by `Ipv4Rx`.
+pub fn parse_options(ip_pkg: &Ipv4Packet) -> Result<Vec<Ipv4Option>, RxError> {
+    let header_length = ip_pkg.get_header_length() as usize * 4;
+    let packet = ip_pkg.packet();
+    if header_length < Ipv4Packet::minimum_packet_size() || header_length > packet.len() {
+        return Err(RxError::InvalidLength);
+    }
+    let mut data = &packet[Ipv4Packet::minimum_packet_size()..header_length];
+    let mut options = vec![];
+    while !data.is_empty() {
+        let (option, len) = Ipv4Option::parse(data)?;
+        data = &data[len..];
+        if option == Ipv4Option::EndOfList {
+            break;
+        }
+        options.push(option);
+    }
+    Ok(options)
+}
+
+/// Returns the number of bytes `options` occupy in a header, including the
+/// padding needed to make the header length a multiple of four bytes.
+pub fn options_length(options: &[Ipv4Option]) -> usize {
+    let len = options.iter().map(|option| option.packet_size()).sum::<usize>();
+    (len + 3) & !0b11
+}
+
+/// Writes `options` to `buffer`, padding with `EndOfList` to the end of the
+/// buffer. Options that are not copied into every fragment are replaced with
+/// `NoOperation` when `first_fragment` is `false`, keeping the header length
+/// the same in all fragments.
+pub fn write_options(options: &[Ipv4Option], buffer: &mut [u8], first_fragment: bool) {
+    let mut i = 0;
+    for option in options {
+        let len = option.packet_size();
+        if first_fragment || option.is_copied() {
+            option.write(&mut buffer[i..i + len]);
+        } else {
+            for byte in &mut buffer[i..i + len] {
+                *byte = NO_OPERATION;
+            }
+        }
+        i += len;
+    }
+    for byte in &mut buffer[i..] {
+        *byte = END_OF_LIST;
+    }
+}
+
+fn read_ip(data: &[u8]) -> Ipv4Addr {
+    Ipv4Addr::new(data[0], data[1], data[2], data[3])
+}
+
+fn read_u32(data: &[u8]) -> u32 {
+    ((data[0] as u32) << 24) | ((data[1] as u32) << 16) | ((data[2] as u32) << 8) |
+    data[3] as u32
+}
+
+fn write_u32(buffer: &mut [u8], value: u32) {
+    buffer[0] = (value >> 24) as u8;
+    buffer[1] = (value >> 16) as u8;
+    buffer[2] = (value >> 8) as u8;
+    buffer[3] = value as u8;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use RxError;
+
+    use pnet::packet::MutablePacket;
+    use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet};
+
+    use std::net::Ipv4Addr;
+
+    #[test]
+    fn write_parse() {
+        let options = vec![Ipv4Option::RouterAlert(0),
+                           Ipv4Option::NoOperation,
+                           Ipv4Option::LooseSourceRoute {
+                               pointer: 4,
+                               route: vec![Ipv4Addr::new(10, 0, 0, 1),
+                                           Ipv4Addr::new(10, 0, 0, 2)],
+                           },
+                           Ipv4Option::Timestamp {
+                               pointer: 5,
+                               overflow: 2,
+                               flag: 1,
+                               entries: vec![(Some(Ipv4Addr::new(10, 0, 0, 3)), 0x01020304)],
+                           },
+                           Ipv4Option::Unknown(30, vec![9, 9])];
+        let len = options_length(&options);
+        assert_eq!(4 + 1 + 11 + 12 + 4, len);
+
+        let pkg = create_packet(&options, true);
+        assert_eq!(options, parse_options(&pkg.to_immutable()).unwrap());
+    }
+
+    #[test]
+    fn write_parse_timestamp() {
+        let options = vec![Ipv4Option::Timestamp {
+                               pointer: 13,
+                               overflow: 0,
+                               flag: 3,
+                               entries: vec![(Some(Ipv4Addr::new(10, 0, 0, 1)), 7),
+                                             (None, 0),
+                                             (Some(Ipv4Addr::new(10, 0, 0, 2)), 0)],
+                           },
+                           Ipv4Option::Timestamp {
+                               pointer: 5,
+                               overflow: 1,
+                               flag: 0,
+                               entries: vec![(None, 0x01020304), (None, 0)],
+                           }];
+        assert_eq!(4 + 3 * 8 + 4 + 2 * 4, options_length(&options));
+
+        let pkg = create_packet(&options, true);
+        assert_eq!(options, parse_options(&pkg.to_immutable()).unwrap());
+    }
+
+    #[test]
+    fn timestamp_valid() {
+        let entries = vec![(Some(Ipv4Addr::new(10, 0, 0, 1)), 0)];
+        assert!(Ipv4Option::Timestamp {
+                pointer: 5,
+                overflow: 0,
+                flag: 1,
+                entries: entries.clone(),
+            }
+            .is_valid());
+        assert!(!Ipv4Option::Timestamp {
+                pointer: 5,
+                overflow: 0,
+                flag: 0,
+                entries: entries.clone(),
+            }
+            .is_valid());
+        assert!(!Ipv4Option::Timestamp {
+                pointer: 5,
+                overflow: 0,
+                flag: 2,
+                entries: entries,
+            }
+            .is_valid());
+    }
+
+    #[test]
+    fn write_not_first_fragment() {
+        let options = vec![Ipv4Option::RecordRoute {
+                               pointer: 4,
+                               route: vec![Ipv4Addr::new(0, 0, 0, 0)],
+                           },
+                           Ipv4Option::RouterAlert(0)];
+        let pkg = create_packet(&options, false);
+        let mut expected = vec![Ipv4Option::NoOperation; 7];
+        expected.push(Ipv4Option::RouterAlert(0));
+        assert_eq!(expected, parse_options(&pkg.to_immutable()).unwrap());
+    }
+
+    #[test]
+    fn copied() {
+        assert!(Ipv4Option::StrictSourceRoute {
+                pointer: 4,
+                route: vec![],
+            }
+            .is_copied());
+        assert!(Ipv4Option::RouterAlert(0).is_copied());
+        assert!(!Ipv4Option::Timestamp {
+                pointer: 5,
+                overflow: 0,
+                flag: 0,
+                entries: vec![],
+            }
+            .is_copied());
+        assert!(!Ipv4Option::NoOperation.is_copied());
+    }
+
+    #[test]
+    fn parse_invalid_length() {
+        // Option length pointing past the end of the header
+        let pkg = create_raw_packet(&[ROUTER_ALERT, 8, 0, 0]);
+        assert_eq!(Err(RxError::InvalidLength), parse_options(&pkg.to_immutable()));
+        // Option length shorter than the option header
+        let pkg = create_raw_packet(&[30, 1, 0, 0]);
+        assert_eq!(Err(RxError::InvalidLength), parse_options(&pkg.to_immutable()));
+        // Route not a multiple of four bytes
+        let pkg = create_raw_packet(&[RECORD_ROUTE, 4, 4, 0]);
+        assert_eq!(Err(RxError::InvalidLength), parse_options(&pkg.to_immutable()));
+    }
+
+    #[test]
+    fn parse_invalid_content() {
+        let pkg = create_raw_packet(&[RECORD_ROUTE, 7, 3, 0, 0, 0, 0, END_OF_LIST]);
+        assert_eq!(Err(RxError::InvalidContent), parse_options(&pkg.to_immutable()));
+    }
+
+    #[test]
+    fn parse_end_of_list() {
+        let pkg = create_raw_packet(&[NO_OPERATION, END_OF_LIST, 99, 99]);
+        assert_eq!(vec![Ipv4Option::NoOperation],
+                   parse_options(&pkg.to_immutable()).unwrap());
+    }
+
+    fn create_packet(options: &[Ipv4Option], first_fragment: bool) -> MutableIpv4Packet<'static> {
+        let mut data = vec![0; options_length(options)];
+        write_options(options, &mut data, first_fragment);
+        create_raw_packet(&data)
+    }
+
+    fn create_raw_packet(options: &[u8]) -> MutableIpv4Packet<'static> {
+        let header_length = Ipv4Packet::minimum_packet_size() + options.len();
+        let mut pkg = MutableIpv4Packet::owned(vec![0; header_length]).unwrap();
+        pkg.set_header_length((header_length / 4) as u8);
+        pkg.set_total_length(header_length as u16);
+        pkg.packet_mut()[Ipv4Packet::minimum_packet_size()..].copy_from_slice(options);
+        pkg
+    }
+}
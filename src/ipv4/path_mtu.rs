@@ -0,0 +1,204 @@
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use util::CacheMap;
+
+/// The smallest MTU every IPv4 link must support, RFC 791.
+pub const MIN_PATH_MTU: usize = 68;
+
+/// Default number of seconds a lowered path MTU is kept before the stack
+/// tries the MTU of the interface again. The value recommended by RFC 1191.
+pub const DEFAULT_PATH_MTU_TIMEOUT_SECS: u64 = 600;
+
+/// Common MTUs, as listed in RFC 1191. Used to guess the path MTU when a
+/// router does not report the MTU of the next hop.
+const MTU_PLATEAUS: [usize; 11] = [65535, 32000, 17914, 8166, 4352, 2002, 1492, 1006, 508, 296,
+                                   MIN_PATH_MTU];
+
+struct PathMtuData {
+    table: CacheMap<Ipv4Addr, usize>,
+    enabled: bool,
+    change_callback: Option<Box<Fn() + Send>>,
+}
+
+impl PathMtuData {
+    fn changed(&self) {
+        if let Some(ref callback) = self.change_callback {
+            callback();
+        }
+    }
+}
+
+/// Cache of discovered path MTUs per destination, as described in RFC 1191.
+/// Shared between the stack, that uses it when creating tx-objects, and
+/// `IcmpRx`, that lowers the path MTU when Icmp "fragmentation needed"
+/// messages arrive.
+#[derive(Clone)]
+pub struct PathMtuCache {
+    data: Arc<Mutex<PathMtuData>>,
+}
+
+impl PathMtuCache {
+    /// Creates a new `PathMtuCache` with no entries and path MTU discovery
+    /// enabled.
+    pub fn new() -> PathMtuCache {
+        let data = PathMtuData {
+            table: CacheMap::new(Duration::from_secs(DEFAULT_PATH_MTU_TIMEOUT_SECS)),
+            enabled: true,
+            change_callback: None,
+        };
+        PathMtuCache { data: Arc::new(Mutex::new(data)) }
+    }
+
+    /// Sets a callback that is called every time a path MTU is lowered or
+    /// restored, or discovery is turned on or off. Used by the stack to
+    /// invalidate existing tx-objects. The callback is called with the cache
+    /// locked.
+    pub fn set_change_callback<F>(&mut self, callback: F)
+        where F: Fn() + Send + 'static
+    {
+        self.data.lock().unwrap().change_callback = Some(Box::new(callback));
+    }
+
+    /// Returns `true` if path MTU discovery is enabled. When enabled, outgoing
+    /// packets that fit the path MTU are sent with the Don't-Fragment flag.
+    pub fn is_enabled(&self) -> bool {
+        self.data.lock().unwrap().enabled
+    }
+
+    /// Turns path MTU discovery on or off. Turning it off clears the cache.
+    pub fn set_enabled(&mut self, enabled: bool) {
+        let mut data = self.data.lock().unwrap();
+        data.enabled = enabled;
+        if !enabled {
+            data.table.clear();
+        }
+        data.changed();
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.data.lock().unwrap().table.timeout()
+    }
+
+    /// Sets for how long a lowered path MTU is used before it's forgotten.
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.data.lock().unwrap().table.set_timeout(timeout);
+    }
+
+    /// Returns the discovered path MTU to `dst`, if it's lower than the MTU
+    /// of the interface.
+    pub fn get(&self, dst: Ipv4Addr) -> Option<usize> {
+        self.data.lock().unwrap().table.get(&dst).cloned()
+    }
+
+    /// Lowers the path MTU to `dst`. Values lower than the current path MTU
+    /// or `MIN_PATH_MTU` are ignored, since the cache never raises a path
+    /// MTU before it times out. Returns `true` if the path MTU was lowered.
+    pub fn update(&mut self, dst: Ipv4Addr, mtu: usize) -> bool {
+        let mut data = self.data.lock().unwrap();
+        if !data.enabled || mtu < MIN_PATH_MTU {
+            return false;
+        }
+        if data.table.get(&dst).map_or(false, |&current| current <= mtu) {
+            return false;
+        }
+        data.table.insert(dst, mtu);
+        data.changed();
+        true
+    }
+
+    /// Forgets the path MTU to `dst`. Returns `true` if there was an entry.
+    pub fn remove(&mut self, dst: Ipv4Addr) -> bool {
+        let mut data = self.data.lock().unwrap();
+        let removed = data.table.remove(&dst).is_some();
+        if removed {
+            data.changed();
+        }
+        removed
+    }
+
+    /// Purges path MTUs that have timed out, so the MTU of the interface is
+    /// tried again. Returns the destinations that were purged.
+    pub fn expire(&mut self) -> Vec<Ipv4Addr> {
+        let mut data = self.data.lock().unwrap();
+        let expired = data.table.expire();
+        if !expired.is_empty() {
+            data.changed();
+        }
+        expired
+    }
+}
+
+impl Default for PathMtuCache {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Guesses the path MTU from the total length of a packet that was too
+/// large, for routers that don't report the MTU of the next hop.
+pub fn plateau_below(total_length: usize) -> usize {
+    *MTU_PLATEAUS.iter().find(|&&mtu| mtu < total_length).unwrap_or(&MIN_PATH_MTU)
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::net::Ipv4Addr;
+    use std::sync::{Arc, Mutex};
+    use std::thread::sleep;
+    use std::time::Duration;
+
+    lazy_static! {
+        static ref DST: Ipv4Addr = Ipv4Addr::new(10, 0, 0, 1);
+    }
+
+    #[test]
+    fn update_only_lowers() {
+        let mut testee = PathMtuCache::new();
+        let changes = Arc::new(Mutex::new(0));
+        {
+            let changes = changes.clone();
+            testee.set_change_callback(move || *changes.lock().unwrap() += 1);
+        }
+        assert!(testee.update(*DST, 1400));
+        assert!(!testee.update(*DST, 1450));
+        assert!(testee.update(*DST, 1000));
+        assert!(!testee.update(*DST, 10));
+        assert_eq!(Some(1000), testee.get(*DST));
+        assert_eq!(2, *changes.lock().unwrap());
+    }
+
+    #[test]
+    fn disabled() {
+        let mut testee = PathMtuCache::new();
+        testee.update(*DST, 1400);
+        testee.set_enabled(false);
+        assert!(!testee.is_enabled());
+        assert_eq!(None, testee.get(*DST));
+        assert!(!testee.update(*DST, 1000));
+        assert_eq!(None, testee.get(*DST));
+    }
+
+    #[test]
+    fn expire() {
+        let mut testee = PathMtuCache::new();
+        testee.set_timeout(Duration::from_millis(10));
+        testee.update(*DST, 1400);
+        assert!(testee.expire().is_empty());
+        sleep(Duration::from_millis(20));
+        assert_eq!(None, testee.get(*DST));
+        assert_eq!(vec![*DST], testee.expire());
+    }
+
+    #[test]
+    fn plateaus() {
+        assert_eq!(1492, plateau_below(1500));
+        assert_eq!(1006, plateau_below(1492));
+        assert_eq!(MIN_PATH_MTU, plateau_below(100));
+        assert_eq!(MIN_PATH_MTU, plateau_below(20));
+    }
+}
@@ -0,0 +1,127 @@
+use super::{BasicIpv4Listener, IpNextHeaderProtocol, Ipv4Fields, Ipv4Tx};
+use {CustomPayload, DatalinkTx, NetworkStack, StackError, StackResult, Tx, TxError, TxResult};
+use ethernet::EthernetTx;
+
+use pnet::packet::ipv4::Ipv4Packet;
+
+use std::collections::HashMap;
+use std::io;
+use std::net::Ipv4Addr;
+use std::sync::{Arc, Mutex};
+use std::sync::mpsc::{self, Receiver};
+use std::time::SystemTime;
+
+/// A socket sending and receiving Ipv4 packets of one protocol, similar to a
+/// `SOCK_RAW` socket. Makes it possible to implement protocols on top of
+/// Ipv4 outside of rips. Protocols the stack handles itself, such as Udp,
+/// Icmp and Igmp, can't be bound.
+pub struct RawIpv4Socket {
+    local_ip: Ipv4Addr,
+    protocol: IpNextHeaderProtocol,
+    stack: Arc<Mutex<NetworkStack>>,
+    tx_cache: HashMap<Ipv4Addr, Ipv4Tx<EthernetTx<DatalinkTx>>>,
+    rx: Receiver<(SystemTime, Ipv4Packet<'static>)>,
+    ttl: Option<u8>,
+}
+
+impl RawIpv4Socket {
+    /// Binds a socket receiving all packets to `local_ip` carrying
+    /// `protocol`. Only one socket can be bound to each address and protocol.
+    /// `local_ip` must be a local address, 0.0.0.0 is not accepted.
+    pub fn bind(stack: Arc<Mutex<NetworkStack>>,
+                local_ip: Ipv4Addr,
+                protocol: IpNextHeaderProtocol)
+                -> io::Result<RawIpv4Socket> {
+        let (tx, rx) = mpsc::channel();
+        {
+            let mut stack = stack.lock().unwrap();
+            try!(stack.ipv4_listen(local_ip, protocol, BasicIpv4Listener { tx: tx }));
+        }
+        Ok(RawIpv4Socket {
+            local_ip: local_ip,
+            protocol: protocol,
+            stack: stack,
+            tx_cache: HashMap::new(),
+            rx: rx,
+            ttl: None,
+        })
+    }
+
+    /// Receives the next packet, header included. Blocks until one arrives.
+    pub fn recv(&self) -> io::Result<Ipv4Packet<'static>> {
+        self.rx.recv().map(|(_time, packet)| packet).map_err(|_| {
+            io::Error::new(io::ErrorKind::NotConnected,
+                           "Socket is no longer bound in the stack".to_owned())
+        })
+    }
+
+    /// Sends `buf` as the payload of a packet to `dst`. The header is built
+    /// by the stack, with the protocol of this socket.
+    pub fn send_to(&mut self, buf: &[u8], dst: Ipv4Addr) -> io::Result<usize> {
+        self.internal_send(buf, dst)
+            .map(|_| buf.len())
+            .map_err(|e| e.into())
+    }
+
+    pub fn local_addr(&self) -> io::Result<Ipv4Addr> {
+        Ok(self.local_ip)
+    }
+
+    pub fn protocol(&self) -> IpNextHeaderProtocol {
+        self.protocol
+    }
+
+    /// Sets the time to live of packets sent from this socket. Until this is
+    /// called the default TTL of the stack is used.
+    pub fn set_ttl(&mut self, ttl: u32) -> io::Result<()> {
+        if ttl > ::std::u8::MAX as u32 {
+            let msg = format!("Invalid TTL {}", ttl);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        self.ttl = Some(ttl as u8);
+        self.tx_cache.clear();
+        Ok(())
+    }
+
+    pub fn ttl(&self) -> io::Result<u32> {
+        let ttl = match self.ttl {
+            Some(ttl) => ttl,
+            None => self.stack.lock().unwrap().default_ttl(),
+        };
+        Ok(ttl as u32)
+    }
+
+    fn internal_send(&mut self, buf: &[u8], dst: Ipv4Addr) -> StackResult<()> {
+        match self.internal_send_on_cached_tx(buf, dst) {
+            None => {
+                let src = Some(self.local_ip);
+                let mut ipv4_tx = try!(self.stack.lock().unwrap().ipv4_tx_from(dst, src));
+                if let Some(ttl) = self.ttl {
+                    ipv4_tx.set_ttl(ttl);
+                }
+                self.tx_cache.insert(dst, ipv4_tx);
+                self.internal_send(buf, dst)
+            }
+            Some(result) => result.map_err(StackError::TxError),
+        }
+    }
+
+    fn internal_send_on_cached_tx(&mut self,
+                                  buf: &[u8],
+                                  dst: Ipv4Addr)
+                                  -> Option<TxResult<()>> {
+        if buf.len() > ::std::u16::MAX as usize - Ipv4Packet::minimum_packet_size() {
+            return Some(Err(TxError::TooLargePayload));
+        }
+        let mut payload = CustomPayload::new(Ipv4Fields(self.protocol), buf);
+        self.tx_cache.get_mut(&dst).and_then(|ipv4_tx| ipv4_tx.send(&mut payload))
+    }
+}
+
+impl Drop for RawIpv4Socket {
+    fn drop(&mut self) {
+        if let Ok(mut stack) = self.stack.lock() {
+            stack.ipv4_unlisten(self.local_ip, self.protocol);
+        }
+    }
+}
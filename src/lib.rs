@@ -55,22 +55,26 @@
 //! - [x] Arp
 //!   - [x] Sending
 //!   - [x] Parsing incoming responses
-//!   - [ ] Timing out old entries in table
+//!   - [x] Timing out old entries in table
 //! - [ ] IPv4
 //!   - [x] Standard send
 //!   - [x] Validate lengths and checksums as part of parsing incoming
 //!   - [x] Fragmenting outgoing packets
-//!   - [ ] Correctly pick an identification field on outgoing IPv4
-//!   - [ ] Reassembling incoming packets
+//!   - [x] Correctly pick an identification field on outgoing IPv4
+//!   - [x] Reassembling incoming packets
 //!     - [x] Works in standard case
-//!     - [ ] Timing out caches of packets that were never completed
-//!     - [ ] Support reassemble out of order fragments?
-//!   - [ ] Header options
+//!     - [x] Timing out caches of packets that were never completed
+//!     - [x] Support reassemble out of order fragments
+//!   - [x] Header options
 //!   - [ ] Routing
 //!     - [x] Works in standard case
 //!     - [ ] Invalidate existing Tx on update
 //!     - [ ] Metrics
-//!   - [ ] Possible to change TTL
+//!   - [x] Possible to change TTL
+//!   - [x] Path MTU discovery
+//!   - [x] Sending and receiving broadcasts
+//!   - [x] Multicast with IGMPv2 and IGMPv3
+//!   - [x] Raw sockets for protocols not implemented in rips
 //! - [ ] IPv6
 //!   - [ ] Path MTU discovery
 //! - [ ] Icmp
@@ -81,8 +85,8 @@
 //! - [ ] Udp
 //!   - [x] Sending Udp packets
 //!   - [x] Provide API similar to Rusts standard `UdpSocket`
-//!   - [ ] Provide improved API for separated sending and receiving
-//!   - [ ] Correctly close and clean up closed sockets
+//!   - [x] Provide improved API for separated sending and receiving
+//!   - [x] Correctly close and clean up closed sockets
 //! - [ ] Tcp
 //!
 //! ## Architecture and terminology
@@ -161,7 +165,6 @@
 //!
 //! * If it's possible to have the same IP on multiple interfaces, which one
 //!   will a socket bound to that local IP receive packets from? Both?
-//! * Should the IP layer reassemble fragmented packets that are out of order?
 //! * Should the `FooTx` structs not contain the underlying `BarTx` and do the
 //!   sending internally. But instead be agnostic of the underlying protocol.
 //!
@@ -175,6 +178,9 @@ extern crate ipnetwork;
 #[cfg(test)]
 #[macro_use]
 extern crate lazy_static;
+#[cfg(test)]
+#[macro_use]
+extern crate assert_matches;
 
 #[macro_use]
 extern crate log;
@@ -207,6 +213,9 @@ pub mod ipv4;
 /// Module containing internet control message procotol (icmp) functionality
 pub mod icmp;
 
+/// Module containing internet group management protocol (IGMP) functionality
+pub mod igmp;
+
 /// Module containing Udp functionality.
 pub mod udp;
 
@@ -11,6 +11,9 @@ pub struct RouteEntry {
     pub net: Ipv4Network,
     pub gw: Option<Ipv4Addr>,
     pub interface: Interface,
+    /// The local address packets sent along this route should have as
+    /// source. Picked by the interface if not given.
+    pub src: Option<Ipv4Addr>,
 }
 
 impl RouteEntry {
@@ -19,11 +22,12 @@ impl RouteEntry {
             net: net,
             gw: gw,
             interface: interface,
+            src: None,
         }
     }
 }
 
-#[derive(Default)]
+#[derive(Default, Clone)]
 pub struct RoutingTable {
     table: BTreeMap<u8, Vec<RouteEntry>>,
 }
@@ -36,8 +40,22 @@ impl RoutingTable {
     // TODO: Check for collision
     // TODO: Increment Tx version counter
     pub fn add_route(&mut self, net: Ipv4Network, gw: Option<Ipv4Addr>, interface: Interface) {
-        let prefix = net.prefix();
-        let entry = RouteEntry::new(net, gw, interface);
+        self.add_entry(RouteEntry::new(net, gw, interface));
+    }
+
+    /// Adds a route whose packets are sent from the local address `src`.
+    pub fn add_route_with_src(&mut self,
+                              net: Ipv4Network,
+                              gw: Option<Ipv4Addr>,
+                              interface: Interface,
+                              src: Ipv4Addr) {
+        let mut entry = RouteEntry::new(net, gw, interface);
+        entry.src = Some(src);
+        self.add_entry(entry);
+    }
+
+    fn add_entry(&mut self, entry: RouteEntry) {
+        let prefix = entry.net.prefix();
         self.table.entry(prefix).or_insert_with(Vec::new).push(entry);
     }
 
@@ -46,11 +64,36 @@ impl RoutingTable {
         self.add_route(net, gw, interface);
     }
 
+    /// Removes a route added with `add_route`. Returns `false` if there was
+    /// no such route.
+    pub fn remove_route(&mut self,
+                        net: Ipv4Network,
+                        gw: Option<Ipv4Addr>,
+                        interface: &Interface)
+                        -> bool {
+        if let Some(entries) = self.table.get_mut(&net.prefix()) {
+            let position = entries.iter().position(|entry| {
+                entry.net == net && entry.gw == gw && entry.interface == *interface &&
+                entry.src.is_none()
+            });
+            if let Some(position) = position {
+                entries.remove(position);
+                return true;
+            }
+        }
+        false
+    }
+
     pub fn route(&self, ip: Ipv4Addr) -> Option<(Option<Ipv4Addr>, Interface)> {
+        self.route_entry(ip).map(|entry| (entry.gw, entry.interface.clone()))
+    }
+
+    /// Returns the entry with the longest prefix matching `ip`.
+    pub fn route_entry(&self, ip: Ipv4Addr) -> Option<&RouteEntry> {
         for (_prefix, entries) in self.table.iter().rev() {
             for entry in entries {
                 if entry.net.contains(ip) {
-                    return Some((entry.gw, entry.interface.clone()));
+                    return Some(entry);
                 }
             }
         }
@@ -68,11 +111,13 @@ impl RoutingTable {
 
 pub struct StackRoutingTable<'a> {
     table: &'a mut RoutingTable,
-    change_callback: Box<FnMut() + 'a>,
+    change_callback: Box<FnMut(&RoutingTable) + 'a>,
 }
 
 impl<'a> StackRoutingTable<'a> {
-    pub fn new(table: &'a mut RoutingTable, change_callback: Box<FnMut() + 'a>) -> Self {
+    pub fn new(table: &'a mut RoutingTable,
+               change_callback: Box<FnMut(&RoutingTable) + 'a>)
+               -> Self {
         StackRoutingTable {
             table: table,
             change_callback: change_callback,
@@ -81,18 +126,31 @@ impl<'a> StackRoutingTable<'a> {
 
     pub fn add_route(&mut self, net: Ipv4Network, gw: Option<Ipv4Addr>, interface: Interface) {
         self.table.add_route(net, gw, interface);
-        (self.change_callback)();
+        (self.change_callback)(&*self.table);
+    }
+
+    pub fn add_route_with_src(&mut self,
+                              net: Ipv4Network,
+                              gw: Option<Ipv4Addr>,
+                              interface: Interface,
+                              src: Ipv4Addr) {
+        self.table.add_route_with_src(net, gw, interface, src);
+        (self.change_callback)(&*self.table);
     }
 
     pub fn add_default_route(&mut self, gw: Option<Ipv4Addr>, interface: Interface) {
         self.table.add_default_route(gw, interface);
-        (self.change_callback)();
+        (self.change_callback)(&*self.table);
     }
 
     pub fn route(&self, ip: Ipv4Addr) -> Option<(Option<Ipv4Addr>, Interface)> {
         self.table.route(ip)
     }
 
+    pub fn route_entry(&self, ip: Ipv4Addr) -> Option<&RouteEntry> {
+        self.table.route_entry(ip)
+    }
+
     pub fn get_entries(&self) -> Vec<RouteEntry> {
         self.table.get_entries()
     }
@@ -166,6 +224,37 @@ mod tests {
         assert_eq!(out_eth2, iface("eth1"));
     }
 
+    #[test]
+    fn with_src() {
+        let src = Ipv4Addr::new(10, 0, 0, 2);
+
+        let mut table = RoutingTable::new();
+        table.add_route(Ipv4Network::from_str("10/8").unwrap(), None, iface("eth0"));
+        table.add_route_with_src(Ipv4Network::from_str("10.1/16").unwrap(),
+                                 None,
+                                 iface("eth0"),
+                                 src);
+
+        assert_eq!(None, table.route_entry(Ipv4Addr::new(10, 0, 0, 1)).unwrap().src);
+        assert_eq!(Some(src),
+                   table.route_entry(Ipv4Addr::new(10, 1, 0, 1)).unwrap().src);
+    }
+
+    #[test]
+    fn remove() {
+        let net = Ipv4Network::from_str("10/8").unwrap();
+
+        let mut table = RoutingTable::new();
+        table.add_route(net, None, iface("eth0"));
+        table.add_route(net, None, iface("eth1"));
+        assert!(!table.remove_route(net, None, &iface("eth2")));
+        assert!(table.remove_route(net, None, &iface("eth0")));
+        let (_, out_eth) = table.route(Ipv4Addr::new(10, 0, 0, 1)).unwrap();
+        assert_eq!(out_eth, iface("eth1"));
+        assert!(table.remove_route(net, None, &iface("eth1")));
+        assert!(table.route(Ipv4Addr::new(10, 0, 0, 1)).is_none());
+    }
+
     #[test]
     fn get_entries_empty() {
         let table = RoutingTable::new();
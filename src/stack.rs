@@ -1,32 +1,45 @@
-use {EthernetChannel, Interface, TxError, TxResult, Tx, Payload};
+use {CustomPayload, EthernetChannel, Interface, RxError, RxResult, TxError, TxResult, Tx,
+     Payload};
 use StackError;
-use arp::{self, ArpPayload, ArpTx, ArpTable, ArpRx};
-use ethernet::{EthernetRx, EthernetTx, MacAddr, EthernetListener};
-use icmp::{IcmpTx, IcmpType, IcmpRx, IcmpListener, IcmpListenerLookup};
+use arp::{self, ArpInsertResult, ArpPayload, ArpProbeConfig, ArpRequestConfig, ArpTx, ArpTable,
+          ArpRx};
+use ethernet::{EthernetFields, EthernetRx, EthernetTx, EtherTypes, MacAddr, EthernetListener,
+               multicast_mac};
+use icmp::{DestinationUnreachableCodes, IcmpFields, IcmpTx, IcmpType, IcmpTypes, IcmpRx,
+           IcmpListener, IcmpListenerLookup, TimeExceededCodes};
+use igmp::{IgmpMessage, IgmpRx, MulticastMemberships, all_systems};
 
 use ipnetwork::Ipv4Network;
 
-use ipv4::{Ipv4Tx, Ipv4Rx, IpNextHeaderProtocols, Ipv4Listener, IpListenerLookup};
+use ipv4::{DEFAULT_TTL, DONT_FRAGMENT, IdentificationGenerator, IdentificationSource, Ipv4Fields,
+           Ipv4Option, Ipv4Tx, Ipv4Rx, Ipv4RxStats, IpNextHeaderProtocol, IpNextHeaderProtocols,
+           Ipv4Listener, IpListenerLookup, LocalAddresses, MIN_PATH_MTU, PathMtuCache, Reassembler,
+           ReassemblyConfig, ReassemblyStats, ReassemblyTimeout, fragment_packet,
+           is_directed_broadcast};
 
 use pnet;
 use pnet::datalink::EthernetDataLinkSender;
-use pnet::packet::MutablePacket;
+use pnet::packet::{MutablePacket, Packet};
 use pnet::packet::ethernet::MutableEthernetPacket;
+use pnet::packet::icmp::IcmpPacket;
+use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet, checksum};
 
 use rand;
 use rand::distributions::{IndependentSample, Range};
 
-use routing::{RoutingTable, StackRoutingTable};
+use routing::{RouteEntry, RoutingTable, StackRoutingTable};
 use rx;
 
 use std::cmp;
-use std::collections::{HashMap, HashSet};
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::collections::hash_map::Entry;
 use std::io;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs};
 use std::sync::{Arc, Mutex, RwLock};
-use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
 use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, SystemTime};
 use udp::{self, UdpTx};
 use util;
 
@@ -34,22 +47,74 @@ pub static DEFAULT_MTU: usize = 1500;
 pub static DEFAULT_BUFFER_SIZE: usize = 1024 * 128;
 pub static LOCAL_PORT_RANGE_START: u16 = 32768;
 pub static LOCAL_PORT_RANGE_END: u16 = 61000;
+/// How often, in milliseconds, the thread of each `StackInterface` performs
+/// periodic housekeeping such as timing out old Arp entries.
+pub static TICK_INTERVAL_MS: u64 = 100;
 
 pub type StackResult<T> = Result<T, StackError>;
 
+/// Callback called when packets waiting for a neighbour to be resolved are
+/// dropped. Called with the IP of the neighbour, the number of dropped
+/// packets and the reason they were dropped.
+pub type PendingDropCallback = Box<Fn(Ipv4Addr, usize, StackError) + Send>;
+
+/// Callback called when another host is detected using one of the addresses
+/// of an interface. Called with the conflicting IP and the MAC of the other
+/// host.
+pub type AddressConflictCallback = Box<Fn(Ipv4Addr, MacAddr) + Send>;
+
 pub enum StackInterfaceMsg {
     UpdateArpTable(Ipv4Addr, MacAddr),
     ArpRequest(Ipv4Addr, MacAddr, Ipv4Addr),
+    /// Start resolving the second IP, using the first IP as sender address in
+    /// the requests. Ignored if a resolution of that IP is already ongoing.
+    ArpResolve(Ipv4Addr, Ipv4Addr, ArpRequestConfig),
+    /// An ethernet frame waiting for the MAC of the given IP to be resolved.
+    /// The destination MAC in the frame is filled in before it's sent.
+    QueuePacket(Ipv4Addr, Box<[u8]>),
+    SetPendingDropCallback(PendingDropCallback),
+    /// Probe for conflicts on the IP before claiming it, then announce it.
+    ProbeAddress(Ipv4Addr, ArpProbeConfig),
+    /// Stop probing for the IP, that was removed from the interface.
+    AbortProbe(Ipv4Addr),
+    SetAddressConflictCallback(AddressConflictCallback),
     Shutdown,
 }
 
+/// Decides which Arp requests an interface answers on behalf of other hosts.
+#[derive(Default)]
+struct ProxyArp {
+    /// Networks to answer Arp requests for.
+    networks: Vec<Ipv4Network>,
+    /// If requests for IPs routed out of other interfaces should be answered.
+    routed: bool,
+    /// Copy of the routing table of the stack, used when `routed` is set.
+    routes: RoutingTable,
+}
+
 struct StackInterfaceData {
     interface: Interface,
     tx: Arc<Mutex<TxBarrier>>,
     ipv4_addresses: RwLock<HashSet<Ipv4Addr>>,
+    proxy_arp: RwLock<ProxyArp>,
+    mtu: AtomicUsize,
+    /// The time to live of packets sent on the interface. Kept in sync with
+    /// the default TTL of the stack.
+    default_ttl: AtomicUsize,
+    /// Where the identification of datagrams sent on the interface is taken
+    /// from. Shared with the rest of the stack.
+    identification: IdentificationSource,
 }
 
 impl StackInterfaceData {
+    fn mtu(&self) -> usize {
+        self.mtu.load(Ordering::SeqCst)
+    }
+
+    fn default_ttl(&self) -> u8 {
+        self.default_ttl.load(Ordering::SeqCst) as u8
+    }
+
     fn tx(&self) -> DatalinkTx {
         let version = self.tx.lock().unwrap().version();
         DatalinkTx::new(self.tx.clone(), version)
@@ -59,6 +124,31 @@ impl StackInterfaceData {
         EthernetTx::new(self.interface.mac, dst, self.tx())
     }
 
+    fn ipv4_tx(&self,
+               ethernet_tx: EthernetTx<DatalinkTx>,
+               src: Ipv4Addr,
+               dst: Ipv4Addr,
+               mtu: usize)
+               -> Ipv4Tx<EthernetTx<DatalinkTx>> {
+        let mut ipv4_tx = Ipv4Tx::new(ethernet_tx, src, dst, mtu);
+        ipv4_tx.set_ttl(self.default_ttl());
+        ipv4_tx.set_identification_source(self.identification.clone());
+        ipv4_tx
+    }
+
+    /// Creates an `EthernetTx` that does not send anything on the network.
+    /// Everything sent through it is instead queued in the interface thread
+    /// behind `thread_tx` until the MAC of `neighbour` has been resolved.
+    fn pending_ethernet_tx(&self,
+                           neighbour: Ipv4Addr,
+                           thread_tx: &Sender<StackInterfaceMsg>)
+                           -> EthernetTx<DatalinkTx> {
+        let version = self.tx.lock().unwrap().version();
+        let tx = DatalinkTx::pending(self.tx.clone(), version, neighbour, thread_tx.clone());
+        let unknown_mac = MacAddr(0, 0, 0, 0, 0, 0);
+        EthernetTx::new(self.interface.mac, unknown_mac, tx)
+    }
+
     fn arp_request_tx(&self) -> ArpTx<EthernetTx<DatalinkTx>> {
         let dst = MacAddr(0xff, 0xff, 0xff, 0xff, 0xff, 0xff);
         ArpTx::new(self.ethernet_tx(dst))
@@ -68,16 +158,83 @@ impl StackInterfaceData {
         ArpTx::new(self.ethernet_tx(dst))
     }
 
+    fn send_arp_request(&self, src: Ipv4Addr, target: Ipv4Addr) {
+        let payload = ArpPayload::request(self.interface.mac, src, target);
+        self.send_arp_broadcast(payload);
+    }
+
+    fn send_arp_broadcast(&self, mut payload: ArpPayload) {
+        if let Err(e) = tx_send!(|| self.arp_request_tx(); &mut payload) {
+            error!("Unable to send arp request for {}: {}", payload.target_ip, e);
+        }
+    }
+
+    /// Sends an IGMP message with a TTL of one and the Router Alert option,
+    /// as required by RFC 2236 and RFC 3376. Sent from the lowest address of
+    /// the interface, or 0.0.0.0 if it has none.
+    fn send_igmp(&self, message: &IgmpMessage) {
+        let src = self.ipv4_addresses
+            .read()
+            .unwrap()
+            .iter()
+            .min()
+            .cloned()
+            .unwrap_or(Ipv4Addr::new(0, 0, 0, 0));
+        let dst = message.destination();
+        let buffer = message.build();
+        let mut payload = CustomPayload::new(Ipv4Fields(IpNextHeaderProtocols::Igmp), &buffer);
+        let result = tx_send!(|| {
+            let ethernet_tx = self.ethernet_tx(multicast_mac(dst));
+            let mut ipv4_tx = self.ipv4_tx(ethernet_tx, src, dst, self.mtu());
+            ipv4_tx.set_ttl(1);
+            ipv4_tx.set_options(vec![Ipv4Option::RouterAlert(0)]);
+            ipv4_tx
+        }; &mut payload);
+        if let Err(e) = result {
+            error!("Unable to send IGMP message to {}: {}", dst, e);
+        }
+    }
+
     fn inc(&self) {
         self.tx.lock().unwrap().inc();
     }
 }
 
 
+/// An outgoing Arp request waiting for a reply. Shared by everyone waiting
+/// for the same IP to be resolved. Holds the frames that will be sent to the
+/// IP once it's resolved.
+struct PendingArpRequest {
+    src: Ipv4Addr,
+    config: ArpRequestConfig,
+    retries: u32,
+    next_send: Instant,
+    deadline: Instant,
+    queue: VecDeque<Box<[u8]>>,
+}
+
+/// An address that is being probed for conflicts before it's claimed, or
+/// announced after it was claimed.
+struct AddressProbe {
+    config: ArpProbeConfig,
+    probes_sent: u32,
+    announcements_sent: u32,
+    next_send: Instant,
+}
+
 struct StackInterfaceThread {
     queue: Receiver<StackInterfaceMsg>,
     data: Arc<StackInterfaceData>,
     arp_table: ArpTable,
+    reassembler: Arc<Mutex<Reassembler>>,
+    path_mtu: PathMtuCache,
+    memberships: Arc<Mutex<MulticastMemberships>>,
+    local_addresses: Arc<Mutex<LocalAddresses>>,
+    arp_requests: HashMap<Ipv4Addr, PendingArpRequest>,
+    pending_drop_callback: Option<PendingDropCallback>,
+    address_probes: HashMap<Ipv4Addr, AddressProbe>,
+    address_conflict_callback: Option<AddressConflictCallback>,
+    last_tick: Instant,
 }
 
 struct StackInterfaceThreadHandle {
@@ -95,12 +252,27 @@ impl Drop for StackInterfaceThreadHandle {
 }
 
 impl StackInterfaceThread {
-    pub fn spawn(data: Arc<StackInterfaceData>, arp_table: ArpTable) -> StackInterfaceThreadHandle {
+    pub fn spawn(data: Arc<StackInterfaceData>,
+                 arp_table: ArpTable,
+                 reassembler: Arc<Mutex<Reassembler>>,
+                 path_mtu: PathMtuCache,
+                 memberships: Arc<Mutex<MulticastMemberships>>,
+                 local_addresses: Arc<Mutex<LocalAddresses>>)
+                 -> StackInterfaceThreadHandle {
         let (thread_tx, rx) = mpsc::channel();
         let stack_interface_thread = StackInterfaceThread {
             queue: rx,
             data: data,
             arp_table: arp_table,
+            reassembler: reassembler,
+            path_mtu: path_mtu,
+            memberships: memberships,
+            local_addresses: local_addresses,
+            arp_requests: HashMap::new(),
+            pending_drop_callback: None,
+            address_probes: HashMap::new(),
+            address_conflict_callback: None,
+            last_tick: Instant::now(),
         };
         let thread_handle = thread::spawn(move || { stack_interface_thread.run(); });
         StackInterfaceThreadHandle {
@@ -110,14 +282,66 @@ impl StackInterfaceThread {
     }
 
     fn run(mut self) {
-        while let Ok(msg) = self.queue.recv() {
-            if !self.process_msg(msg) {
-                break;
+        let tick_interval = Duration::from_millis(TICK_INTERVAL_MS);
+        loop {
+            match self.queue.recv_timeout(tick_interval) {
+                Ok(msg) => {
+                    if !self.process_msg(msg) {
+                        break;
+                    }
+                }
+                Err(RecvTimeoutError::Timeout) => (),
+                Err(RecvTimeoutError::Disconnected) => break,
+            }
+            if self.last_tick.elapsed() >= tick_interval {
+                self.last_tick = Instant::now();
+                self.tick();
             }
         }
         debug!("StackInterfaceThread is quitting");
     }
 
+    /// Periodic housekeeping. Called roughly every `TICK_INTERVAL_MS`.
+    fn tick(&mut self) {
+        let expired = self.arp_table.expire();
+        if !expired.is_empty() {
+            debug!("Arp entries expired: {:?}", expired);
+        }
+        self.retransmit_arp_requests();
+        self.advance_address_probes();
+        let timeouts = self.reassembler.lock().unwrap().expire();
+        for timeout in timeouts {
+            self.send_reassembly_time_exceeded(timeout);
+        }
+        let expired = self.path_mtu.expire();
+        if !expired.is_empty() {
+            debug!("Path MTUs expired: {:?}", expired);
+        }
+        let reports = self.memberships.lock().unwrap().expire();
+        for report in reports {
+            self.data.send_igmp(&report);
+        }
+    }
+
+    /// Tells the source of a packet that it could not be reassembled in time,
+    /// as described in RFC 792.
+    fn send_reassembly_time_exceeded(&self, timeout: ReassemblyTimeout) {
+        if !self.is_claimed(timeout.dst) || timeout.src.is_broadcast() ||
+           timeout.src.is_multicast() {
+            return;
+        }
+        let code = TimeExceededCodes::FragmentReasemblyTimeExceeded;
+        let mut payload = CustomPayload::new(IcmpFields::time_exceeded(code), &timeout.data);
+        let result = tx_send!(|| {
+            let ethernet_tx = self.data.ethernet_tx(timeout.src_mac);
+            let mtu = self.data.mtu();
+            IcmpTx::new(self.data.ipv4_tx(ethernet_tx, timeout.dst, timeout.src, mtu))
+        }; &mut payload);
+        if let Err(e) = result {
+            error!("Unable to send Icmp Time Exceeded to {}: {}", timeout.src, e);
+        }
+    }
+
     fn process_msg(&mut self, msg: StackInterfaceMsg) -> bool {
         use self::StackInterfaceMsg::*;
         match msg {
@@ -125,14 +349,136 @@ impl StackInterfaceThread {
             ArpRequest(sender_ip, sender_mac, target_ip) => {
                 self.handle_arp_request(sender_ip, sender_mac, target_ip)
             }
+            ArpResolve(src, target_ip, config) => self.resolve_arp(src, target_ip, config),
+            QueuePacket(ip, frame) => self.queue_packet(ip, frame),
+            SetPendingDropCallback(callback) => self.pending_drop_callback = Some(callback),
+            ProbeAddress(ip, config) => self.probe_address(ip, config),
+            AbortProbe(ip) => {
+                self.address_probes.remove(&ip);
+            }
+            SetAddressConflictCallback(callback) => {
+                self.address_conflict_callback = Some(callback)
+            }
             Shutdown => return false,
         }
         true
     }
 
     fn update_arp(&mut self, ip: Ipv4Addr, mac: MacAddr) {
-        if self.arp_table.insert(ip, mac) {
-            self.data.inc();
+        if self.is_own_address(ip) {
+            self.address_conflict(ip, mac);
+            return;
+        }
+        let request = self.arp_requests.remove(&ip);
+        // Queued packets go to the MAC the table ended up with
+        let mac = match self.arp_table.insert(ip, mac) {
+            ArpInsertResult::Rejected(table_mac) => {
+                debug!("Arp update of {} to {} rejected, keeping {}", ip, mac, table_mac);
+                table_mac
+            }
+            _ => mac,
+        };
+        if let Some(request) = request {
+            self.flush_queue(request.queue, mac);
+        }
+    }
+
+    fn resolve_arp(&mut self, src: Ipv4Addr, target_ip: Ipv4Addr, config: ArpRequestConfig) {
+        if self.arp_requests.contains_key(&target_ip) {
+            return;
+        }
+        self.data.send_arp_request(src, target_ip);
+        let now = Instant::now();
+        let request = PendingArpRequest {
+            src: src,
+            config: config,
+            retries: 0,
+            next_send: now + config.retransmit_time,
+            deadline: now + config.timeout,
+            queue: VecDeque::new(),
+        };
+        self.arp_requests.insert(target_ip, request);
+    }
+
+    /// Resends Arp requests that did not get a reply in time, with the wait
+    /// time doubled for every retry. Requests that pass their deadline are
+    /// aborted, waking up everyone waiting for them with an error and
+    /// dropping all packets queued for them.
+    fn retransmit_arp_requests(&mut self) {
+        let now = Instant::now();
+        let mut finished = vec![];
+        for (ip, request) in &mut self.arp_requests {
+            if !self.arp_table.is_requested(*ip) || now >= request.deadline {
+                finished.push(*ip);
+            } else if now >= request.next_send && request.retries < request.config.max_retries {
+                request.retries += 1;
+                request.next_send = now + request.config.retransmit_interval(request.retries);
+                trace!("Retransmitting Arp request for {}", ip);
+                self.data.send_arp_request(request.src, *ip);
+            }
+        }
+        for ip in finished {
+            let request = self.arp_requests.remove(&ip).unwrap();
+            // The table might have been updated without going through this thread
+            if let Some(mac) = self.arp_table.lookup(ip) {
+                self.flush_queue(request.queue, mac);
+            } else {
+                debug!("Arp resolution of {} timed out", ip);
+                self.arp_table.abort_request(ip);
+                if !request.queue.is_empty() {
+                    Self::report_drop(&self.pending_drop_callback,
+                                      ip,
+                                      request.queue.len(),
+                                      StackError::HostUnreachable);
+                }
+                // Invalidate all tx-objects still queueing packets for this IP
+                self.data.inc();
+            }
+        }
+    }
+
+    fn queue_packet(&mut self, ip: Ipv4Addr, frame: Box<[u8]>) {
+        if let Some(mac) = self.arp_table.lookup(ip) {
+            self.send_frame(frame, mac);
+        } else if let Some(request) = self.arp_requests.get_mut(&ip) {
+            if request.queue.len() >= request.config.max_queued_packets {
+                request.queue.pop_front();
+                let msg = format!("Pending queue for {} is full", ip);
+                Self::report_drop(&self.pending_drop_callback,
+                                  ip,
+                                  1,
+                                  StackError::TxError(TxError::Other(msg)));
+            }
+            request.queue.push_back(frame);
+        } else {
+            Self::report_drop(&self.pending_drop_callback,
+                              ip,
+                              1,
+                              StackError::HostUnreachable);
+        }
+    }
+
+    fn flush_queue(&self, queue: VecDeque<Box<[u8]>>, mac: MacAddr) {
+        for frame in queue {
+            self.send_frame(frame, mac);
+        }
+    }
+
+    fn send_frame(&self, mut frame: Box<[u8]>, dst: MacAddr) {
+        MutableEthernetPacket::new(&mut frame[..]).unwrap().set_destination(dst);
+        let mut payload = CustomPayload::new((), &frame);
+        if let Err(e) = tx_send!(|| self.data.tx(); &mut payload) {
+            error!("Unable to send queued packet to {}: {}", dst, e);
+        }
+    }
+
+    fn report_drop(callback: &Option<PendingDropCallback>,
+                   ip: Ipv4Addr,
+                   count: usize,
+                   error: StackError) {
+        warn!("Dropping {} packet(s) queued for {}: {}", count, ip, error);
+        if let Some(ref callback) = *callback {
+            callback(ip, count, error);
         }
     }
 
@@ -140,15 +486,155 @@ impl StackInterfaceThread {
                           sender_ip: Ipv4Addr,
                           sender_mac: MacAddr,
                           target_ip: Ipv4Addr) {
-        let has_target_ip = self.data.ipv4_addresses.read().unwrap().contains(&target_ip);
-        if has_target_ip {
+        if self.is_own_address(sender_ip) {
+            self.address_conflict(sender_ip, sender_mac);
+        } else if sender_ip == Ipv4Addr::new(0, 0, 0, 0) &&
+                  self.address_probes.contains_key(&target_ip) &&
+                  !self.is_claimed(target_ip) {
+            // Someone else is probing for the address we are probing for
+            self.address_conflict(target_ip, sender_mac);
+        } else if sender_ip != Ipv4Addr::new(0, 0, 0, 0) {
+            self.merge_arp(sender_ip, sender_mac, target_ip);
+        }
+        if self.is_claimed(target_ip) {
             debug!("Incoming Arp request for my IP {}", target_ip);
-            let mut payload =
-                ArpPayload::reply(self.data.interface.mac, target_ip, sender_mac, sender_ip);
-            if let Err(e) = tx_send!(|| self.data.arp_tx(sender_mac); &mut payload) {
-                error!("Unable to send arp response to {}: {}", sender_ip, e);
+            self.send_arp_reply(sender_ip, sender_mac, target_ip);
+        } else if self.is_proxied(sender_ip, target_ip) {
+            debug!("Incoming Arp request for proxied IP {}", target_ip);
+            self.send_arp_reply(sender_ip, sender_mac, target_ip);
+        }
+    }
+
+    /// Learns the sender mapping of an incoming Arp request, as described by
+    /// the merge logic in RFC 826. Existing entries are always updated, new
+    /// entries are only added when the request is for one of our addresses.
+    fn merge_arp(&mut self, sender_ip: Ipv4Addr, sender_mac: MacAddr, target_ip: Ipv4Addr) {
+        let known = self.arp_table.lookup(sender_ip).is_some() ||
+                    self.arp_requests.contains_key(&sender_ip);
+        if known || self.is_claimed(target_ip) {
+            self.update_arp(sender_ip, sender_mac);
+        }
+    }
+
+    fn send_arp_reply(&self, sender_ip: Ipv4Addr, sender_mac: MacAddr, target_ip: Ipv4Addr) {
+        let mut payload =
+            ArpPayload::reply(self.data.interface.mac, target_ip, sender_mac, sender_ip);
+        if let Err(e) = tx_send!(|| self.data.arp_tx(sender_mac); &mut payload) {
+            error!("Unable to send arp response to {}: {}", sender_ip, e);
+        }
+    }
+
+    /// Returns `true` if this interface should answer Arp requests for
+    /// `target_ip` on behalf of another host. Probes and announcements are
+    /// never answered.
+    fn is_proxied(&self, sender_ip: Ipv4Addr, target_ip: Ipv4Addr) -> bool {
+        if sender_ip == target_ip || sender_ip == Ipv4Addr::new(0, 0, 0, 0) {
+            return false;
+        }
+        let proxy_arp = self.data.proxy_arp.read().unwrap();
+        if proxy_arp.networks.iter().any(|net| net.contains(target_ip)) {
+            return true;
+        }
+        if proxy_arp.routed {
+            if let Some((_, interface)) = proxy_arp.routes.route(target_ip) {
+                return interface != self.data.interface;
             }
         }
+        false
+    }
+
+    fn is_claimed(&self, ip: Ipv4Addr) -> bool {
+        self.data.ipv4_addresses.read().unwrap().contains(&ip)
+    }
+
+    fn is_own_address(&self, ip: Ipv4Addr) -> bool {
+        self.is_claimed(ip) || self.address_probes.contains_key(&ip)
+    }
+
+    /// Called when an Arp packet shows that `mac` is using one of our IPs.
+    /// Addresses that are still being probed for are never claimed, and stay
+    /// tentative until removed with `NetworkStack::remove_ipv4`.
+    fn address_conflict(&mut self, ip: Ipv4Addr, mac: MacAddr) {
+        if mac == self.data.interface.mac {
+            return;
+        }
+        if !self.is_claimed(ip) && self.address_probes.remove(&ip).is_some() {
+            warn!("Address conflict while probing for {}, used by {}. Not using it",
+                  ip,
+                  mac);
+        } else {
+            warn!("Address conflict on {}, also used by {}", ip, mac);
+        }
+        if let Some(ref callback) = self.address_conflict_callback {
+            callback(ip, mac);
+        }
+    }
+
+    /// Starts probing for `ip` after a random delay of at most
+    /// `config.probe_wait`, RFC 5227 section 2.1.1.
+    fn probe_address(&mut self, ip: Ipv4Addr, config: ArpProbeConfig) {
+        let wait_ms = config.probe_wait.as_secs() * 1000 +
+                      config.probe_wait.subsec_nanos() as u64 / 1_000_000;
+        let delay_ms = if wait_ms == 0 {
+            0
+        } else {
+            Range::new(0, wait_ms).ind_sample(&mut rand::thread_rng())
+        };
+        let probe = AddressProbe {
+            config: config,
+            probes_sent: 0,
+            announcements_sent: 0,
+            next_send: Instant::now() + Duration::from_millis(delay_ms),
+        };
+        self.address_probes.insert(ip, probe);
+        self.advance_address_probes();
+    }
+
+    /// Sends the probes and announcements that are due. Claims addresses that
+    /// have been probed for without any conflict being detected.
+    fn advance_address_probes(&mut self) {
+        let now = Instant::now();
+        let mut finished = vec![];
+        for (ip, probe) in &mut self.address_probes {
+            if now < probe.next_send {
+                continue;
+            }
+            if probe.probes_sent < probe.config.probe_num {
+                trace!("Sending Arp probe for {}", ip);
+                self.data.send_arp_broadcast(ArpPayload::probe(self.data.interface.mac, *ip));
+                probe.probes_sent += 1;
+                probe.next_send = if probe.probes_sent < probe.config.probe_num {
+                    now + probe.config.probe_interval
+                } else {
+                    now + probe.config.announce_wait
+                };
+                continue;
+            }
+            if probe.announcements_sent == 0 {
+                // The address might have been removed while probing
+                if !self.local_addresses.lock().unwrap().tentative.remove(ip) {
+                    finished.push(*ip);
+                    continue;
+                }
+                debug!("No conflict found for {}. Claiming it", ip);
+                self.data.ipv4_addresses.write().unwrap().insert(*ip);
+                // Let existing tx-objects pick the new address as source
+                self.data.inc();
+            }
+            if probe.announcements_sent < probe.config.announce_num {
+                trace!("Sending Arp announcement for {}", ip);
+                let payload = ArpPayload::announcement(self.data.interface.mac, *ip);
+                self.data.send_arp_broadcast(payload);
+                probe.announcements_sent += 1;
+                probe.next_send = now + probe.config.announce_interval;
+            }
+            if probe.announcements_sent >= probe.config.announce_num {
+                finished.push(*ip);
+            }
+        }
+        for ip in finished {
+            self.address_probes.remove(&ip);
+        }
     }
 }
 
@@ -158,34 +644,299 @@ struct Ipv4Data {
     icmp_listeners: Arc<Mutex<IcmpListenerLookup>>,
 }
 
+/// Listeners bound to 0.0.0.0. Shared by all interfaces of a stack, and
+/// given packets to any local address that no listener bound to the address
+/// itself accepted.
+#[derive(Clone, Default)]
+struct WildcardListeners {
+    udp: Arc<Mutex<udp::UdpListenerLookup>>,
+    icmp: Arc<Mutex<IcmpListenerLookup>>,
+}
+
+/// What the forwarding of packets needs to know about one interface.
+#[derive(Clone)]
+struct ForwardingInterface {
+    data: Arc<StackInterfaceData>,
+    thread_tx: Sender<StackInterfaceMsg>,
+    arp_table: ArpTable,
+    arp_request_config: ArpRequestConfig,
+    nets: Vec<Ipv4Network>,
+}
+
+impl ForwardingInterface {
+    /// Returns the local IP to use as source for packets the stack itself
+    /// sends towards `next_hop` on this interface.
+    fn local_ip(&self, next_hop: Ipv4Addr) -> Option<Ipv4Addr> {
+        let claimed = self.data.ipv4_addresses.read().unwrap();
+        let nets: Vec<&Ipv4Network> =
+            self.nets.iter().filter(|net| claimed.contains(&net.ip())).collect();
+        nets.iter()
+            .find(|net| net.contains(next_hop))
+            .or_else(|| nets.first())
+            .map(|net| net.ip())
+    }
+
+    /// Creates an `EthernetTx` to `next_hop`. If the MAC of `next_hop` is not
+    /// known it's resolved and the packets are queued in the interface thread
+    /// meanwhile.
+    fn ethernet_tx(&self, next_hop: Ipv4Addr, src: Ipv4Addr) -> EthernetTx<DatalinkTx> {
+        if let Some(mac) = self.arp_table.lookup(next_hop) {
+            return self.data.ethernet_tx(mac);
+        }
+        let msg = StackInterfaceMsg::ArpResolve(src, next_hop, self.arp_request_config);
+        if self.thread_tx.send(msg).is_err() {
+            error!("Unable to send arp resolve command to interface thread");
+        }
+        self.data.pending_ethernet_tx(next_hop, &self.thread_tx)
+    }
+}
+
+/// State shared by the stack and the `Ipv4Forwarder` of every interface.
+#[derive(Default)]
+struct ForwardingTable {
+    enabled: bool,
+    /// Copy of the routing table of the stack.
+    routes: RoutingTable,
+    interfaces: HashMap<Interface, ForwardingInterface>,
+}
+
+impl ForwardingTable {
+    /// Returns the next hop and a copy of the interface to send packets to
+    /// `dst` on, so the table does not have to stay locked while sending.
+    fn route(&self, dst: Ipv4Addr) -> Option<(Ipv4Addr, ForwardingInterface)> {
+        self.routes.route(dst).and_then(|(gw, interface)| {
+            self.interfaces.get(&interface).map(|egress| (gw.unwrap_or(dst), egress.clone()))
+        })
+    }
+}
+
+/// Routes packets that are not addressed to the stack itself out on the
+/// interface given by the routing table, as described in RFC 1812. Drops
+/// everything while forwarding is disabled on the `NetworkStack`.
+struct Ipv4Forwarder {
+    table: Arc<Mutex<ForwardingTable>>,
+}
+
+impl Ipv4Forwarder {
+    fn route(&self, dst: Ipv4Addr) -> Option<(Ipv4Addr, ForwardingInterface)> {
+        self.table.lock().unwrap().route(dst)
+    }
+
+    fn forward(&self, ip_pkg: &Ipv4Packet) -> RxResult {
+        let dst = ip_pkg.get_destination();
+        if ip_pkg.get_ttl() <= 1 {
+            let code = TimeExceededCodes::TimeToLiveExceededInTransit;
+            self.send_icmp_error(ip_pkg, IcmpFields::time_exceeded(code));
+            return Ok(());
+        }
+        let (next_hop, egress) = match self.route(dst) {
+            Some(route) => route,
+            None => {
+                let code = DestinationUnreachableCodes::DestinationNetworkUnreachable;
+                self.send_icmp_error(ip_pkg, IcmpFields::destination_unreachable(code));
+                return Ok(());
+            }
+        };
+        if egress.nets.iter().any(|net| is_directed_broadcast(net, dst)) {
+            // Directed broadcasts are not forwarded by default, RFC 2644
+            return Err(RxError::NoListener(format!("Ipv4 {}", dst)));
+        }
+        let src = match egress.local_ip(next_hop) {
+            Some(src) => src,
+            None => return Err(RxError::Other("No address on egress interface".to_owned())),
+        };
+
+        let mut buffer = ip_pkg.packet().to_vec();
+        {
+            let mut pkg = MutableIpv4Packet::new(&mut buffer).unwrap();
+            let ttl = pkg.get_ttl();
+            pkg.set_ttl(ttl - 1);
+            let checksum = checksum(&pkg.to_immutable());
+            pkg.set_checksum(checksum);
+        }
+        let mtu = egress.data.mtu();
+        let packets = if buffer.len() <= mtu {
+            vec![buffer]
+        } else if ip_pkg.get_flags() & DONT_FRAGMENT != 0 {
+            let fields = IcmpFields::fragmentation_needed(mtu as u16);
+            self.send_icmp_error(ip_pkg, fields);
+            return Ok(());
+        } else {
+            match fragment_packet(&Ipv4Packet::new(&buffer).unwrap(), mtu) {
+                Ok(fragments) => fragments,
+                Err(e) => return Err(RxError::Other(format!("Unable to fragment {}: {}", dst, e))),
+            }
+        };
+        for packet in packets {
+            let mut payload = CustomPayload::new(EthernetFields(EtherTypes::Ipv4), &packet);
+            if let Err(e) = tx_send!(|| egress.ethernet_tx(next_hop, src); &mut payload) {
+                return Err(RxError::Other(format!("Unable to forward to {}: {}", dst, e)));
+            }
+        }
+        Ok(())
+    }
+
+    /// Sends an Icmp error about `ip_pkg` to its source. Never sent about
+    /// Icmp errors, fragments other than the first or packets without a
+    /// unicast source, as described in RFC 1812.
+    fn send_icmp_error(&self, ip_pkg: &Ipv4Packet, fields: IcmpFields) {
+        let dst = ip_pkg.get_source();
+        if dst.is_broadcast() || dst.is_multicast() || dst == Ipv4Addr::new(0, 0, 0, 0) ||
+           ip_pkg.get_fragment_offset() != 0 || Self::is_icmp_error(ip_pkg) {
+            return;
+        }
+        if let Some((next_hop, egress)) = self.route(dst) {
+            if let Some(src) = egress.local_ip(next_hop) {
+                let header_length = ip_pkg.get_header_length() as usize * 4;
+                let len = cmp::min(ip_pkg.packet().len(), header_length + 8);
+                let mut payload = CustomPayload::new(fields, &ip_pkg.packet()[..len]);
+                let result = tx_send!(|| {
+                    let ethernet_tx = egress.ethernet_tx(next_hop, src);
+                    let mtu = egress.data.mtu();
+                    IcmpTx::new(egress.data.ipv4_tx(ethernet_tx, src, dst, mtu))
+                }; &mut payload);
+                if let Err(e) = result {
+                    error!("Unable to send Icmp error to {}: {}", dst, e);
+                }
+            }
+        }
+    }
+
+    fn is_icmp_error(ip_pkg: &Ipv4Packet) -> bool {
+        if ip_pkg.get_next_level_protocol() != IpNextHeaderProtocols::Icmp {
+            return false;
+        }
+        match IcmpPacket::new(ip_pkg.payload()) {
+            Some(icmp_pkg) => {
+                let icmp_type = icmp_pkg.get_icmp_type();
+                icmp_type == IcmpTypes::DestinationUnreachable ||
+                icmp_type == IcmpTypes::SourceQuench ||
+                icmp_type == IcmpTypes::RedirectMessage ||
+                icmp_type == IcmpTypes::TimeExceeded ||
+                icmp_type == IcmpTypes::ParameterProblem
+            }
+            None => true,
+        }
+    }
+}
+
+impl Ipv4Listener for Ipv4Forwarder {
+    fn recv(&mut self, _time: SystemTime, ip_pkg: Ipv4Packet) -> RxResult {
+        let dst = ip_pkg.get_destination();
+        let enabled = self.table.lock().unwrap().enabled;
+        if !enabled || dst.is_broadcast() || dst.is_multicast() ||
+           dst == Ipv4Addr::new(0, 0, 0, 0) {
+            return Err(RxError::NoListener(format!("Ipv4 {}", dst)));
+        }
+        self.forward(&ip_pkg)
+    }
+}
+
 /// Represents the stack on one physical interface.
 /// The larger `NetworkStack` comprises multiple of these.
 pub struct StackInterface {
     data: Arc<StackInterfaceData>,
-    mtu: usize,
-    _thread_handle: StackInterfaceThreadHandle,
+    thread_handle: StackInterfaceThreadHandle,
     arp_table: ArpTable,
+    arp_request_config: ArpRequestConfig,
+    arp_probe_config: Option<ArpProbeConfig>,
+    reassembler: Arc<Mutex<Reassembler>>,
+    path_mtu: PathMtuCache,
     ipv4_datas: HashMap<Ipv4Addr, Ipv4Data>,
+    primary_ipv4: Option<Ipv4Addr>,
     ipv4_listeners: Arc<Mutex<IpListenerLookup>>,
+    local_addresses: Arc<Mutex<LocalAddresses>>,
+    ipv4_rx_stats: Arc<Mutex<Ipv4RxStats>>,
+    memberships: Arc<Mutex<MulticastMemberships>>,
+    forwarding: Arc<Mutex<ForwardingTable>>,
 }
 
 impl StackInterface {
     pub fn new(interface: Interface, channel: EthernetChannel) -> StackInterface {
+        let forwarding = Arc::new(Mutex::new(ForwardingTable::default()));
+        Self::with_forwarding(interface,
+                              channel,
+                              forwarding,
+                              WildcardListeners::default(),
+                              IdentificationSource::default())
+    }
+
+    /// Creates a `StackInterface` that forwards packets not addressed to
+    /// itself according to `forwarding`, and gives packets to its addresses
+    /// that have no other listener to `wildcard`. Identifications of sent
+    /// datagrams are taken from `identification`.
+    fn with_forwarding(interface: Interface,
+                       channel: EthernetChannel,
+                       forwarding: Arc<Mutex<ForwardingTable>>,
+                       wildcard: WildcardListeners,
+                       identification: IdentificationSource)
+                       -> StackInterface {
         let stack_interface_data = Arc::new(StackInterfaceData {
             interface: interface,
             tx: Arc::new(Mutex::new(TxBarrier::new(channel.sender, channel.write_buffer_size))),
             ipv4_addresses: RwLock::new(HashSet::new()),
+            proxy_arp: RwLock::new(ProxyArp::default()),
+            mtu: AtomicUsize::new(DEFAULT_MTU),
+            default_ttl: AtomicUsize::new(DEFAULT_TTL as usize),
+            identification: identification,
         });
 
-        let arp_table = arp::ArpTable::new();
+        let mut arp_table = arp::ArpTable::new();
+        {
+            let data = stack_interface_data.clone();
+            arp_table.set_change_callback(move || data.inc());
+        }
 
+        let mut path_mtu = PathMtuCache::new();
+        {
+            let data = stack_interface_data.clone();
+            path_mtu.set_change_callback(move || data.inc());
+        }
+
+        let reassembler = Arc::new(Mutex::new(Reassembler::new(ReassemblyConfig::default())));
+        let memberships = Arc::new(Mutex::new(MulticastMemberships::new()));
+        let mut local_addresses = LocalAddresses::default();
+        local_addresses.multicast_groups.insert(all_systems());
+        let local_addresses = Arc::new(Mutex::new(local_addresses));
         let thread_handle = StackInterfaceThread::spawn(stack_interface_data.clone(),
-                                                        arp_table.clone());
+                                                        arp_table.clone(),
+                                                        reassembler.clone(),
+                                                        path_mtu.clone(),
+                                                        memberships.clone(),
+                                                        local_addresses.clone());
 
         let arp_rx = Box::new(ArpRx::new(thread_handle.tx.clone())) as Box<EthernetListener>;
 
-        let ipv4_listeners = Arc::new(Mutex::new(HashMap::new()));
-        let ipv4_rx = Ipv4Rx::new(ipv4_listeners.clone());
+        let mut wildcard_listeners = HashMap::new();
+        let udp_rx = udp::UdpRx::new(stack_interface_data.interface.clone(), wildcard.udp);
+        wildcard_listeners.insert(IpNextHeaderProtocols::Udp,
+                                  Box::new(udp_rx) as Box<Ipv4Listener>);
+        let icmp_rx = IcmpRx::new(wildcard.icmp);
+        wildcard_listeners.insert(IpNextHeaderProtocols::Icmp,
+                                  Box::new(icmp_rx) as Box<Ipv4Listener>);
+        let mut ipv4_listeners = HashMap::new();
+        ipv4_listeners.insert(Ipv4Addr::new(0, 0, 0, 0), wildcard_listeners);
+        let ipv4_listeners = Arc::new(Mutex::new(ipv4_listeners));
+        let ipv4_rx_stats = Arc::new(Mutex::new(Ipv4RxStats::default()));
+        let forwarder = Box::new(Ipv4Forwarder { table: forwarding.clone() });
+        let ipv4_rx = Ipv4Rx::with_forwarder(ipv4_listeners.clone(),
+                                             reassembler.clone(),
+                                             local_addresses.clone(),
+                                             ipv4_rx_stats.clone(),
+                                             stack_interface_data.interface.mac,
+                                             forwarder);
+
+        let forwarding_interface = ForwardingInterface {
+            data: stack_interface_data.clone(),
+            thread_tx: thread_handle.tx.clone(),
+            arp_table: arp_table.clone(),
+            arp_request_config: ArpRequestConfig::default(),
+            nets: vec![],
+        };
+        forwarding.lock()
+            .unwrap()
+            .interfaces
+            .insert(stack_interface_data.interface.clone(), forwarding_interface);
 
         let ethernet_listeners = vec![arp_rx, ipv4_rx];
         let ethernet_rx = EthernetRx::new(ethernet_listeners);
@@ -193,11 +944,19 @@ impl StackInterface {
 
         StackInterface {
             data: stack_interface_data,
-            mtu: DEFAULT_MTU,
-            _thread_handle: thread_handle,
+            thread_handle: thread_handle,
             arp_table: arp_table,
+            arp_request_config: ArpRequestConfig::default(),
+            arp_probe_config: None,
+            reassembler: reassembler,
+            path_mtu: path_mtu,
             ipv4_datas: HashMap::new(),
+            primary_ipv4: None,
             ipv4_listeners: ipv4_listeners,
+            local_addresses: local_addresses,
+            ipv4_rx_stats: ipv4_rx_stats,
+            memberships: memberships,
+            forwarding: forwarding,
         }
     }
 
@@ -217,30 +976,149 @@ impl StackInterface {
         self.data.arp_tx(dst)
     }
 
+    /// Returns the Arp table of this interface. Any change to the table
+    /// invalidates all existing tx-objects on this interface.
     pub fn arp_table(&mut self) -> &mut arp::ArpTable {
         &mut self.arp_table
     }
 
+    pub fn arp_request_config(&self) -> ArpRequestConfig {
+        self.arp_request_config
+    }
+
+    /// Sets how Arp requests sent by `ipv4_tx` are retransmitted, when they
+    /// time out and how many packets can be queued while waiting for them.
+    pub fn set_arp_request_config(&mut self, config: ArpRequestConfig) {
+        self.arp_request_config = config;
+        self.update_forwarding(|forwarding| forwarding.arp_request_config = config);
+    }
+
+    /// Sets a callback that is called whenever packets queued while waiting
+    /// for Arp resolution are dropped, because the resolution timed out or the
+    /// queue was full.
+    pub fn set_pending_drop_callback<F>(&mut self, callback: F)
+        where F: Fn(Ipv4Addr, usize, StackError) + Send + 'static
+    {
+        let msg = StackInterfaceMsg::SetPendingDropCallback(Box::new(callback));
+        if self.thread_handle.tx.send(msg).is_err() {
+            error!("Unable to send drop callback to interface thread");
+        }
+    }
+
+    pub fn arp_probe_config(&self) -> Option<ArpProbeConfig> {
+        self.arp_probe_config
+    }
+
+    /// Sets how addresses added with `add_ipv4` are probed for conflicts and
+    /// announced, as described in RFC 5227. With `None`, the default, addresses
+    /// are claimed directly without sending anything.
+    pub fn set_arp_probe_config(&mut self, config: Option<ArpProbeConfig>) {
+        self.arp_probe_config = config;
+    }
+
+    /// Sets a callback that is called whenever another host is detected using
+    /// one of the addresses of this interface, both while probing and after
+    /// the address has been claimed. An address with a conflict while probing
+    /// is never used, and should be removed with `NetworkStack::remove_ipv4`.
+    /// The callback is called from the interface thread, so it must not wait
+    /// for the stack.
+    pub fn set_address_conflict_callback<F>(&mut self, callback: F)
+        where F: Fn(Ipv4Addr, MacAddr) + Send + 'static
+    {
+        let msg = StackInterfaceMsg::SetAddressConflictCallback(Box::new(callback));
+        if self.thread_handle.tx.send(msg).is_err() {
+            error!("Unable to send conflict callback to interface thread");
+        }
+    }
+
+    pub fn reassembly_config(&self) -> ReassemblyConfig {
+        self.reassembler.lock().unwrap().config()
+    }
+
+    /// Sets timeouts and memory limits for the reassembly of incoming
+    /// fragmented packets.
+    pub fn set_reassembly_config(&mut self, config: ReassemblyConfig) {
+        self.reassembler.lock().unwrap().set_config(config);
+    }
+
+    /// Returns counters for incoming fragmented packets that were dropped
+    /// before they could be reassembled.
+    pub fn reassembly_stats(&self) -> ReassemblyStats {
+        self.reassembler.lock().unwrap().stats()
+    }
+
+    /// Returns counters for incoming Ipv4 packets that were dropped because
+    /// of an invalid header or source address.
+    pub fn ipv4_rx_stats(&self) -> Ipv4RxStats {
+        *self.ipv4_rx_stats.lock().unwrap()
+    }
+
+    /// Returns the path MTUs discovered for destinations reached through this
+    /// interface. Any change to the cache invalidates all existing tx-objects
+    /// on this interface.
+    pub fn path_mtu_cache(&mut self) -> &mut PathMtuCache {
+        &mut self.path_mtu
+    }
+
+    /// Makes this interface answer Arp requests for all IPs in `net`, with its
+    /// own MAC.
+    pub fn add_proxy_arp_network(&mut self, net: Ipv4Network) {
+        self.data.proxy_arp.write().unwrap().networks.push(net);
+    }
+
+    /// Stops answering Arp requests for `net`. Returns `false` if proxy Arp
+    /// was not enabled for `net`.
+    pub fn remove_proxy_arp_network(&mut self, net: Ipv4Network) -> bool {
+        let mut proxy_arp = self.data.proxy_arp.write().unwrap();
+        let len_before = proxy_arp.networks.len();
+        proxy_arp.networks.retain(|n| *n != net);
+        proxy_arp.networks.len() != len_before
+    }
+
+    pub fn proxy_arp_networks(&self) -> Vec<Ipv4Network> {
+        self.data.proxy_arp.read().unwrap().networks.clone()
+    }
+
+    /// Sets if this interface should answer Arp requests for all IPs that the
+    /// routing table of the stack routes out of another interface.
+    pub fn set_proxy_arp_routed(&mut self, enabled: bool) {
+        self.data.proxy_arp.write().unwrap().routed = enabled;
+    }
+
+    /// Adds an IPv4 address and network to this interface. If probing is
+    /// enabled with `set_arp_probe_config` the address is tentative until
+    /// probing has completed without detecting any conflict. Until then Arp
+    /// requests for it are not answered, packets to it are dropped and it's
+    /// never used as source.
     pub fn add_ipv4(&mut self, ip_net: Ipv4Network) -> StackResult<()> {
         let ip = ip_net.ip();
         match self.ipv4_datas.entry(ip) {
             Entry::Occupied(_) => Err(StackError::IllegalArgument),
             Entry::Vacant(entry) => {
+                if self.arp_probe_config.is_some() {
+                    self.local_addresses.lock().unwrap().tentative.insert(ip);
+                }
                 let mut proto_listeners = HashMap::new();
 
                 let udp_listeners = Arc::new(Mutex::new(HashMap::new()));
-                let udp_rx = udp::UdpRx::new(udp_listeners.clone());
+                let udp_rx = udp::UdpRx::new(self.data.interface.clone(), udp_listeners.clone());
                 let udp_ipv4_listener = Box::new(udp_rx) as Box<Ipv4Listener>;
                 proto_listeners.insert(IpNextHeaderProtocols::Udp, udp_ipv4_listener);
 
                 let icmp_listeners = Arc::new(Mutex::new(HashMap::new()));
-                let icmp_rx = IcmpRx::new(icmp_listeners.clone());
+                let icmp_rx = IcmpRx::with_path_mtu_cache(icmp_listeners.clone(),
+                                                          self.path_mtu.clone());
                 let icmp_listener = Box::new(icmp_rx) as Box<Ipv4Listener>;
                 proto_listeners.insert(IpNextHeaderProtocols::Icmp, icmp_listener);
+
+                let igmp_rx = IgmpRx::new(self.memberships.clone());
+                let igmp_listener = Box::new(igmp_rx) as Box<Ipv4Listener>;
+                proto_listeners.insert(IpNextHeaderProtocols::Igmp, igmp_listener);
                 {
                     let mut ipv4_listeners = self.ipv4_listeners.lock().unwrap();
                     ipv4_listeners.insert(ip, proto_listeners);
                 }
+                self.local_addresses.lock().unwrap().networks.push(ip_net);
 
                 let data = Ipv4Data {
                     net: ip_net,
@@ -248,29 +1126,123 @@ impl StackInterface {
                     icmp_listeners: icmp_listeners,
                 };
                 entry.insert(data);
-                self.data.ipv4_addresses.write().unwrap().insert(ip);
+                if self.primary_ipv4.is_none() {
+                    self.primary_ipv4 = Some(ip);
+                }
+                {
+                    let mut forwarding = self.forwarding.lock().unwrap();
+                    if let Some(forwarding_interface) = forwarding.interfaces
+                        .get_mut(&self.data.interface) {
+                        forwarding_interface.nets.push(ip_net);
+                    }
+                }
+                match self.arp_probe_config {
+                    Some(config) => {
+                        let msg = StackInterfaceMsg::ProbeAddress(ip, config);
+                        if self.thread_handle.tx.send(msg).is_err() {
+                            error!("Unable to send probe command to interface thread");
+                        }
+                    }
+                    None => {
+                        self.data.ipv4_addresses.write().unwrap().insert(ip);
+                    }
+                }
                 Ok(())
             }
         }
     }
 
+    /// Returns `true` if `ip` was added with `add_ipv4` but is not claimed,
+    /// because it's still being probed for or another host was found using
+    /// it while probing.
+    pub fn is_tentative(&self, ip: Ipv4Addr) -> bool {
+        self.ipv4_datas.contains_key(&ip) &&
+        !self.data.ipv4_addresses.read().unwrap().contains(&ip)
+    }
+
+    /// Removes an address added with `add_ipv4`, together with its
+    /// listeners. Returns the network the address was added with.
+    pub fn remove_ipv4(&mut self, ip: Ipv4Addr) -> StackResult<Ipv4Network> {
+        let ip_net = match self.ipv4_datas.remove(&ip) {
+            Some(ip_data) => ip_data.net,
+            None => return Err(StackError::IllegalArgument),
+        };
+        self.ipv4_listeners.lock().unwrap().remove(&ip);
+        {
+            let mut local_addresses = self.local_addresses.lock().unwrap();
+            local_addresses.networks.retain(|net| *net != ip_net);
+            local_addresses.tentative.remove(&ip);
+        }
+        self.data.ipv4_addresses.write().unwrap().remove(&ip);
+        {
+            let mut forwarding = self.forwarding.lock().unwrap();
+            if let Some(forwarding_interface) = forwarding.interfaces
+                .get_mut(&self.data.interface) {
+                forwarding_interface.nets.retain(|net| *net != ip_net);
+            }
+        }
+        if self.primary_ipv4 == Some(ip) {
+            self.primary_ipv4 = self.ipv4_datas.keys().min().cloned();
+        }
+        if self.thread_handle.tx.send(StackInterfaceMsg::AbortProbe(ip)).is_err() {
+            error!("Unable to send abort probe command to interface thread");
+        }
+        self.inc();
+        Ok(ip_net)
+    }
+
+    /// Returns the address preferred as source when no other address on
+    /// this interface is a better match. The first address added, unless
+    /// changed with `set_primary_ipv4`.
+    pub fn primary_ipv4(&self) -> Option<Ipv4Addr> {
+        self.primary_ipv4
+    }
+
+    pub fn set_primary_ipv4(&mut self, ip: Ipv4Addr) -> StackResult<()> {
+        if self.ipv4_datas.contains_key(&ip) {
+            self.primary_ipv4 = Some(ip);
+            self.inc();
+            Ok(())
+        } else {
+            Err(StackError::IllegalArgument)
+        }
+    }
+
+    /// Creates an `Ipv4Tx` to `dst` via the gateway `gw`, or directly to
+    /// `dst` if there is none. The source address is `src` if given, and
+    /// otherwise picked with `closest_local_ip`.
     pub fn ipv4_tx(&mut self,
                    dst: Ipv4Addr,
-                   gw: Option<Ipv4Addr>)
+                   gw: Option<Ipv4Addr>,
+                   src: Option<Ipv4Addr>)
                    -> StackResult<Ipv4Tx<EthernetTx<DatalinkTx>>> {
-        let local_dst = gw.unwrap_or(dst);
-        if let Some(src) = self.closest_local_ip(local_dst) {
-            let dst_mac = match self.arp_table.get(local_dst) {
-                Ok(mac) => mac,
-                Err(rx) => {
-                    let src_mac = self.data.interface.mac;
-                    let mut arp_request = arp::ArpPayload::request(src_mac, src, local_dst);
-                    tx_send!(|| self.arp_request_tx(); &mut arp_request)?;
-                    rx.recv().unwrap()
+        let broadcast = self.is_broadcast(dst) || dst.is_multicast();
+        let local_dst = if broadcast { dst } else { gw.unwrap_or(dst) };
+        let src = self.closest_local_ip(dst, local_dst, src);
+        if let Some(src) = src {
+            let ethernet_tx = if dst.is_multicast() {
+                self.ethernet_tx(multicast_mac(dst))
+            } else if broadcast {
+                self.ethernet_tx(MacAddr(0xff, 0xff, 0xff, 0xff, 0xff, 0xff))
+            } else {
+                match self.arp_table.get(local_dst) {
+                    Ok(mac) => self.ethernet_tx(mac),
+                    Err(_) => {
+                        let config = self.arp_request_config;
+                        let msg = StackInterfaceMsg::ArpResolve(src, local_dst, config);
+                        if self.thread_handle.tx.send(msg).is_err() {
+                            error!("Unable to send arp resolve command to interface thread");
+                            return Err(StackError::InvalidInterface);
+                        }
+                        self.pending_ethernet_tx(local_dst)
+                    }
                 }
             };
-            let ethernet_tx = self.ethernet_tx(dst_mac);
-            Ok(Ipv4Tx::new(ethernet_tx, src, dst, self.mtu))
+            let mtu = self.get_mtu();
+            let mtu = self.path_mtu.get(dst).map_or(mtu, |path_mtu| cmp::min(path_mtu, mtu));
+            let mut ipv4_tx = self.data.ipv4_tx(ethernet_tx, src, dst, mtu);
+            ipv4_tx.set_dont_fragment(self.path_mtu.is_enabled() && !broadcast);
+            Ok(ipv4_tx)
         } else {
             Err(StackError::IllegalArgument)
         }
@@ -293,33 +1265,168 @@ impl StackInterface {
         }
     }
 
+    /// Gives packets to `local_ip` carrying `protocol` to `listener`. Fails
+    /// if the protocol already has a listener, which all protocols handled by
+    /// the stack itself have.
+    pub fn ipv4_listen(&mut self,
+                       local_ip: Ipv4Addr,
+                       protocol: IpNextHeaderProtocol,
+                       listener: Box<Ipv4Listener>)
+                       -> io::Result<()> {
+        let mut ipv4_listeners = self.ipv4_listeners.lock().unwrap();
+        if let Some(proto_listeners) = ipv4_listeners.get_mut(&local_ip) {
+            match proto_listeners.entry(protocol) {
+                Entry::Occupied(_) => {
+                    let msg = format!("Protocol {:?} is already in use on {}", protocol, local_ip);
+                    Err(io::Error::new(io::ErrorKind::AddrInUse, msg))
+                }
+                Entry::Vacant(entry) => {
+                    entry.insert(listener);
+                    Ok(())
+                }
+            }
+        } else {
+            let msg = "Bind address does not exist on interface".to_owned();
+            Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+        }
+    }
+
+    /// Removes a listener added with `ipv4_listen`. Returns `false` if there
+    /// was none. The listeners of the stack itself can't be removed.
+    pub fn ipv4_unlisten(&mut self, local_ip: Ipv4Addr, protocol: IpNextHeaderProtocol) -> bool {
+        let stack_protocols =
+            [IpNextHeaderProtocols::Udp, IpNextHeaderProtocols::Icmp, IpNextHeaderProtocols::Igmp];
+        if stack_protocols.contains(&protocol) {
+            return false;
+        }
+        let mut ipv4_listeners = self.ipv4_listeners.lock().unwrap();
+        ipv4_listeners.get_mut(&local_ip)
+            .map_or(false, |proto_listeners| proto_listeners.remove(&protocol).is_some())
+    }
+
+    /// Joins the multicast group `group` on this interface. Every join must be
+    /// matched by a call to `leave_multicast_v4`. The membership is reported
+    /// with IGMP when the group is first joined.
+    pub fn join_multicast_v4(&mut self, group: Ipv4Addr) -> StackResult<()> {
+        if !group.is_multicast() {
+            return Err(StackError::IllegalArgument);
+        }
+        let report = self.memberships.lock().unwrap().join(group);
+        self.local_addresses.lock().unwrap().multicast_groups.insert(group);
+        if let Some(report) = report {
+            self.data.send_igmp(&report);
+        }
+        Ok(())
+    }
+
+    /// Leaves the multicast group `group` on this interface. Packets to the
+    /// group are no longer received once every join has been matched by a
+    /// leave.
+    pub fn leave_multicast_v4(&mut self, group: Ipv4Addr) -> StackResult<()> {
+        let message = {
+            let mut memberships = self.memberships.lock().unwrap();
+            if !memberships.groups().contains(&group) {
+                return Err(StackError::IllegalArgument);
+            }
+            let message = memberships.leave(group);
+            if !memberships.is_member(group) {
+                self.local_addresses.lock().unwrap().multicast_groups.remove(&group);
+            }
+            message
+        };
+        if let Some(message) = message {
+            self.data.send_igmp(&message);
+        }
+        Ok(())
+    }
+
+    /// Returns the multicast groups joined on this interface.
+    pub fn multicast_groups(&self) -> Vec<Ipv4Addr> {
+        self.memberships.lock().unwrap().groups()
+    }
+
+    /// Returns `true` if `ip` is the limited broadcast address or the
+    /// broadcast address of one of the networks on this interface.
+    pub fn is_broadcast(&self, ip: Ipv4Addr) -> bool {
+        ip.is_broadcast() ||
+        self.ipv4_datas.values().any(|ip_data| is_directed_broadcast(&ip_data.net, ip))
+    }
+
     pub fn get_mtu(&self) -> usize {
-        self.mtu
+        self.data.mtu()
     }
 
-    pub fn set_mtu(&mut self, mtu: usize) {
-        self.mtu = mtu;
+    /// Sets the MTU of the interface. Returns `IllegalArgument` if it's
+    /// lower than the 68 bytes every IPv4 link must support.
+    pub fn set_mtu(&mut self, mtu: usize) -> StackResult<()> {
+        if mtu < MIN_PATH_MTU {
+            return Err(StackError::IllegalArgument);
+        }
+        self.data.mtu.store(mtu, Ordering::SeqCst);
         self.data.inc();
+        Ok(())
     }
 
     fn inc(&self) {
         self.data.inc();
     }
 
-    /// Finds which local IP is suitable as src ip for packets sent to `dst`.
-    /// TODO: Smarter algorithm
-    fn closest_local_ip(&self, dst: Ipv4Addr) -> Option<Ipv4Addr> {
-        for (ip, ip_data) in &self.ipv4_datas {
-            if ip_data.net.contains(dst) {
-                return Some(*ip);
+    fn update_forwarding<F>(&self, f: F)
+        where F: FnOnce(&mut ForwardingInterface)
+    {
+        let mut forwarding = self.forwarding.lock().unwrap();
+        if let Some(forwarding_interface) = forwarding.interfaces
+            .get_mut(&self.data.interface) {
+            f(forwarding_interface);
+        }
+    }
+
+    fn set_routes(&self, routing_table: &RoutingTable) {
+        self.data.proxy_arp.write().unwrap().routes = routing_table.clone();
+    }
+
+    fn pending_ethernet_tx(&self, neighbour: Ipv4Addr) -> EthernetTx<DatalinkTx> {
+        self.data.pending_ethernet_tx(neighbour, &self.thread_handle.tx)
+    }
+
+    /// Finds which local IP is suitable as src ip for packets sent to `dst`
+    /// through `next_hop`, loosely following RFC 6724. `hint` is used if it's
+    /// an address on this interface. Otherwise addresses in a network
+    /// containing `next_hop` are preferred, longest prefix first. Ties are
+    /// broken by preferring the primary address, then the longest prefix in
+    /// common with `dst` and last the lowest address. Tentative addresses are
+    /// never picked.
+    fn closest_local_ip(&self,
+                        dst: Ipv4Addr,
+                        next_hop: Ipv4Addr,
+                        hint: Option<Ipv4Addr>)
+                        -> Option<Ipv4Addr> {
+        let claimed = self.data.ipv4_addresses.read().unwrap();
+        if let Some(hint) = hint {
+            if claimed.contains(&hint) {
+                return Some(hint);
             }
+            warn!("Source {} is not a claimed address on {}",
+                  hint,
+                  self.data.interface.name);
         }
-        None
+        self.ipv4_datas
+            .iter()
+            .filter(|&(ip, _)| claimed.contains(ip))
+            .max_by_key(|&(ip, ip_data)| {
+                let on_link = ip_data.net.contains(next_hop);
+                let prefix = if on_link { ip_data.net.prefix() } else { 0 };
+                let primary = self.primary_ipv4 == Some(*ip);
+                let common_prefix = (u32::from(*ip) ^ u32::from(dst)).leading_zeros();
+                (on_link, prefix, primary, common_prefix, !u32::from(*ip))
+            })
+            .map(|(ip, _)| *ip)
     }
 }
 
 impl Drop for StackInterface {
     fn drop(&mut self) {
+        self.forwarding.lock().unwrap().interfaces.remove(&self.data.interface);
         self.data.inc();
     }
 }
@@ -327,10 +1434,19 @@ impl Drop for StackInterface {
 /// The main struct of this library, managing an entire TCP/IP stack. Takes
 /// care of ARP, routing tables, threads, TCP resends/fragmentation etc. Most
 /// of this is still unimplemented.
-#[derive(Default)]
 pub struct NetworkStack {
     interfaces: HashMap<Interface, StackInterface>,
     routing_table: RoutingTable,
+    default_ttl: u8,
+    identification: IdentificationSource,
+    forwarding: Arc<Mutex<ForwardingTable>>,
+    wildcard: WildcardListeners,
+}
+
+impl Default for NetworkStack {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl NetworkStack {
@@ -338,6 +1454,48 @@ impl NetworkStack {
         NetworkStack {
             interfaces: HashMap::new(),
             routing_table: RoutingTable::new(),
+            default_ttl: DEFAULT_TTL,
+            identification: IdentificationSource::default(),
+            forwarding: Arc::new(Mutex::new(ForwardingTable::default())),
+            wildcard: WildcardListeners::default(),
+        }
+    }
+
+    /// Sets how the identification field of outgoing IPv4 datagrams is
+    /// picked. Defaults to a `PerDestinationCounter`. Also affects existing
+    /// tx-objects.
+    pub fn set_identification_generator<G>(&mut self, generator: G)
+        where G: IdentificationGenerator + 'static
+    {
+        self.identification.set_generator(generator);
+    }
+
+    /// Returns `true` if packets arriving for other hosts are forwarded.
+    pub fn forwarding(&self) -> bool {
+        self.forwarding.lock().unwrap().enabled
+    }
+
+    /// Turns forwarding on or off. When on, packets arriving on any
+    /// interface that are not addressed to the stack are routed out on the
+    /// interface given by the routing table, making the stack act as a
+    /// router. Off by default.
+    pub fn set_forwarding(&mut self, enabled: bool) {
+        self.forwarding.lock().unwrap().enabled = enabled;
+    }
+
+    /// Returns the time to live given to packets sent from this stack, unless
+    /// set to something else on the tx-object or socket.
+    pub fn default_ttl(&self) -> u8 {
+        self.default_ttl
+    }
+
+    /// Sets the time to live given to packets sent from this stack. Invalidates
+    /// all existing tx-objects, so the new value takes effect on recreation.
+    pub fn set_default_ttl(&mut self, ttl: u8) {
+        self.default_ttl = ttl;
+        for interface in self.interfaces.values() {
+            interface.data.default_ttl.store(ttl as usize, Ordering::SeqCst);
+            interface.inc();
         }
     }
 
@@ -346,13 +1504,25 @@ impl NetworkStack {
                          channel: EthernetChannel)
                          -> StackResult<()> {
         match self.interfaces.entry(interface) {
-            Entry::Occupied(_) => Err(StackError::InvalidInterface),
+            Entry::Occupied(_) => return Err(StackError::InvalidInterface),
             Entry::Vacant(entry) => {
                 let interface = entry.key().clone();
-                entry.insert(StackInterface::new(interface, channel));
-                Ok(())
+                let forwarding = self.forwarding.clone();
+                let wildcard = self.wildcard.clone();
+                let identification = self.identification.clone();
+                let stack_interface = StackInterface::with_forwarding(interface,
+                                                                      channel,
+                                                                      forwarding,
+                                                                      wildcard,
+                                                                      identification);
+                stack_interface.data
+                    .default_ttl
+                    .store(self.default_ttl as usize, Ordering::SeqCst);
+                entry.insert(stack_interface);
             }
         }
+        self.update_interface_routes();
+        Ok(())
     }
 
     pub fn interfaces(&self) -> Vec<Interface> {
@@ -377,24 +1547,56 @@ impl NetworkStack {
 
     pub fn routing_table(&mut self) -> StackRoutingTable {
         let interfaces = &mut self.interfaces;
-        let callback = move || for interface in interfaces.values() {
-            interface.inc();
+        let forwarding = &self.forwarding;
+        let callback = move |routing_table: &RoutingTable| {
+            for interface in interfaces.values() {
+                interface.inc();
+            }
+            copy_routes(interfaces, forwarding, routing_table);
         };
         StackRoutingTable::new(&mut self.routing_table, Box::new(callback))
     }
 
+    fn update_interface_routes(&self) {
+        copy_routes(&self.interfaces, &self.forwarding, &self.routing_table);
+    }
+
     /// Attach an IPv4 network to an interface.
     /// TODO: Deprecate and make the routing stuff better instead
     pub fn add_ipv4(&mut self, interface: &Interface, ip_net: Ipv4Network) -> StackResult<()> {
         self.interface(interface)?.add_ipv4(ip_net)?;
         self.routing_table.add_route(ip_net, None, interface.clone());
+        self.update_interface_routes();
         Ok(())
     }
 
+    /// Removes an address added with `add_ipv4`, and the route to its
+    /// network. Sockets bound to the address stop receiving anything.
+    pub fn remove_ipv4(&mut self, interface: &Interface, ip: Ipv4Addr) -> StackResult<()> {
+        let ip_net = self.interface(interface)?.remove_ipv4(ip)?;
+        self.routing_table.remove_route(ip_net, None, interface);
+        self.update_interface_routes();
+        Ok(())
+    }
+
+    /// Creates an `Ipv4Tx` to `dst`. Packets to the limited broadcast address
+    /// or multicast groups are sent out on the interface of the default
+    /// route, or if there is none, the first interface with an IPv4 address.
     pub fn ipv4_tx(&mut self, dst: Ipv4Addr) -> StackResult<Ipv4Tx<EthernetTx<DatalinkTx>>> {
-        if let Some((gw, interface)) = self.routing_table.route(dst) {
-            if let Some(stack_interface) = self.interfaces.get_mut(&interface) {
-                stack_interface.ipv4_tx(dst, gw)
+        self.ipv4_tx_from(dst, None)
+    }
+
+    /// Like `ipv4_tx`, but sends from `src` if it's an address on the
+    /// interface the packets are routed out on. Without `src` the preferred
+    /// source of the route is used.
+    pub fn ipv4_tx_from(&mut self,
+                        dst: Ipv4Addr,
+                        src: Option<Ipv4Addr>)
+                        -> StackResult<Ipv4Tx<EthernetTx<DatalinkTx>>> {
+        if let Some(route) = self.route(dst) {
+            if let Some(stack_interface) = self.interfaces.get_mut(&route.interface) {
+                let src = src.or(route.src);
+                stack_interface.ipv4_tx(dst, route.gw, src)
             } else {
                 Err(StackError::IllegalArgument)
             }
@@ -403,6 +1605,102 @@ impl NetworkStack {
         }
     }
 
+    /// Returns `true` if `ip` is the limited broadcast address or the
+    /// broadcast address of a network on any interface.
+    pub fn is_broadcast(&self, ip: Ipv4Addr) -> bool {
+        ip.is_broadcast() ||
+        self.interfaces.values().any(|stack_interface| stack_interface.is_broadcast(ip))
+    }
+
+    /// Joins the multicast group `group` on the interface with the address
+    /// `interface_ip`. If `interface_ip` is 0.0.0.0 the interface is picked
+    /// the same way as when sending to the group.
+    pub fn join_multicast_v4(&mut self,
+                             group: Ipv4Addr,
+                             interface_ip: Ipv4Addr)
+                             -> io::Result<()> {
+        let interface = self.multicast_interface(group, interface_ip)?;
+        self.interface(&interface)?.join_multicast_v4(group)?;
+        Ok(())
+    }
+
+    /// Leaves the multicast group `group` joined with `join_multicast_v4`.
+    pub fn leave_multicast_v4(&mut self,
+                              group: Ipv4Addr,
+                              interface_ip: Ipv4Addr)
+                              -> io::Result<()> {
+        let interface = self.multicast_interface(group, interface_ip)?;
+        self.interface(&interface)?.leave_multicast_v4(group)?;
+        Ok(())
+    }
+
+    fn multicast_interface(&self,
+                           group: Ipv4Addr,
+                           interface_ip: Ipv4Addr)
+                           -> io::Result<Interface> {
+        let interface = if interface_ip == Ipv4Addr::new(0, 0, 0, 0) {
+            self.route(group).map(|route| route.interface)
+        } else {
+            self.interfaces
+                .values()
+                .find(|stack_interface| stack_interface.ipv4_datas.contains_key(&interface_ip))
+                .map(|stack_interface| stack_interface.interface().clone())
+        };
+        interface.ok_or_else(|| {
+            let msg = format!("No interface for multicast group {}", group);
+            io::Error::new(io::ErrorKind::AddrNotAvailable, msg)
+        })
+    }
+
+    /// Looks up the route to send packets to `dst` along. Broadcasts and
+    /// multicasts without a route are sent out on the first interface with an
+    /// IPv4 address.
+    fn route(&self, dst: Ipv4Addr) -> Option<RouteEntry> {
+        self.routing_table.route_entry(dst).cloned().or_else(|| {
+            if dst.is_broadcast() || dst.is_multicast() {
+                let net = Ipv4Network::new(dst, 32).unwrap();
+                self.interfaces
+                    .values()
+                    .filter(|stack_interface| !stack_interface.ipv4_datas.is_empty())
+                    .map(|stack_interface| stack_interface.interface().clone())
+                    .min_by_key(|interface| interface.name.clone())
+                    .map(|interface| RouteEntry::new(net, None, interface))
+            } else {
+                None
+            }
+        })
+    }
+
+    /// Gives packets to `local_ip` carrying `protocol` to `listener`. Used
+    /// to implement protocols on top of Ipv4 outside of the stack.
+    pub fn ipv4_listen<L>(&mut self,
+                          local_ip: Ipv4Addr,
+                          protocol: IpNextHeaderProtocol,
+                          listener: L)
+                          -> io::Result<()>
+        where L: Ipv4Listener + 'static
+    {
+        if local_ip == Ipv4Addr::new(0, 0, 0, 0) {
+            let msg = "Rips does not support listening to all interfaces yet".to_owned();
+            return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, msg));
+        }
+        for stack_interface in self.interfaces.values_mut() {
+            if stack_interface.ipv4_datas.contains_key(&local_ip) {
+                return stack_interface.ipv4_listen(local_ip, protocol, Box::new(listener));
+            }
+        }
+        let msg = "Bind address does not exist in stack".to_owned();
+        Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+    }
+
+    /// Removes a listener added with `ipv4_listen`. Returns `false` if there
+    /// was none.
+    pub fn ipv4_unlisten(&mut self, local_ip: Ipv4Addr, protocol: IpNextHeaderProtocol) -> bool {
+        self.interfaces
+            .values_mut()
+            .any(|stack_interface| stack_interface.ipv4_unlisten(local_ip, protocol))
+    }
+
     pub fn icmp_tx(&mut self,
                    dst: Ipv4Addr)
                    -> StackResult<IcmpTx<Ipv4Tx<EthernetTx<DatalinkTx>>>> {
@@ -418,10 +1716,9 @@ impl NetworkStack {
         where L: IcmpListener + 'static + Clone
     {
         if local_ip == Ipv4Addr::new(0, 0, 0, 0) {
-            let msg = "Rips does not support listening to all interfaces
-    yet"
-                .to_owned();
-            Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+            let mut icmp_listeners = self.wildcard.icmp.lock().unwrap();
+            icmp_listeners.entry(icmp_type).or_insert_with(Vec::new).push(Box::new(listener));
+            Ok(())
         } else {
             let mut added_to_interface = false;
             for stack_interface in self.interfaces.values_mut() {
@@ -461,45 +1758,82 @@ impl NetworkStack {
         }
     }
 
+    /// Binds `listener` to `addr`. Listeners bound to 0.0.0.0 receive
+    /// datagrams to all local addresses, including ones added later. A port
+    /// bound on 0.0.0.0 can not also be bound on a specific address, and the
+    /// other way around, so every datagram has at most one listener.
     fn udp_listen_ipv4<L>(&mut self, addr: SocketAddrV4, listener: L) -> io::Result<SocketAddr>
         where L: udp::UdpListener + 'static + Clone
     {
-        let local_ip = addr.ip();
-        let mut local_port = addr.port();
-        if local_ip == &Ipv4Addr::new(0, 0, 0, 0) {
-            let msg = "Rips does not support listening to all interfaces
-    yet"
-                .to_owned();
-            Err(io::Error::new(io::ErrorKind::AddrNotAvailable, msg))
+        let local_ip = *addr.ip();
+        let local_listeners = if local_ip == Ipv4Addr::new(0, 0, 0, 0) {
+            self.wildcard.udp.clone()
         } else {
-            for stack_interface in self.interfaces.values() {
-                if let Some(ip_data) = stack_interface.ipv4_datas.get(local_ip) {
-                    let mut udp_listeners = ip_data.udp_listeners.lock().unwrap();
-                    if local_port == 0 {
-                        local_port = self.get_random_port(&*udp_listeners);
-                    }
-                    if !udp_listeners.contains_key(&local_port) {
-                        udp_listeners.insert(local_port, Box::new(listener));
-                        return Ok(SocketAddr::V4(SocketAddrV4::new(*local_ip, local_port)));
-                    } else {
-                        let msg =
-                            format!("Port {} is already occupied on {}", local_port, local_ip);
-                        return Err(io::Error::new(io::ErrorKind::AddrInUse, msg));
-                    }
+            match self.interfaces
+                .values()
+                .filter_map(|stack_interface| stack_interface.ipv4_datas.get(&local_ip))
+                .next() {
+                Some(ip_data) => ip_data.udp_listeners.clone(),
+                None => {
+                    let msg = "Bind address does not exist in stack".to_owned();
+                    return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
                 }
             }
-            let msg = "Bind address does not exist in stack".to_owned();
-            Err(io::Error::new(io::ErrorKind::InvalidInput, msg))
+        };
+        let mut local_port = addr.port();
+        if local_port == 0 {
+            local_port = self.get_random_port(local_ip);
+        }
+        if self.udp_port_in_use(local_ip, local_port) {
+            let msg = format!("Port {} is already occupied on {}", local_port, local_ip);
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, msg));
         }
+        local_listeners.lock().unwrap().insert(local_port, Box::new(listener));
+        Ok(SocketAddr::V4(SocketAddrV4::new(local_ip, local_port)))
     }
 
-    fn get_random_port(&self, listeners: &udp::UdpListenerLookup) -> u16 {
+    /// Removes the Udp listener bound to `addr`, making the port available
+    /// again. Returns `false` if nothing was bound to it. Only the owner of
+    /// the listener should call this, since the port might have been bound
+    /// again if the listener closed itself.
+    pub fn udp_unlisten(&mut self, addr: SocketAddrV4) -> bool {
+        let local_ip = *addr.ip();
+        let listeners = if local_ip == Ipv4Addr::new(0, 0, 0, 0) {
+            Some(self.wildcard.udp.clone())
+        } else {
+            self.interfaces
+                .values()
+                .filter_map(|stack_interface| stack_interface.ipv4_datas.get(&local_ip))
+                .map(|ip_data| ip_data.udp_listeners.clone())
+                .next()
+        };
+        listeners.map_or(false, |listeners| {
+            listeners.lock().unwrap().remove(&addr.port()).is_some()
+        })
+    }
+
+    /// Returns `true` if binding to `port` on `local_ip` would conflict with
+    /// an existing listener. A wildcard bind conflicts with listeners on any
+    /// address, and any bind conflicts with a wildcard listener.
+    fn udp_port_in_use(&self, local_ip: Ipv4Addr, port: u16) -> bool {
+        let wildcard = local_ip == Ipv4Addr::new(0, 0, 0, 0);
+        if self.wildcard.udp.lock().unwrap().contains_key(&port) {
+            return true;
+        }
+        self.interfaces
+            .values()
+            .flat_map(|stack_interface| stack_interface.ipv4_datas.iter())
+            .filter(|&(ip, _)| wildcard || *ip == local_ip)
+            .any(|(_, ip_data)| ip_data.udp_listeners.lock().unwrap().contains_key(&port))
+    }
+
+    fn get_random_port(&self, local_ip: Ipv4Addr) -> u16 {
         let range = Range::new(LOCAL_PORT_RANGE_START, LOCAL_PORT_RANGE_END);
         let mut rng = rand::thread_rng();
         let mut port = 0;
         while port == 0 {
             let n = range.ind_sample(&mut rng);
-            if !listeners.contains_key(&n) {
+            if !self.udp_port_in_use(local_ip, n) {
                 port = n;
                 break;
             }
@@ -508,10 +1842,22 @@ impl NetworkStack {
     }
 }
 
+/// Gives the proxy Arp of every interface, and the forwarding, their own copy
+/// of `routing_table`.
+fn copy_routes(interfaces: &HashMap<Interface, StackInterface>,
+               forwarding: &Mutex<ForwardingTable>,
+               routing_table: &RoutingTable) {
+    for interface in interfaces.values() {
+        interface.set_routes(routing_table);
+    }
+    forwarding.lock().unwrap().routes = routing_table.clone();
+}
+
 #[derive(Clone)]
 pub struct DatalinkTx {
     tx: Arc<Mutex<TxBarrier>>,
     version: u64,
+    pending: Option<(Ipv4Addr, Sender<StackInterfaceMsg>)>,
 }
 
 impl DatalinkTx {
@@ -519,8 +1865,41 @@ impl DatalinkTx {
         DatalinkTx {
             tx: tx,
             version: version,
+            pending: None,
+        }
+    }
+
+    /// Creates a `DatalinkTx` that sends all packets to the pending queue for
+    /// `neighbour` in the interface thread instead of out on the network.
+    fn pending(tx: Arc<Mutex<TxBarrier>>,
+               version: u64,
+               neighbour: Ipv4Addr,
+               chan: Sender<StackInterfaceMsg>)
+               -> Self {
+        DatalinkTx {
+            tx: tx,
+            version: version,
+            pending: Some((neighbour, chan)),
         }
     }
+
+    fn enqueue<'p, P>(neighbour: Ipv4Addr,
+                      chan: &Sender<StackInterfaceMsg>,
+                      payload: &'p mut P)
+                      -> TxResult<()>
+        where P: Payload<()>
+    {
+        let packet_size = payload.packet_size();
+        for _ in 0..payload.num_packets() {
+            let mut buffer = vec![0; packet_size];
+            payload.build(&mut buffer);
+            let msg = StackInterfaceMsg::QueuePacket(neighbour, buffer.into_boxed_slice());
+            if chan.send(msg).is_err() {
+                return Err(TxError::Other("Interface thread has quit".to_owned()));
+            }
+        }
+        Ok(())
+    }
 }
 
 impl Tx<()> for DatalinkTx {
@@ -530,6 +1909,8 @@ impl Tx<()> for DatalinkTx {
         let mut tx = self.tx.lock().expect("Poisoned lock in stack. This is a Rips bug");
         if self.version != tx.version() {
             None
+        } else if let Some((ref neighbour, ref chan)) = self.pending {
+            Some(Self::enqueue(*neighbour, chan, payload))
         } else {
             Some(tx.send(payload))
         }
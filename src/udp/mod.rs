@@ -1,28 +1,47 @@
 use {NetworkStack, StackError, StackResult, DatalinkTx};
 use {TxError, TxResult};
 use ethernet::EthernetTx;
+use icmp::IcmpTypes;
 use ipv4::Ipv4Tx;
 
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::io;
-use std::net::{SocketAddr, SocketAddrV4, ToSocketAddrs};
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs};
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 
 use util;
 
 mod udp_rx;
 mod udp_tx;
 
-pub use self::udp_rx::{UdpListener, UdpListenerLookup, UdpRx};
-use self::udp_rx::UdpSocketReader;
+pub use self::udp_rx::{PacketInfo, UdpListener, UdpListenerLookup, UdpRx};
+use self::udp_rx::{Connection, PortUnreachableListener, UdpSocketReader};
 pub use self::udp_tx::{UdpBuilder, UdpTx};
 
+/// The port a socket is bound to. Shared by all handles to the socket and
+/// released in the stack when the last of them is dropped.
+struct UdpBinding {
+    addr: SocketAddr,
+    stack: Arc<Mutex<NetworkStack>>,
+}
 
+impl Drop for UdpBinding {
+    fn drop(&mut self) {
+        if let SocketAddr::V4(addr) = self.addr {
+            if let Ok(mut stack) = self.stack.lock() {
+                stack.udp_unlisten(addr);
+            }
+        }
+    }
+}
+
+/// A Udp socket bound to a local address. Made up of a `UdpSendHalf` and a
+/// `UdpRecvHalf`, that it can be split into. The port is released when the
+/// last handle to the socket is dropped.
 pub struct UdpSocket {
-    socket_addr: SocketAddr,
-    stack: Arc<Mutex<NetworkStack>>,
-    tx_cache: HashMap<SocketAddrV4, UdpTx<Ipv4Tx<EthernetTx<DatalinkTx>>>>,
-    rx: Option<UdpSocketReader>,
+    tx: UdpSendHalf,
+    rx: Option<UdpRecvHalf>,
 }
 
 impl UdpSocket {
@@ -34,19 +53,225 @@ impl UdpSocket {
             let mut stack = stack.lock().unwrap();
             try!(stack.udp_listen(addr, socket_reader.listener()))
         };
-        Ok(UdpSocket {
+        let binding = Arc::new(UdpBinding {
+            addr: socket_addr,
+            stack: stack.clone(),
+        });
+        let rx = UdpRecvHalf {
+            reader: socket_reader,
+            stack: stack.clone(),
+            binding: binding.clone(),
+            connection: None,
+            multicast_memberships: HashSet::new(),
+        };
+        let tx = UdpSendHalf {
             socket_addr: socket_addr,
             stack: stack,
+            binding: binding,
             tx_cache: HashMap::new(),
-            rx: Some(socket_reader),
+            ttl: None,
+            tos: 0,
+            broadcast: false,
+            multicast_ttl: 1,
+            connection: None,
+        };
+        Ok(UdpSocket {
+            tx: tx,
+            rx: Some(rx),
         })
     }
 
+    /// Splits the socket into a half for sending and one for receiving, so
+    /// they can be used from different threads without sharing a lock. The
+    /// send half can be cloned to send from more threads. The port stays
+    /// bound until both halves, and all clones, are dropped.
+    pub fn split(self) -> io::Result<(UdpSendHalf, UdpRecvHalf)> {
+        match self.rx {
+            Some(rx) => Ok((self.tx, rx)),
+            None => Err(no_reader()),
+        }
+    }
+
     pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
-        self.rx.as_ref().unwrap().recv_from(buf)
+        try!(self.reader()).recv_from(buf)
+    }
+
+    /// Like `recv_from`, but also returns what local address and interface
+    /// the datagram was received on. Useful on sockets bound to 0.0.0.0.
+    pub fn recv_from_info(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, PacketInfo)> {
+        try!(self.reader()).recv_from_info(buf)
+    }
+
+    /// Receives a datagram without removing it, so the next read returns it
+    /// again.
+    pub fn peek_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        try!(self.reader()).peek_from(buf)
+    }
+
+    /// Like `recv_from`, but without returning the source address. On a
+    /// connected socket only datagrams from the peer are received.
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        try!(self.reader()).recv(buf)
+    }
+
+    /// Sets how long reads wait for a datagram before failing with
+    /// `TimedOut`. `None` makes them wait forever, which is the default. A
+    /// zero duration is not allowed.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        try!(self.reader()).set_read_timeout(timeout)
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        try!(self.reader()).read_timeout()
+    }
+
+    /// Makes reads fail with `WouldBlock` instead of waiting when no
+    /// datagram is available. Sending never blocks.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        try!(self.reader()).set_nonblocking(nonblocking)
     }
 
     pub fn send_to<A: ToSocketAddrs>(&mut self, buf: &[u8], addr: A) -> io::Result<usize> {
+        self.tx.send_to(buf, addr)
+    }
+
+    /// Fixes the remote address of this socket to `addr`. Datagrams can then
+    /// be sent with `send`, and datagrams from other addresses are discarded.
+    /// If the peer reports the port as unreachable, the next call on the
+    /// socket fails with `ConnectionRefused`.
+    pub fn connect<A: ToSocketAddrs>(&mut self, addr: A) -> io::Result<()> {
+        let connection = try!(self.tx.connect_tx(addr));
+        if let Some(rx) = self.rx.as_mut() {
+            rx.set_connection(connection);
+        }
+        Ok(())
+    }
+
+    /// Sends `buf` to the address given to `connect`.
+    pub fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.tx.send(buf)
+    }
+
+    /// Returns the address given to `connect`.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.tx.peer_addr()
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        self.tx.local_addr()
+    }
+
+    /// Creates a handle to the same socket that can only send. Use `split`
+    /// to send and receive from different threads.
+    pub fn try_clone(&self) -> io::Result<UdpSocket> {
+        Ok(UdpSocket {
+            tx: self.tx.clone(),
+            rx: None,
+        })
+    }
+
+    /// Sets the time to live of packets sent from this socket. Until this is
+    /// called the default TTL of the stack is used.
+    pub fn set_ttl(&mut self, ttl: u32) -> io::Result<()> {
+        self.tx.set_ttl(ttl)
+    }
+
+    pub fn ttl(&self) -> io::Result<u32> {
+        self.tx.ttl()
+    }
+
+    /// Sets the type of service byte of packets sent from this socket, like
+    /// `IP_TOS`. The upper six bits are the DSCP and the lower two the ECN.
+    pub fn set_tos(&mut self, tos: u8) -> io::Result<()> {
+        self.tx.set_tos(tos)
+    }
+
+    pub fn tos(&self) -> io::Result<u8> {
+        self.tx.tos()
+    }
+
+    /// Sets if this socket is allowed to send to broadcast addresses, like
+    /// `SO_BROADCAST`. Disabled by default.
+    pub fn set_broadcast(&mut self, broadcast: bool) -> io::Result<()> {
+        self.tx.set_broadcast(broadcast)
+    }
+
+    pub fn broadcast(&self) -> io::Result<bool> {
+        self.tx.broadcast()
+    }
+
+    /// Joins the multicast group `multiaddr` on the interface with the
+    /// address `interface`, or the interface packets to the group are routed
+    /// out on if `interface` is 0.0.0.0. Packets to the group are then
+    /// received on this socket if they are sent to its port. The group is
+    /// left when the socket is dropped.
+    pub fn join_multicast_v4(&mut self,
+                             multiaddr: &Ipv4Addr,
+                             interface: &Ipv4Addr)
+                             -> io::Result<()> {
+        try!(self.reader_mut()).join_multicast_v4(multiaddr, interface)
+    }
+
+    /// Leaves a multicast group joined with `join_multicast_v4`.
+    pub fn leave_multicast_v4(&mut self,
+                              multiaddr: &Ipv4Addr,
+                              interface: &Ipv4Addr)
+                              -> io::Result<()> {
+        try!(self.reader_mut()).leave_multicast_v4(multiaddr, interface)
+    }
+
+    /// Sets the time to live of multicast packets sent from this socket.
+    /// Defaults to one, keeping the packets on the local network.
+    pub fn set_multicast_ttl_v4(&mut self, ttl: u32) -> io::Result<()> {
+        self.tx.set_multicast_ttl_v4(ttl)
+    }
+
+    pub fn multicast_ttl_v4(&self) -> io::Result<u32> {
+        self.tx.multicast_ttl_v4()
+    }
+
+    fn reader(&self) -> io::Result<&UdpRecvHalf> {
+        self.rx.as_ref().ok_or_else(no_reader)
+    }
+
+    fn reader_mut(&mut self) -> io::Result<&mut UdpRecvHalf> {
+        self.rx.as_mut().ok_or_else(no_reader)
+    }
+}
+
+/// The sending half of a `UdpSocket`, created by `UdpSocket::split`.
+pub struct UdpSendHalf {
+    socket_addr: SocketAddr,
+    stack: Arc<Mutex<NetworkStack>>,
+    binding: Arc<UdpBinding>,
+    tx_cache: HashMap<SocketAddrV4, UdpTx<Ipv4Tx<EthernetTx<DatalinkTx>>>>,
+    ttl: Option<u8>,
+    tos: u8,
+    broadcast: bool,
+    multicast_ttl: u8,
+    connection: Option<Arc<Mutex<Connection>>>,
+}
+
+impl Clone for UdpSendHalf {
+    /// Clones the settings of this half. The clone builds its own tx-objects.
+    fn clone(&self) -> Self {
+        UdpSendHalf {
+            socket_addr: self.socket_addr,
+            stack: self.stack.clone(),
+            binding: self.binding.clone(),
+            tx_cache: HashMap::new(),
+            ttl: self.ttl,
+            tos: self.tos,
+            broadcast: self.broadcast,
+            multicast_ttl: self.multicast_ttl,
+            connection: self.connection.clone(),
+        }
+    }
+}
+
+impl UdpSendHalf {
+    pub fn send_to<A: ToSocketAddrs>(&mut self, buf: &[u8], addr: A) -> io::Result<usize> {
+        try!(take_refused(&self.connection));
         match try!(util::first_socket_addr(addr)) {
             SocketAddr::V4(dst) => {
                 self.internal_send(buf, dst)
@@ -62,28 +287,151 @@ yet"
         }
     }
 
+    /// Sends `buf` to the address the socket was connected to.
+    pub fn send(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let peer = try!(self.connected_peer());
+        self.send_to(buf, peer)
+    }
+
+    /// Returns the address the socket was connected to.
+    pub fn peer_addr(&self) -> io::Result<SocketAddr> {
+        self.connected_peer().map(SocketAddr::V4)
+    }
+
     pub fn local_addr(&self) -> io::Result<SocketAddr> {
         Ok(self.socket_addr)
     }
 
-    pub fn try_clone(&self) -> io::Result<UdpSocket> {
-        Ok(UdpSocket {
-            socket_addr: self.socket_addr,
-            stack: self.stack.clone(),
-            tx_cache: HashMap::new(),
-            rx: None,
-        })
+    /// Sets the time to live of packets sent from this half. Until this is
+    /// called the default TTL of the stack is used.
+    pub fn set_ttl(&mut self, ttl: u32) -> io::Result<()> {
+        if ttl > ::std::u8::MAX as u32 {
+            let msg = format!("Invalid TTL {}", ttl);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        self.ttl = Some(ttl as u8);
+        self.tx_cache.clear();
+        Ok(())
+    }
+
+    pub fn ttl(&self) -> io::Result<u32> {
+        let ttl = match self.ttl {
+            Some(ttl) => ttl,
+            None => self.stack.lock().unwrap().default_ttl(),
+        };
+        Ok(ttl as u32)
+    }
+
+    /// Sets the type of service byte of packets sent from this half, like
+    /// `IP_TOS`.
+    pub fn set_tos(&mut self, tos: u8) -> io::Result<()> {
+        self.tos = tos;
+        self.tx_cache.clear();
+        Ok(())
+    }
+
+    pub fn tos(&self) -> io::Result<u8> {
+        Ok(self.tos)
+    }
+
+    /// Sets if this half is allowed to send to broadcast addresses, like
+    /// `SO_BROADCAST`.
+    pub fn set_broadcast(&mut self, broadcast: bool) -> io::Result<()> {
+        self.broadcast = broadcast;
+        self.tx_cache.clear();
+        Ok(())
+    }
+
+    pub fn broadcast(&self) -> io::Result<bool> {
+        Ok(self.broadcast)
+    }
+
+    /// Sets the time to live of multicast packets sent from this half.
+    pub fn set_multicast_ttl_v4(&mut self, ttl: u32) -> io::Result<()> {
+        if ttl > ::std::u8::MAX as u32 {
+            let msg = format!("Invalid TTL {}", ttl);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        self.multicast_ttl = ttl as u8;
+        self.tx_cache.clear();
+        Ok(())
+    }
+
+    pub fn multicast_ttl_v4(&self) -> io::Result<u32> {
+        Ok(self.multicast_ttl as u32)
+    }
+
+    /// Connects this half to `addr` and returns the connection, to be shared
+    /// with the receive half of the socket.
+    fn connect_tx<A: ToSocketAddrs>(&mut self, addr: A) -> io::Result<Arc<Mutex<Connection>>> {
+        let peer = match try!(util::first_socket_addr(addr)) {
+            SocketAddr::V4(peer) => peer,
+            SocketAddr::V6(_) => {
+                let msg = "Rips does not support IPv6 yet".to_owned();
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        };
+        let local_ip = match self.socket_addr {
+            SocketAddr::V4(addr) => *addr.ip(),
+            SocketAddr::V6(_) => {
+                let msg = "Rips does not support IPv6 yet".to_owned();
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        };
+        let udp_tx = try!(self.create_tx(peer));
+        let connection = Arc::new(Mutex::new(Connection {
+            local: udp_tx.src(),
+            peer: peer,
+            refused: false,
+        }));
+        {
+            let listener = PortUnreachableListener::new(&connection);
+            let mut stack = self.stack.lock().unwrap();
+            try!(stack.icmp_listen(local_ip, IcmpTypes::DestinationUnreachable, listener));
+        }
+        self.tx_cache.insert(peer, udp_tx);
+        self.connection = Some(connection.clone());
+        Ok(connection)
+    }
+
+    fn connected_peer(&self) -> io::Result<SocketAddrV4> {
+        match self.connection {
+            Some(ref connection) => Ok(connection.lock().unwrap().peer),
+            None => {
+                Err(io::Error::new(io::ErrorKind::NotConnected,
+                                   "Socket is not connected".to_owned()))
+            }
+        }
+    }
+
+    fn create_tx(&self, dst: SocketAddrV4) -> StackResult<UdpTx<Ipv4Tx<EthernetTx<DatalinkTx>>>> {
+        let (dst_ip, dst_port) = (*dst.ip(), dst.port());
+        let mut ipv4_tx = {
+            let mut stack = self.stack.lock().unwrap();
+            if !self.broadcast && stack.is_broadcast(dst_ip) {
+                let msg = format!("Broadcast not enabled on socket, can't send to {}", dst_ip);
+                let e = io::Error::new(io::ErrorKind::PermissionDenied, msg);
+                return Err(StackError::IoError(e));
+            }
+            try!(stack.ipv4_tx(dst_ip))
+        };
+        if dst_ip.is_multicast() {
+            ipv4_tx.set_ttl(self.multicast_ttl);
+        } else if let Some(ttl) = self.ttl {
+            ipv4_tx.set_ttl(ttl);
+        }
+        ipv4_tx.set_dscp(self.tos >> 2);
+        ipv4_tx.set_ecn(self.tos & 0b11);
+        let src = SocketAddrV4::new(ipv4_tx.src(), self.socket_addr.port());
+        let dst_addr = SocketAddrV4::new(dst_ip, dst_port);
+        Ok(UdpTx::new(ipv4_tx, src, dst_addr))
     }
 
     fn internal_send(&mut self, buf: &[u8], dst: SocketAddrV4) -> StackResult<()> {
         match self.internal_send_on_cached_tx(buf, dst) {
             None => {
-                let (dst_ip, dst_port) = (*dst.ip(), dst.port());
-                let new_udp_tx = {
-                    let mut stack = self.stack.lock().unwrap();
-                    try!(stack.udp_tx(dst_ip, self.socket_addr.port(), dst_port))
-                };
-                self.tx_cache.insert(dst, new_udp_tx);
+                let udp_tx = try!(self.create_tx(dst));
+                self.tx_cache.insert(dst, udp_tx);
                 self.internal_send(buf, dst)
             }
             Some(result) => result.map_err(StackError::TxError),
@@ -100,3 +448,130 @@ yet"
         self.tx_cache.get_mut(&dst).and_then(|udp_tx| udp_tx.send(buf))
     }
 }
+
+/// The receiving half of a `UdpSocket`, created by `UdpSocket::split`. Owns
+/// the multicast memberships of the socket and leaves them when dropped.
+pub struct UdpRecvHalf {
+    reader: UdpSocketReader,
+    stack: Arc<Mutex<NetworkStack>>,
+    binding: Arc<UdpBinding>,
+    connection: Option<Arc<Mutex<Connection>>>,
+    multicast_memberships: HashSet<(Ipv4Addr, Ipv4Addr)>,
+}
+
+impl UdpRecvHalf {
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        self.recv_from_info(buf).map(|(len, addr, _info)| (len, addr))
+    }
+
+    /// Like `recv_from`, but also returns what local address and interface
+    /// the datagram was received on.
+    pub fn recv_from_info(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, PacketInfo)> {
+        try!(take_refused(&self.connection));
+        self.reader.recv_from(buf)
+    }
+
+    /// Receives a datagram without removing it, so the next read returns it
+    /// again.
+    pub fn peek_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
+        try!(take_refused(&self.connection));
+        self.reader.peek_from(buf).map(|(len, addr, _info)| (len, addr))
+    }
+
+    pub fn recv(&self, buf: &mut [u8]) -> io::Result<usize> {
+        self.recv_from(buf).map(|(len, _addr)| len)
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        self.reader.set_read_timeout(timeout)
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        Ok(self.reader.read_timeout())
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        self.reader.set_nonblocking(nonblocking);
+        Ok(())
+    }
+
+    pub fn local_addr(&self) -> io::Result<SocketAddr> {
+        Ok(self.binding.addr)
+    }
+
+    /// Joins the multicast group `multiaddr` on the interface with the
+    /// address `interface`, like `UdpSocket::join_multicast_v4`. The group
+    /// is left when this half is dropped.
+    pub fn join_multicast_v4(&mut self,
+                             multiaddr: &Ipv4Addr,
+                             interface: &Ipv4Addr)
+                             -> io::Result<()> {
+        let membership = (*multiaddr, *interface);
+        if self.multicast_memberships.contains(&membership) {
+            let msg = format!("Already joined {} on {}", multiaddr, interface);
+            return Err(io::Error::new(io::ErrorKind::AddrInUse, msg));
+        }
+        try!(self.stack.lock().unwrap().join_multicast_v4(*multiaddr, *interface));
+        self.multicast_memberships.insert(membership);
+        Ok(())
+    }
+
+    /// Leaves a multicast group joined with `join_multicast_v4`.
+    pub fn leave_multicast_v4(&mut self,
+                              multiaddr: &Ipv4Addr,
+                              interface: &Ipv4Addr)
+                              -> io::Result<()> {
+        let membership = (*multiaddr, *interface);
+        if !self.multicast_memberships.contains(&membership) {
+            let msg = format!("Not a member of {} on {}", multiaddr, interface);
+            return Err(io::Error::new(io::ErrorKind::AddrNotAvailable, msg));
+        }
+        try!(self.stack.lock().unwrap().leave_multicast_v4(*multiaddr, *interface));
+        self.multicast_memberships.remove(&membership);
+        Ok(())
+    }
+
+    /// Makes this half only receive datagrams from the peer of `connection`
+    /// and report its errors.
+    fn set_connection(&mut self, connection: Arc<Mutex<Connection>>) {
+        let peer = connection.lock().unwrap().peer;
+        self.reader.set_peer(Some(peer));
+        self.connection = Some(connection);
+    }
+}
+
+impl Drop for UdpRecvHalf {
+    fn drop(&mut self) {
+        leave_multicast_groups(&self.stack, &mut self.multicast_memberships);
+    }
+}
+
+/// Returns the "port unreachable" error reported by the peer of
+/// `connection` since the last call, if any.
+fn take_refused(connection: &Option<Arc<Mutex<Connection>>>) -> io::Result<()> {
+    if let Some(ref connection) = *connection {
+        let mut connection = connection.lock().unwrap();
+        if connection.refused {
+            connection.refused = false;
+            let msg = format!("Port unreachable at {}", connection.peer);
+            return Err(io::Error::new(io::ErrorKind::ConnectionRefused, msg));
+        }
+    }
+    Ok(())
+}
+
+fn leave_multicast_groups(stack: &Arc<Mutex<NetworkStack>>,
+                          memberships: &mut HashSet<(Ipv4Addr, Ipv4Addr)>) {
+    if let Ok(mut stack) = stack.lock() {
+        for (multiaddr, interface) in memberships.drain() {
+            if let Err(e) = stack.leave_multicast_v4(multiaddr, interface) {
+                warn!("Unable to leave {} on {}: {}", multiaddr, interface, e);
+            }
+        }
+    }
+}
+
+fn no_reader() -> io::Error {
+    io::Error::new(io::ErrorKind::Other,
+                   "Cloned sockets can't receive".to_owned())
+}
@@ -1,29 +1,56 @@
-use {RxError, RxResult};
-use ipv4::Ipv4Listener;
+use {Interface, RxError, RxResult};
+use icmp::{DestinationUnreachableCodes, IcmpListener};
+use ipv4::{IpNextHeaderProtocols, Ipv4Listener};
 
 use pnet::packet::Packet;
+use pnet::packet::icmp::IcmpPacket;
 use pnet::packet::ipv4::Ipv4Packet;
 use pnet::packet::udp::UdpPacket;
 
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
 use std::io;
-use std::net::{SocketAddr, SocketAddrV4};
-use std::sync::{Arc, Mutex, mpsc};
-use std::time::SystemTime;
+use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
+use std::sync::{Arc, Mutex, Weak, mpsc};
+use std::time::{Duration, Instant, SystemTime};
 
 pub trait UdpListener: Send {
-    fn recv(&mut self, time: SystemTime, packet: &Ipv4Packet) -> (RxResult, bool);
+    /// Called by `UdpRx` with packets to the port of this listener, received
+    /// on `interface`. Returning `false` removes the listener and frees its
+    /// port.
+    fn recv(&mut self,
+            time: SystemTime,
+            interface: &Interface,
+            packet: &Ipv4Packet)
+            -> (RxResult, bool);
 }
 
 pub type UdpListenerLookup = HashMap<u16, Box<UdpListener>>;
 
+/// Where a datagram received on a `UdpSocket` was sent to, similar to
+/// `IP_PKTINFO`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PacketInfo {
+    /// The destination of the datagram. One of the local addresses, or a
+    /// broadcast or multicast address.
+    pub local_ip: Ipv4Addr,
+    /// The interface the datagram was received on.
+    pub interface: Interface,
+}
+
+/// Listener and parser of Udp packets to one local address, or to any
+/// address of an interface.
 pub struct UdpRx {
+    interface: Interface,
     listeners: Arc<Mutex<UdpListenerLookup>>,
 }
 
 impl UdpRx {
-    pub fn new(listeners: Arc<Mutex<UdpListenerLookup>>) -> UdpRx {
-        UdpRx { listeners: listeners }
+    pub fn new(interface: Interface, listeners: Arc<Mutex<UdpListenerLookup>>) -> UdpRx {
+        UdpRx {
+            interface: interface,
+            listeners: listeners,
+        }
     }
 
     fn get_port(pkg: &Ipv4Packet) -> Result<u16, RxError> {
@@ -47,32 +74,55 @@ impl Ipv4Listener for UdpRx {
     fn recv(&mut self, time: SystemTime, ip_pkg: Ipv4Packet) -> RxResult {
         let port = try!(Self::get_port(&ip_pkg));
         let mut listeners = self.listeners.lock().unwrap();
-        if let Some(listener) = listeners.get_mut(&port) {
-            let (result, _resume) = listener.recv(time, &ip_pkg);
-            result
-            // TODO: When resume turns false, remove this socket.
-        } else {
-            Err(RxError::NoListener(format!("Udp, no listener for port {:?}", port)))
+        let (result, resume) = match listeners.get_mut(&port) {
+            Some(listener) => listener.recv(time, &self.interface, &ip_pkg),
+            None => {
+                return Err(RxError::NoListener(format!("Udp, no listener for port {:?}", port)))
+            }
+        };
+        if !resume {
+            debug!("Udp listener on port {} closed, removing it", port);
+            listeners.remove(&port);
         }
+        result
     }
 }
 
+/// A received datagram, as queued by `UdpSocketListener`.
+type Datagram = (SystemTime, Interface, Box<[u8]>);
+
+/// Queues datagrams for a `UdpSocketReader`. Never asks to be removed, not
+/// even after the reader is dropped, since the port belongs to the socket
+/// until its `UdpBinding` releases it.
 #[derive(Clone)]
 pub struct UdpSocketListener {
-    chan: mpsc::Sender<(SystemTime, Box<[u8]>)>,
+    chan: mpsc::Sender<Datagram>,
 }
 
 impl UdpListener for UdpSocketListener {
-    fn recv(&mut self, time: SystemTime, packet: &Ipv4Packet) -> (RxResult, bool) {
+    fn recv(&mut self,
+            time: SystemTime,
+            interface: &Interface,
+            packet: &Ipv4Packet)
+            -> (RxResult, bool) {
         let data = packet.packet().to_vec().into_boxed_slice();
-        let resume = self.chan.send((time, data)).is_ok();
-        (Ok(()), resume)
+        let result = match self.chan.send((time, interface.clone(), data)) {
+            Ok(()) => Ok(()),
+            Err(_) => Err(RxError::NoListener("Udp socket is not receiving".to_owned())),
+        };
+        (result, true)
     }
 }
 
 pub struct UdpSocketReader {
-    port: mpsc::Receiver<(SystemTime, Box<[u8]>)>,
+    port: mpsc::Receiver<Datagram>,
     chan: UdpSocketListener,
+    /// A datagram read by `peek_from`, returned by the next read.
+    peeked: RefCell<Option<Datagram>>,
+    read_timeout: Cell<Option<Duration>>,
+    nonblocking: Cell<bool>,
+    /// Only datagrams from this address are returned, if set.
+    peer: Option<SocketAddrV4>,
 }
 
 impl UdpSocketReader {
@@ -81,13 +131,131 @@ impl UdpSocketReader {
         UdpSocketReader {
             port: rx,
             chan: UdpSocketListener { chan: tx },
+            peeked: RefCell::new(None),
+            read_timeout: Cell::new(None),
+            nonblocking: Cell::new(false),
+            peer: None,
+        }
+    }
+
+    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, PacketInfo)> {
+        let peeked = self.peeked.borrow_mut().take();
+        let datagram = match peeked.into_iter().find(|datagram| self.accepts(datagram)) {
+            Some(datagram) => datagram,
+            None => try!(self.wait()),
+        };
+        Self::read(&datagram, buf)
+    }
+
+    /// Like `recv_from`, but leaves the datagram to be returned again by the
+    /// next read.
+    pub fn peek_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr, PacketInfo)> {
+        let mut peeked = self.peeked.borrow_mut();
+        let accepted = peeked.as_ref().map_or(false, |datagram| self.accepts(datagram));
+        if !accepted {
+            *peeked = Some(try!(self.wait()));
+        }
+        Self::read(peeked.as_ref().unwrap(), buf)
+    }
+
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        if timeout == Some(Duration::new(0, 0)) {
+            let msg = "Cannot set a 0 duration timeout".to_owned();
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
         }
+        self.read_timeout.set(timeout);
+        Ok(())
     }
 
-    pub fn recv_from(&self, buf: &mut [u8]) -> io::Result<(usize, SocketAddr)> {
-        let (_time, data) = self.port.recv().unwrap();
-        let ipv4_pkg = Ipv4Packet::new(&data).unwrap();
+    pub fn read_timeout(&self) -> Option<Duration> {
+        self.read_timeout.get()
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) {
+        self.nonblocking.set(nonblocking);
+    }
+
+    /// Makes reads discard datagrams not sent from `peer`.
+    pub fn set_peer(&mut self, peer: Option<SocketAddrV4>) {
+        self.peer = peer;
+    }
+
+    pub fn listener(&mut self) -> UdpSocketListener {
+        self.chan.clone()
+    }
+
+    /// Waits for the next datagram from the stack that is not discarded
+    /// because of the peer. Returns `WouldBlock` if in nonblocking mode and
+    /// there is none, or `TimedOut` if the read timeout passes without one
+    /// arriving.
+    fn wait(&self) -> io::Result<Datagram> {
+        let deadline = self.read_timeout.get().map(|timeout| Instant::now() + timeout);
+        loop {
+            let timeout = match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        return Err(Self::timed_out());
+                    }
+                    Some(deadline - now)
+                }
+                None => None,
+            };
+            let datagram = try!(self.wait_any(timeout));
+            if self.accepts(&datagram) {
+                return Ok(datagram);
+            }
+        }
+    }
+
+    fn wait_any(&self, timeout: Option<Duration>) -> io::Result<Datagram> {
+        if self.nonblocking.get() {
+            self.port.try_recv().map_err(|e| match e {
+                mpsc::TryRecvError::Empty => {
+                    io::Error::new(io::ErrorKind::WouldBlock, "No datagram available".to_owned())
+                }
+                mpsc::TryRecvError::Disconnected => Self::closed(),
+            })
+        } else if let Some(timeout) = timeout {
+            self.port.recv_timeout(timeout).map_err(|e| match e {
+                mpsc::RecvTimeoutError::Timeout => Self::timed_out(),
+                mpsc::RecvTimeoutError::Disconnected => Self::closed(),
+            })
+        } else {
+            self.port.recv().map_err(|_| Self::closed())
+        }
+    }
+
+    fn accepts(&self, datagram: &Datagram) -> bool {
+        match self.peer {
+            Some(peer) => {
+                let ipv4_pkg = Ipv4Packet::new(&datagram.2).unwrap();
+                let udp_pkg = UdpPacket::new(ipv4_pkg.payload()).unwrap();
+                *peer.ip() == ipv4_pkg.get_source() && peer.port() == udp_pkg.get_source()
+            }
+            None => true,
+        }
+    }
+
+    fn timed_out() -> io::Error {
+        io::Error::new(io::ErrorKind::TimedOut, "Read timed out".to_owned())
+    }
+
+    fn closed() -> io::Error {
+        io::Error::new(io::ErrorKind::NotConnected,
+                       "Socket is no longer bound in the stack".to_owned())
+    }
+
+    fn read(datagram: &Datagram,
+            buf: &mut [u8])
+            -> io::Result<(usize, SocketAddr, PacketInfo)> {
+        let (_, ref interface, ref data) = *datagram;
+        let ipv4_pkg = Ipv4Packet::new(data).unwrap();
         let ip = ipv4_pkg.get_source();
+        let info = PacketInfo {
+            local_ip: ipv4_pkg.get_destination(),
+            interface: interface.clone(),
+        };
         let udp_pkg = UdpPacket::new(ipv4_pkg.payload()).unwrap();
         let port = udp_pkg.get_source();
         let data = udp_pkg.payload();
@@ -96,11 +264,78 @@ impl UdpSocketReader {
                                "Data does not fit buffer".to_owned()))
         } else {
             buf[..data.len()].copy_from_slice(data);
-            Ok((data.len(), SocketAddr::V4(SocketAddrV4::new(ip, port))))
+            Ok((data.len(), SocketAddr::V4(SocketAddrV4::new(ip, port)), info))
         }
     }
+}
 
-    pub fn listener(&mut self) -> UdpSocketListener {
-        self.chan.clone()
+/// The addresses of a connected `UdpSocket`, shared with the
+/// `PortUnreachableListener` reporting errors about it.
+pub struct Connection {
+    /// The address datagrams to the peer are sent from.
+    pub local: SocketAddrV4,
+    pub peer: SocketAddrV4,
+    /// Set when the peer reported the port as unreachable, until the error
+    /// is returned from the socket.
+    pub refused: bool,
+}
+
+/// Listens for Icmp "port unreachable" messages about datagrams sent from
+/// the local address to the peer of a connection. Closes when the connection
+/// is dropped or replaced.
+#[derive(Clone)]
+pub struct PortUnreachableListener {
+    connection: Weak<Mutex<Connection>>,
+}
+
+impl PortUnreachableListener {
+    pub fn new(connection: &Arc<Mutex<Connection>>) -> Self {
+        PortUnreachableListener { connection: Arc::downgrade(connection) }
+    }
+
+    /// Reads the source and destination of the Udp datagram an Icmp "port
+    /// unreachable" message was sent about.
+    fn unreachable_datagram(icmp_pkg: &IcmpPacket) -> Option<(SocketAddrV4, SocketAddrV4)> {
+        if icmp_pkg.get_icmp_code() != DestinationUnreachableCodes::DestinationPortUnreachable {
+            return None;
+        }
+        let data = icmp_pkg.packet();
+        if data.len() < 8 + Ipv4Packet::minimum_packet_size() {
+            return None;
+        }
+        let orig_pkg = Ipv4Packet::new(&data[8..]).unwrap();
+        let header_length = orig_pkg.get_header_length() as usize * 4;
+        let orig_data = &data[8..];
+        if orig_pkg.get_next_level_protocol() != IpNextHeaderProtocols::Udp ||
+           orig_data.len() < header_length + 4 {
+            return None;
+        }
+        let ports = &orig_data[header_length..];
+        let src_port = ((ports[0] as u16) << 8) | ports[1] as u16;
+        let dst_port = ((ports[2] as u16) << 8) | ports[3] as u16;
+        Some((SocketAddrV4::new(orig_pkg.get_source(), src_port),
+              SocketAddrV4::new(orig_pkg.get_destination(), dst_port)))
+    }
+}
+
+impl IcmpListener for PortUnreachableListener {
+    fn recv(&mut self, _time: SystemTime, packet: &Ipv4Packet) {
+        let icmp_pkg = match IcmpPacket::new(packet.payload()) {
+            Some(icmp_pkg) => icmp_pkg,
+            None => return,
+        };
+        if let Some((local, peer)) = Self::unreachable_datagram(&icmp_pkg) {
+            if let Some(connection) = self.connection.upgrade() {
+                let mut connection = connection.lock().unwrap();
+                if connection.local == local && connection.peer == peer {
+                    debug!("Udp port {} reported unreachable", peer);
+                    connection.refused = true;
+                }
+            }
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        self.connection.upgrade().is_some()
     }
 }
@@ -22,6 +22,10 @@ impl<T> UdpTx<T> {
             dst: dst,
         }
     }
+
+    pub fn src(&self) -> SocketAddrV4 {
+        self.src
+    }
 }
 
 impl<T: Tx<Ipv4Fields>> UdpTx<T> {
@@ -1,57 +1,259 @@
+use std::cmp;
 use std::ops::{Deref, DerefMut};
 
+/// Decides what a `Buffer` does with pushed data that overlaps data it
+/// already has.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OverlapPolicy {
+    /// Keep the data that arrived first, only fill in the missing parts.
+    First,
+    /// Overwrite existing data with the newly pushed data.
+    Last,
+    /// Fail the push if the overlapping data differs from the existing data.
+    /// Exact duplicates are accepted.
+    Reject,
+}
+
+/// Errors returned when pushing data to a `Buffer`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BufferError {
+    /// The data does not fit in the buffer, or extends past the end of the
+    /// data given by an earlier push.
+    OutOfBounds,
+    /// The end of the data does not match the end given by an earlier push,
+    /// or data already exists past this end.
+    InvalidEnd,
+    /// The data overlaps existing data with different content and the policy
+    /// is `OverlapPolicy::Reject`.
+    Overlap,
+}
+
 /// Structure used to reassemble data arriving in fragments.
-/// Supposed to handle out of order arrival, but does not at the moment.
+/// Data can arrive in any order. Keeps track of what is missing with a list
+/// of holes, as described in RFC 815.
 pub struct Buffer {
     data: Vec<u8>,
-    lowest_missing: usize,
+    capacity: usize,
+    /// Sorted, non-overlapping, ranges of missing data. Start inclusive, end
+    /// exclusive.
+    holes: Vec<(usize, usize)>,
+    end: Option<usize>,
+    policy: OverlapPolicy,
 }
 
 impl Buffer {
-    pub fn new(capacity: usize) -> Buffer {
+    pub fn new(capacity: usize, policy: OverlapPolicy) -> Buffer {
         Buffer {
-            data: vec![0; capacity],
-            lowest_missing: 0,
+            data: Vec::new(),
+            capacity: capacity,
+            holes: vec![(0, capacity)],
+            end: None,
+            policy: policy,
         }
     }
 
-    /// Push new data to this `Buffer`. Returns the lowest index of missing
-    /// data on success.
-    /// This is equivalent to the length of the valid data at the start of the
-    /// buffer. Will fail if the given data offset is not valid.
-    // TODO: Support out of order data
-    pub fn push(&mut self, offset: usize, data: &[u8]) -> Result<usize, ()> {
-        if offset == self.lowest_missing {
-            self.lowest_missing += data.len();
+    /// Push new data to this `Buffer` at `offset`. `last` marks that this is
+    /// the last piece of data, so its end is the end of the complete data.
+    /// Data may be pushed in any order, with overlaps handled according to the
+    /// `OverlapPolicy` of this `Buffer`.
+    pub fn push(&mut self, offset: usize, data: &[u8], last: bool) -> Result<(), BufferError> {
+        let end = offset + data.len();
+        if end > self.capacity || self.end.map_or(false, |e| end > e) {
+            return Err(BufferError::OutOfBounds);
+        }
+        if last && (self.end.map_or(false, |e| e != end) || self.data.len() > end) {
+            return Err(BufferError::InvalidEnd);
+        }
+        let missing = self.missing_in(offset, end);
+        if self.policy == OverlapPolicy::Reject && !self.matches_existing(offset, data, &missing) {
+            return Err(BufferError::Overlap);
+        }
+
+        if self.data.len() < end {
+            self.data.resize(end, 0);
+        }
+        if self.policy == OverlapPolicy::Last {
+            self.data[offset..end].copy_from_slice(data);
         } else {
-            return Err(());
+            for &(start, stop) in &missing {
+                self.data[start..stop].copy_from_slice(&data[start - offset..stop - offset]);
+            }
         }
-        self.data[offset..offset + data.len()].copy_from_slice(data);
-        Ok(self.lowest_missing)
+        self.fill_holes(offset, end);
+        if last {
+            self.end = Some(end);
+            self.holes.retain(|&(start, _)| start < end);
+            if let Some(hole) = self.holes.last_mut() {
+                hole.1 = cmp::min(hole.1, end);
+            }
+        }
+        Ok(())
+    }
+
+    /// Returns `true` when the last data has been pushed and there are no
+    /// holes left.
+    pub fn is_complete(&self) -> bool {
+        self.end.is_some() && self.holes.is_empty()
+    }
+
+    /// Returns the number of bytes currently allocated for data. Grows with
+    /// the highest end of the pushed data.
+    pub fn allocated(&self) -> usize {
+        self.data.len()
     }
 
     /// Consumes the `Buffer` and returns the data in an owned slice
     pub fn into_vec(self) -> Vec<u8> {
         self.data
     }
+
+    /// Returns the lowest index of missing data. This is equivalent to the
+    /// length of the valid data at the start of the buffer.
+    fn lowest_missing(&self) -> usize {
+        match self.holes.first() {
+            Some(&(start, _)) => cmp::min(start, self.data.len()),
+            None => self.data.len(),
+        }
+    }
+
+    /// Returns the parts of `start..end` that are missing, in order.
+    fn missing_in(&self, start: usize, end: usize) -> Vec<(usize, usize)> {
+        self.holes
+            .iter()
+            .filter(|&&(hole_start, hole_end)| hole_start < end && start < hole_end)
+            .map(|&(hole_start, hole_end)| (cmp::max(start, hole_start), cmp::min(end, hole_end)))
+            .collect()
+    }
+
+    /// Checks that all parts of `data` that are not `missing` are equal to
+    /// what is already in the buffer.
+    fn matches_existing(&self, offset: usize, data: &[u8], missing: &[(usize, usize)]) -> bool {
+        let end = offset + data.len();
+        let tail = [(end, end)];
+        let mut pos = offset;
+        for &(start, stop) in missing.iter().chain(tail.iter()) {
+            if self.data[pos..start] != data[pos - offset..start - offset] {
+                return false;
+            }
+            pos = stop;
+        }
+        true
+    }
+
+    /// Removes `start..end` from the holes, as described in RFC 815.
+    fn fill_holes(&mut self, start: usize, end: usize) {
+        let mut holes = Vec::with_capacity(self.holes.len() + 1);
+        for &(hole_start, hole_end) in &self.holes {
+            if end <= hole_start || hole_end <= start {
+                holes.push((hole_start, hole_end));
+                continue;
+            }
+            if hole_start < start {
+                holes.push((hole_start, start));
+            }
+            if end < hole_end {
+                holes.push((end, hole_end));
+            }
+        }
+        self.holes = holes;
+    }
 }
 
 impl Deref for Buffer {
     type Target = [u8];
 
     fn deref(&self) -> &[u8] {
-        &self.data[..self.lowest_missing]
+        &self.data[..self.lowest_missing()]
     }
 }
 
 impl DerefMut for Buffer {
     fn deref_mut(&mut self) -> &mut [u8] {
-        &mut self.data[..self.lowest_missing]
+        let lowest_missing = self.lowest_missing();
+        &mut self.data[..lowest_missing]
     }
 }
 
 
 #[cfg(test)]
 mod tests {
-    // TODO: Write unit tests
+    use super::*;
+
+    #[test]
+    fn in_order() {
+        let mut testee = Buffer::new(100, OverlapPolicy::First);
+        testee.push(0, &[1, 2], false).unwrap();
+        assert_eq!([1, 2], *testee);
+        testee.push(2, &[3, 4], true).unwrap();
+        assert!(testee.is_complete());
+        assert_eq!(vec![1, 2, 3, 4], testee.into_vec());
+    }
+
+    #[test]
+    fn out_of_order() {
+        let mut testee = Buffer::new(100, OverlapPolicy::First);
+        testee.push(4, &[5, 6], true).unwrap();
+        assert!(!testee.is_complete());
+        assert_eq!(0, testee.len());
+        testee.push(2, &[3, 4], false).unwrap();
+        assert!(!testee.is_complete());
+        testee.push(0, &[1, 2], false).unwrap();
+        assert!(testee.is_complete());
+        assert_eq!(vec![1, 2, 3, 4, 5, 6], testee.into_vec());
+    }
+
+    #[test]
+    fn duplicate() {
+        let mut testee = Buffer::new(100, OverlapPolicy::Reject);
+        testee.push(0, &[1, 2], false).unwrap();
+        testee.push(0, &[1, 2], false).unwrap();
+        testee.push(2, &[3], true).unwrap();
+        assert!(testee.is_complete());
+        assert_eq!(vec![1, 2, 3], testee.into_vec());
+    }
+
+    #[test]
+    fn overlap_first() {
+        let mut testee = Buffer::new(100, OverlapPolicy::First);
+        testee.push(2, &[3, 4], false).unwrap();
+        testee.push(0, &[1, 2, 9, 9, 5], true).unwrap();
+        assert!(testee.is_complete());
+        assert_eq!(vec![1, 2, 3, 4, 5], testee.into_vec());
+    }
+
+    #[test]
+    fn overlap_last() {
+        let mut testee = Buffer::new(100, OverlapPolicy::Last);
+        testee.push(2, &[3, 4], false).unwrap();
+        testee.push(0, &[1, 2, 9, 9, 5], true).unwrap();
+        assert!(testee.is_complete());
+        assert_eq!(vec![1, 2, 9, 9, 5], testee.into_vec());
+    }
+
+    #[test]
+    fn overlap_reject() {
+        let mut testee = Buffer::new(100, OverlapPolicy::Reject);
+        testee.push(2, &[3, 4], false).unwrap();
+        assert_eq!(Err(BufferError::Overlap), testee.push(0, &[1, 2, 9, 9, 5], true));
+        testee.push(0, &[1, 2, 3, 4, 5], true).unwrap();
+        assert!(testee.is_complete());
+        assert_eq!(vec![1, 2, 3, 4, 5], testee.into_vec());
+    }
+
+    #[test]
+    fn out_of_bounds() {
+        let mut testee = Buffer::new(4, OverlapPolicy::First);
+        assert_eq!(Err(BufferError::OutOfBounds), testee.push(2, &[1, 2, 3], false));
+        testee.push(0, &[1, 2], true).unwrap();
+        assert_eq!(Err(BufferError::OutOfBounds), testee.push(1, &[2, 3], false));
+    }
+
+    #[test]
+    fn invalid_end() {
+        let mut testee = Buffer::new(100, OverlapPolicy::First);
+        testee.push(4, &[5, 6], false).unwrap();
+        assert_eq!(Err(BufferError::InvalidEnd), testee.push(0, &[1, 2], true));
+        testee.push(6, &[7], true).unwrap();
+        assert_eq!(Err(BufferError::InvalidEnd), testee.push(5, &[6], true));
+    }
 }
@@ -1,8 +1,12 @@
 use std::borrow::Borrow;
 use std::collections::HashMap;
+use std::collections::hash_map;
 use std::hash::Hash;
 use std::time::{Duration, Instant};
 
+/// A `HashMap` where every value carries the time it was inserted or last
+/// refreshed. Values older than the timeout are hidden from lookups and can be
+/// purged with `expire`.
 pub struct CacheMap<K, V> {
     map: HashMap<K, (Instant, V)>,
     timeout: Duration,
@@ -18,6 +22,14 @@ impl<K, V> CacheMap<K, V>
         }
     }
 
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    pub fn set_timeout(&mut self, timeout: Duration) {
+        self.timeout = timeout;
+    }
+
     pub fn get<Q: ?Sized>(&mut self, k: &Q) -> Option<&V>
         where K: Borrow<Q>,
               Q: Hash + Eq
@@ -48,8 +60,89 @@ impl<K, V> CacheMap<K, V>
         }
     }
 
-    pub fn insert(&mut self, k: K, v: V) {
-        self.map.insert(k, (Instant::now(), v));
+    /// Returns how long ago the value for `k` was inserted or refreshed.
+    /// Also returns the age of values that have timed out but not yet been
+    /// purged.
+    pub fn age<Q: ?Sized>(&self, k: &Q) -> Option<Duration>
+        where K: Borrow<Q>,
+              Q: Hash + Eq
+    {
+        self.map.get(k).map(|&(ref i, _)| i.elapsed())
+    }
+
+    /// Resets the age of the value for `k` if it exists and has not timed
+    /// out. Returns `true` if a value was refreshed.
+    pub fn refresh<Q: ?Sized>(&mut self, k: &Q) -> bool
+        where K: Borrow<Q>,
+              Q: Hash + Eq
+    {
+        let timeout = self.timeout;
+        if let Some(&mut (ref mut i, _)) = self.map.get_mut(k) {
+            if i.elapsed() < timeout {
+                *i = Instant::now();
+                return true;
+            }
+        }
+        false
+    }
+
+    /// Inserts `v` under `k` with a fresh timestamp. Returns the value
+    /// previously stored under `k`, timed out or not.
+    pub fn insert(&mut self, k: K, v: V) -> Option<V> {
+        self.map.insert(k, (Instant::now(), v)).map(|(_, v)| v)
+    }
+
+    pub fn remove<Q: ?Sized>(&mut self, k: &Q) -> Option<V>
+        where K: Borrow<Q>,
+              Q: Hash + Eq
+    {
+        self.map.remove(k).map(|(_, v)| v)
+    }
+
+    /// Returns an iterator over all keys, ages and values, including values
+    /// that have timed out but not yet been purged.
+    pub fn iter(&self) -> Iter<K, V> {
+        Iter { inner: self.map.iter() }
+    }
+
+    pub fn len(&self) -> usize {
+        self.map.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.map.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.map.clear();
+    }
+
+    /// Removes all values that have timed out and returns their keys.
+    pub fn expire(&mut self) -> Vec<K>
+        where K: Clone
+    {
+        let timeout = self.timeout;
+        let expired = self.map
+            .iter()
+            .filter(|&(_, &(ref i, _))| i.elapsed() >= timeout)
+            .map(|(k, _)| k.clone())
+            .collect::<Vec<K>>();
+        for k in &expired {
+            self.map.remove(k);
+        }
+        expired
+    }
+}
+
+pub struct Iter<'a, K: 'a, V: 'a> {
+    inner: hash_map::Iter<'a, K, (Instant, V)>,
+}
+
+impl<'a, K, V> Iterator for Iter<'a, K, V> {
+    type Item = (&'a K, Duration, &'a V);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.inner.next().map(|(k, &(ref i, ref v))| (k, i.elapsed(), v))
     }
 }
 
@@ -91,4 +184,33 @@ mod tests {
         assert!(testee.get(&0).is_none());
         assert!(testee.get(&1).is_none());
     }
+
+    #[test]
+    fn refresh() {
+        let mut testee = CacheMap::new(Duration::new(0, 100_000_000));
+        testee.insert(0, 15);
+        sleep(Duration::new(0, 60_000_000));
+        assert!(testee.refresh(&0));
+        assert!(!testee.refresh(&1));
+
+        sleep(Duration::new(0, 60_000_000));
+        assert_eq!(testee.get(&0), Some(&15));
+
+        sleep(Duration::new(0, 60_000_000));
+        assert!(!testee.refresh(&0));
+        assert!(testee.get(&0).is_none());
+    }
+
+    #[test]
+    fn expire() {
+        let mut testee = CacheMap::new(Duration::new(0, 50_000_000));
+        testee.insert(0, 15);
+        assert!(testee.expire().is_empty());
+
+        sleep(Duration::new(0, 60_000_000));
+        testee.insert(1, 99);
+        assert_eq!(testee.expire(), vec![0]);
+        assert!(testee.age(&0).is_none());
+        assert!(testee.age(&1).is_some());
+    }
 }
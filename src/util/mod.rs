@@ -1,12 +1,11 @@
 use std::io;
 use std::net::{SocketAddr, ToSocketAddrs};
 
-// mod cachemap;
-// pub use util::cachemap::CacheMap;
-
 mod buffer;
+mod cachemap;
 
-pub use util::buffer::Buffer;
+pub use util::buffer::{Buffer, OverlapPolicy};
+pub use util::cachemap::CacheMap;
 
 pub fn first_socket_addr<A: ToSocketAddrs>(addr: A) -> io::Result<SocketAddr> {
     if let Some(addr) = try!(addr.to_socket_addrs()).next() {
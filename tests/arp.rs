@@ -2,17 +2,21 @@ extern crate pnet;
 extern crate ipnetwork;
 extern crate rips;
 #[macro_use]
+extern crate assert_matches;
+#[macro_use]
 extern crate lazy_static;
 
 use ipnetwork::Ipv4Network;
 
 use pnet::packet::{MutablePacket, Packet};
-use pnet::packet::arp::{ArpPacket, MutableArpPacket, ArpOperations};
+use pnet::packet::arp::{ArpHardwareTypes, ArpPacket, MutableArpPacket, ArpOperations};
 use pnet::packet::ethernet::{EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ipv4::Ipv4Packet;
 
-use rips::Tx;
-use rips::arp::ArpPayload;
-use rips::ethernet::{EtherTypes, MacAddr};
+use rips::{CustomPayload, Interface, StackError, Tx};
+use rips::arp::{ArpPayload, ArpProbeConfig, ArpRequestConfig};
+use rips::ethernet::{EtherTypes, EthernetFields, MacAddr};
+use rips::ipv4::{IpNextHeaderProtocols, Ipv4Fields};
 
 use std::io;
 use std::net::Ipv4Addr;
@@ -47,6 +51,22 @@ fn arp_invalidate_on_update() {
     assert!(arp_request_tx.send(&mut payload).is_none());
 }
 
+#[test]
+fn arp_remove_invalidate_tx() {
+    let mac = MacAddr::new(9, 8, 7, 6, 5, 4);
+
+    let mut dummy = helper::dummy_stack();
+    let stack_interface = dummy.stack.interface(&dummy.interface).unwrap();
+    stack_interface.arp_table().insert_static(*DST_IP, mac);
+
+    let mut tx = stack_interface.ethernet_tx(mac);
+    let mut payload = CustomPayload::new(EthernetFields(EtherTypes::Arp), &[]);
+    tx.send(&mut payload).expect("Invalid Tx").expect("Send error");
+
+    assert_eq!(Some(mac), stack_interface.arp_table().remove(*DST_IP));
+    assert!(tx.send(&mut payload).is_none());
+}
+
 #[test]
 fn arp_reply_to_request() {
     let net = Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 1), 24).unwrap();
@@ -63,6 +83,99 @@ fn arp_reply_to_request() {
     assert_eq!(ArpOperations::Reply, arp_request.get_operation());
 }
 
+#[test]
+fn arp_learn_from_request() {
+    let sender_ip = Ipv4Addr::new(10, 0, 0, 2);
+    let net = Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 3), 24).unwrap();
+
+    let mut dummy = helper::dummy_stack();
+    dummy.stack.add_ipv4(&dummy.interface, net).unwrap();
+
+    // Request not for us from an unknown host, should not be learned
+    send_arp_request(dummy.inject_handle.clone());
+    thread::sleep(Duration::from_millis(100));
+    {
+        let arp_table = dummy.stack.interface(&dummy.interface).unwrap().arp_table();
+        assert!(arp_table.lookup(sender_ip).is_none());
+    }
+
+    dummy.stack.add_ipv4(&dummy.interface, Ipv4Network::new(*DST_IP, 24).unwrap()).unwrap();
+    send_arp_request(dummy.inject_handle);
+    thread::sleep(Duration::from_millis(100));
+    let arp_table = dummy.stack.interface(&dummy.interface).unwrap().arp_table();
+    assert_eq!(Some(MacAddr::new(9, 8, 7, 6, 5, 4)), arp_table.lookup(sender_ip));
+}
+
+#[test]
+fn arp_drop_malformed() {
+    let mut dummy = helper::dummy_stack();
+    dummy.stack.add_ipv4(&dummy.interface, Ipv4Network::new(*DST_IP, 24).unwrap()).unwrap();
+
+    let mut buffer = vec![0; EthernetPacket::minimum_packet_size() + 10];
+    MutableEthernetPacket::new(&mut buffer[..]).unwrap().set_ethertype(EtherTypes::Arp);
+    dummy.inject_handle.send(Ok(buffer.into_boxed_slice())).unwrap();
+
+    // The rx thread must survive the malformed frame and answer the next request
+    send_arp_request(dummy.inject_handle);
+    thread::sleep(Duration::from_millis(100));
+    let reply_u8 = dummy.read_handle.try_recv().expect("Expected Arp reply");
+    let reply_eth = EthernetPacket::new(&reply_u8[..]).unwrap();
+    let reply = ArpPacket::new(reply_eth.payload()).unwrap();
+    assert_eq!(ArpOperations::Reply, reply.get_operation());
+}
+
+#[test]
+fn proxy_arp_network() {
+    let net = Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 0), 30).unwrap();
+
+    let mut dummy = helper::dummy_stack();
+    let mac = dummy.interface.mac;
+    dummy.stack.interface(&dummy.interface).unwrap().add_proxy_arp_network(net);
+
+    send_arp_request(dummy.inject_handle.clone());
+    thread::sleep(Duration::from_millis(100));
+
+    let reply_u8 = dummy.read_handle.try_recv().expect("Expected proxy Arp reply");
+    let reply_eth = EthernetPacket::new(&reply_u8[..]).unwrap();
+    let reply = ArpPacket::new(reply_eth.payload()).unwrap();
+    assert_eq!(ArpOperations::Reply, reply.get_operation());
+    assert_eq!(mac, reply.get_sender_hw_addr());
+    assert_eq!(Ipv4Addr::new(10, 0, 0, 1), reply.get_sender_proto_addr());
+
+    assert!(dummy.stack.interface(&dummy.interface).unwrap().remove_proxy_arp_network(net));
+    send_arp_request(dummy.inject_handle);
+    thread::sleep(Duration::from_millis(100));
+    assert!(dummy.read_handle.try_recv().is_err());
+}
+
+#[test]
+fn proxy_arp_routed() {
+    let mut dummy = helper::dummy_stack();
+    let other_ethernet = helper::dummy_ethernet();
+    let other_interface = Interface {
+        name: "other0".to_owned(),
+        mac: MacAddr::new(1, 1, 1, 1, 1, 1),
+    };
+    dummy.stack.add_interface(other_interface.clone(), other_ethernet.channel).unwrap();
+    dummy.stack.interface(&dummy.interface).unwrap().set_proxy_arp_routed(true);
+
+    // Not routed anywhere, should not be answered
+    send_arp_request(dummy.inject_handle.clone());
+    thread::sleep(Duration::from_millis(100));
+    assert!(dummy.read_handle.try_recv().is_err());
+
+    let net = Ipv4Network::new(Ipv4Addr::new(10, 0, 0, 1), 32).unwrap();
+    dummy.stack.routing_table().add_route(net, None, other_interface);
+
+    send_arp_request(dummy.inject_handle);
+    thread::sleep(Duration::from_millis(100));
+    let reply_u8 = dummy.read_handle.try_recv().expect("Expected proxy Arp reply");
+    let reply_eth = EthernetPacket::new(&reply_u8[..]).unwrap();
+    let reply = ArpPacket::new(reply_eth.payload()).unwrap();
+    assert_eq!(ArpOperations::Reply, reply.get_operation());
+    assert_eq!(dummy.interface.mac, reply.get_sender_hw_addr());
+}
+
 #[test]
 fn arp_locking() {
     let thread_count = 100;
@@ -116,6 +229,209 @@ fn arp_locking() {
     assert!(arp_thread_rx.try_recv().is_err());
 }
 
+#[test]
+fn arp_resolve_timeout() {
+    let net = Ipv4Network::new(*SRC_IP, 24).unwrap();
+    let (drop_tx, drop_rx) = mpsc::channel();
+
+    let mut dummy = helper::dummy_stack();
+    dummy.stack.add_ipv4(&dummy.interface, net).unwrap();
+    {
+        let stack_interface = dummy.stack.interface(&dummy.interface).unwrap();
+        stack_interface.set_arp_request_config(ArpRequestConfig {
+            retransmit_time: Duration::from_millis(100),
+            max_retries: 2,
+            timeout: Duration::from_millis(500),
+            max_queued_packets: 8,
+        });
+        stack_interface.set_pending_drop_callback(move |ip, count, error| {
+            drop_tx.send((ip, count, error)).unwrap();
+        });
+    }
+
+    // Sending should not block while the destination is being resolved
+    let mut ipv4_tx = dummy.stack.ipv4_tx(*DST_IP).unwrap();
+    let mut payload = CustomPayload::new(Ipv4Fields(IpNextHeaderProtocols::Udp), &[1, 2, 3]);
+    ipv4_tx.send(&mut payload).expect("Invalid Tx").expect("Send error");
+    thread::sleep(Duration::from_millis(700));
+
+    // The first request and two retransmissions should have been sent, but no
+    // IPv4 packet
+    for _ in 0..3 {
+        let arp_request_u8 = dummy.read_handle.try_recv().expect("Expected Arp request");
+        let arp_request_eth = EthernetPacket::new(&arp_request_u8[..]).unwrap();
+        assert_eq!(EtherTypes::Arp, arp_request_eth.get_ethertype());
+        let arp_request = ArpPacket::new(arp_request_eth.payload()).unwrap();
+        assert_eq!(ArpOperations::Request, arp_request.get_operation());
+        assert_eq!(*DST_IP, arp_request.get_target_proto_addr());
+    }
+    assert!(dummy.read_handle.try_recv().is_err());
+
+    let (ip, count, error) = drop_rx.try_recv().expect("Expected drop callback");
+    assert_eq!(*DST_IP, ip);
+    assert_eq!(1, count);
+    assert_matches!(error, StackError::HostUnreachable);
+    // The timeout should invalidate the queueing Tx
+    assert!(ipv4_tx.send(&mut payload).is_none());
+}
+
+#[test]
+fn arp_resolve_flush_queue() {
+    let net = Ipv4Network::new(*SRC_IP, 24).unwrap();
+
+    let mut dummy = helper::dummy_stack();
+    dummy.stack.add_ipv4(&dummy.interface, net).unwrap();
+
+    let mut ipv4_tx = dummy.stack.ipv4_tx(*DST_IP).unwrap();
+    let mut payload = CustomPayload::new(Ipv4Fields(IpNextHeaderProtocols::Udp), &[1, 2, 3]);
+    ipv4_tx.send(&mut payload).expect("Invalid Tx").expect("Send error");
+    thread::sleep(Duration::from_millis(100));
+
+    let arp_request_u8 = dummy.read_handle.try_recv().expect("Expected Arp request");
+    let arp_request_eth = EthernetPacket::new(&arp_request_u8[..]).unwrap();
+    assert_eq!(EtherTypes::Arp, arp_request_eth.get_ethertype());
+    assert!(dummy.read_handle.try_recv().is_err());
+
+    send_arp_reply(dummy.inject_handle);
+    thread::sleep(Duration::from_millis(100));
+
+    let ip_u8 = dummy.read_handle.try_recv().expect("Expected queued packet");
+    let ip_eth = EthernetPacket::new(&ip_u8[..]).unwrap();
+    assert_eq!(EtherTypes::Ipv4, ip_eth.get_ethertype());
+    assert_eq!(MacAddr::new(9, 8, 7, 6, 5, 4), ip_eth.get_destination());
+    assert_eq!(dummy.interface.mac, ip_eth.get_source());
+    let ip_pkg = Ipv4Packet::new(ip_eth.payload()).unwrap();
+    assert_eq!(*DST_IP, ip_pkg.get_destination());
+    assert_eq!([1, 2, 3], ip_pkg.payload()[..3]);
+    assert!(dummy.read_handle.try_recv().is_err());
+
+    // The resolution should invalidate the queueing Tx
+    assert!(ipv4_tx.send(&mut payload).is_none());
+}
+
+#[test]
+fn arp_probe_and_announce() {
+    let net = Ipv4Network::new(*DST_IP, 24).unwrap();
+
+    let mut dummy = helper::dummy_stack();
+    dummy.stack.interface(&dummy.interface).unwrap().set_arp_probe_config(Some(probe_config()));
+    dummy.stack.add_ipv4(&dummy.interface, net).unwrap();
+    thread::sleep(Duration::from_millis(500));
+
+    // Two probes followed by one announcement
+    let unspecified = Ipv4Addr::new(0, 0, 0, 0);
+    for &sender_ip in &[unspecified, unspecified, *DST_IP] {
+        let arp_u8 = dummy.read_handle.try_recv().expect("Expected Arp packet");
+        let arp_eth = EthernetPacket::new(&arp_u8[..]).unwrap();
+        assert_eq!(MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff),
+                   arp_eth.get_destination());
+        let arp = ArpPacket::new(arp_eth.payload()).unwrap();
+        assert_eq!(ArpOperations::Request, arp.get_operation());
+        assert_eq!(dummy.interface.mac, arp.get_sender_hw_addr());
+        assert_eq!(sender_ip, arp.get_sender_proto_addr());
+        assert_eq!(*DST_IP, arp.get_target_proto_addr());
+    }
+    assert!(dummy.read_handle.try_recv().is_err());
+
+    // The address is claimed, so requests for it should be answered
+    send_arp_request(dummy.inject_handle);
+    thread::sleep(Duration::from_millis(100));
+    let arp_reply_u8 = dummy.read_handle.try_recv().expect("Expected Arp reply");
+    let arp_reply_eth = EthernetPacket::new(&arp_reply_u8[..]).unwrap();
+    let arp_reply = ArpPacket::new(arp_reply_eth.payload()).unwrap();
+    assert_eq!(ArpOperations::Reply, arp_reply.get_operation());
+    assert!(!dummy.stack.interface(&dummy.interface).unwrap().is_tentative(*DST_IP));
+}
+
+#[test]
+fn arp_probe_tentative() {
+    let net = Ipv4Network::new(*DST_IP, 24).unwrap();
+    let dst = Ipv4Addr::new(10, 0, 0, 2);
+
+    let mut dummy = helper::dummy_stack();
+    dummy.stack.interface(&dummy.interface).unwrap().set_arp_probe_config(Some(ArpProbeConfig {
+        probe_interval: Duration::from_millis(300),
+        ..probe_config()
+    }));
+    dummy.stack.add_ipv4(&dummy.interface, net).unwrap();
+    thread::sleep(Duration::from_millis(50));
+
+    // The address can not be used as source while probing
+    {
+        let stack_interface = dummy.stack.interface(&dummy.interface).unwrap();
+        assert!(stack_interface.is_tentative(*DST_IP));
+        assert!(stack_interface.ipv4_tx(dst, None, None).is_err());
+    }
+
+    // After a conflict it stays tentative and unusable
+    send_arp_reply(dummy.inject_handle);
+    thread::sleep(Duration::from_millis(800));
+    {
+        let stack_interface = dummy.stack.interface(&dummy.interface).unwrap();
+        assert!(stack_interface.is_tentative(*DST_IP));
+        assert!(stack_interface.ipv4_tx(dst, None, None).is_err());
+    }
+    assert!(dummy.stack.add_ipv4(&dummy.interface, net).is_err());
+
+    // Until it's removed and added again, without any conflict this time
+    dummy.stack.remove_ipv4(&dummy.interface, *DST_IP).unwrap();
+    assert!(!dummy.stack.interface(&dummy.interface).unwrap().is_tentative(*DST_IP));
+    assert!(dummy.stack.remove_ipv4(&dummy.interface, *DST_IP).is_err());
+    dummy.stack.add_ipv4(&dummy.interface, net).unwrap();
+    thread::sleep(Duration::from_millis(1000));
+    let stack_interface = dummy.stack.interface(&dummy.interface).unwrap();
+    assert!(!stack_interface.is_tentative(*DST_IP));
+    assert!(stack_interface.ipv4_tx(dst, None, None).is_ok());
+}
+
+#[test]
+fn arp_probe_conflict() {
+    let net = Ipv4Network::new(*DST_IP, 24).unwrap();
+    let (conflict_tx, conflict_rx) = mpsc::channel();
+
+    let mut dummy = helper::dummy_stack();
+    {
+        let stack_interface = dummy.stack.interface(&dummy.interface).unwrap();
+        stack_interface.set_arp_probe_config(Some(ArpProbeConfig {
+            probe_interval: Duration::from_millis(300),
+            ..probe_config()
+        }));
+        stack_interface.set_address_conflict_callback(move |ip, mac| {
+            conflict_tx.send((ip, mac)).unwrap();
+        });
+    }
+    dummy.stack.add_ipv4(&dummy.interface, net).unwrap();
+    thread::sleep(Duration::from_millis(50));
+
+    // Another host replies for the address we are probing for
+    send_arp_reply(dummy.inject_handle.clone());
+    thread::sleep(Duration::from_millis(100));
+    let (ip, mac) = conflict_rx.try_recv().expect("Expected conflict callback");
+    assert_eq!(*DST_IP, ip);
+    assert_eq!(MacAddr::new(9, 8, 7, 6, 5, 4), mac);
+
+    // Probing should stop and the address should never be claimed
+    thread::sleep(Duration::from_millis(800));
+    send_arp_request(dummy.inject_handle);
+    thread::sleep(Duration::from_millis(100));
+    let probe_u8 = dummy.read_handle.try_recv().expect("Expected first probe");
+    let probe_eth = EthernetPacket::new(&probe_u8[..]).unwrap();
+    let probe = ArpPacket::new(probe_eth.payload()).unwrap();
+    assert_eq!(Ipv4Addr::new(0, 0, 0, 0), probe.get_sender_proto_addr());
+    assert!(dummy.read_handle.try_recv().is_err());
+}
+
+fn probe_config() -> ArpProbeConfig {
+    ArpProbeConfig {
+        probe_wait: Duration::from_millis(0),
+        probe_num: 2,
+        probe_interval: Duration::from_millis(100),
+        announce_wait: Duration::from_millis(100),
+        announce_num: 1,
+        announce_interval: Duration::from_millis(100),
+    }
+}
+
 fn send_arp_reply(inject_handle: mpsc::Sender<io::Result<Box<[u8]>>>) {
     // Send the response back to librips
     let mut buffer = vec![0; EthernetPacket::minimum_packet_size() +
@@ -124,6 +440,10 @@ fn send_arp_reply(inject_handle: mpsc::Sender<io::Result<Box<[u8]>>>) {
         let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
         eth_pkg.set_ethertype(EtherTypes::Arp);
         let mut arp_pkg = MutableArpPacket::new(eth_pkg.payload_mut()).unwrap();
+        arp_pkg.set_hardware_type(ArpHardwareTypes::Ethernet);
+        arp_pkg.set_protocol_type(EtherTypes::Ipv4);
+        arp_pkg.set_hw_addr_len(6);
+        arp_pkg.set_proto_addr_len(4);
         arp_pkg.set_operation(ArpOperations::Reply);
         arp_pkg.set_sender_hw_addr(MacAddr::new(9, 8, 7, 6, 5, 4));
         arp_pkg.set_sender_proto_addr(Ipv4Addr::new(10, 0, 0, 1));
@@ -138,6 +458,10 @@ fn send_arp_request(inject_handle: mpsc::Sender<io::Result<Box<[u8]>>>) {
         let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
         eth_pkg.set_ethertype(EtherTypes::Arp);
         let mut arp_pkg = MutableArpPacket::new(eth_pkg.payload_mut()).unwrap();
+        arp_pkg.set_hardware_type(ArpHardwareTypes::Ethernet);
+        arp_pkg.set_protocol_type(EtherTypes::Ipv4);
+        arp_pkg.set_hw_addr_len(6);
+        arp_pkg.set_proto_addr_len(4);
         arp_pkg.set_operation(ArpOperations::Request);
         arp_pkg.set_sender_hw_addr(MacAddr::new(9, 8, 7, 6, 5, 4));
         arp_pkg.set_sender_proto_addr(Ipv4Addr::new(10, 0, 0, 2));
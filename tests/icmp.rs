@@ -10,7 +10,8 @@ use pnet::packet::ipv4::Ipv4Packet;
 
 use rips::{Payload, CustomPayload};
 use rips::ethernet::{EthernetBuilder, MacAddr};
-use rips::icmp::{IcmpFields, IcmpBuilder, IcmpListener, IcmpTypes, EchoCodes};
+use rips::icmp::{DestinationUnreachableCodes, IcmpFields, IcmpBuilder, IcmpListener, IcmpTypes,
+                 EchoCodes};
 use rips::ipv4::{Ipv4Builder, IpNextHeaderProtocols};
 
 use std::net::Ipv4Addr;
@@ -68,3 +69,68 @@ fn recv_icmp() {
     assert_eq!(icmp_pkg.get_icmp_type(), IcmpTypes::EchoRequest);
     assert_eq!(EchoCodes::NoCode, icmp_pkg.get_icmp_code());
 }
+
+#[test]
+fn recv_icmp_wildcard() {
+    let mac = MacAddr::new(0, 0, 0, 0, 0, 0);
+    let remote_ip = Ipv4Addr::new(10, 1, 2, 3);
+    let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+    let local_net = Ipv4Network::new(local_ip, 24).unwrap();
+
+    let (tx, rx) = mpsc::channel();
+    let listener = MockIcmpListener { tx: tx };
+
+    let mut dummy = helper::dummy_stack();
+    let wildcard = Ipv4Addr::new(0, 0, 0, 0);
+    dummy.stack.icmp_listen(wildcard, IcmpTypes::EchoRequest, listener).unwrap();
+    dummy.stack.add_ipv4(&dummy.interface, local_net).unwrap();
+
+    let mut payload = CustomPayload::new(IcmpFields::echo_request(), &[6, 5]);
+    let mut icmp_builder = IcmpBuilder::new(&mut payload);
+    let mut ipv4_builder = Ipv4Builder::new(remote_ip, local_ip, 1500, &mut icmp_builder);
+    let mut eth_builder = EthernetBuilder::new(mac, mac, &mut ipv4_builder);
+    let mut buffer = vec![0; eth_builder.packet_size()];
+    eth_builder.build(&mut buffer);
+
+    dummy.inject_handle.send(Ok(buffer.into_boxed_slice())).unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    let pkg = rx.try_recv().expect("No packet received");
+    let ip_pkg = Ipv4Packet::new(&pkg[..]).unwrap();
+    assert_eq!(ip_pkg.get_destination(), local_ip);
+}
+
+#[test]
+fn recv_icmp_exact_and_wildcard() {
+    let mac = MacAddr::new(0, 0, 0, 0, 0, 0);
+    let remote_ip = Ipv4Addr::new(10, 1, 2, 3);
+    let local_ip = Ipv4Addr::new(10, 0, 0, 2);
+    let local_net = Ipv4Network::new(local_ip, 24).unwrap();
+
+    let (exact_tx, exact_rx) = mpsc::channel();
+    let (wildcard_tx, wildcard_rx) = mpsc::channel();
+
+    let mut dummy = helper::dummy_stack();
+    dummy.stack.add_ipv4(&dummy.interface, local_net).unwrap();
+    let wildcard = Ipv4Addr::new(0, 0, 0, 0);
+    let icmp_type = IcmpTypes::DestinationUnreachable;
+    dummy.stack.icmp_listen(local_ip, icmp_type, MockIcmpListener { tx: exact_tx }).unwrap();
+    dummy.stack.icmp_listen(wildcard, icmp_type, MockIcmpListener { tx: wildcard_tx }).unwrap();
+
+    let code = DestinationUnreachableCodes::DestinationPortUnreachable;
+    let mut payload = CustomPayload::new(IcmpFields::destination_unreachable(code), &[0; 28]);
+    let mut icmp_builder = IcmpBuilder::new(&mut payload);
+    let mut ipv4_builder = Ipv4Builder::new(remote_ip, local_ip, 1500, &mut icmp_builder);
+    let mut eth_builder = EthernetBuilder::new(mac, mac, &mut ipv4_builder);
+    let mut buffer = vec![0; eth_builder.packet_size()];
+    eth_builder.build(&mut buffer);
+
+    dummy.inject_handle.send(Ok(buffer.into_boxed_slice())).unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    // Both listeners get the message, once each
+    exact_rx.try_recv().expect("No packet received on the address");
+    wildcard_rx.try_recv().expect("No packet received on 0.0.0.0");
+    assert!(exact_rx.try_recv().is_err());
+    assert!(wildcard_rx.try_recv().is_err());
+}
@@ -8,16 +8,21 @@ use ipnetwork::Ipv4Network;
 
 use pnet::packet::{MutablePacket, Packet};
 use pnet::packet::ethernet::{EthernetPacket, MutableEthernetPacket};
+use pnet::packet::icmp::IcmpPacket;
 use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet, checksum};
 
-use rips::{rx, NetworkStack, DatalinkTx, CustomPayload, Tx};
+use rips::{rx, Interface, NetworkStack, DatalinkTx, CustomPayload, Tx};
 use rips::ethernet::{EthernetRx, EthernetTx, MacAddr, EtherTypes};
-use rips::ipv4::{BasicIpv4Listener, Ipv4Fields, Ipv4Rx, Ipv4Tx, IpNextHeaderProtocols};
+use rips::icmp::{DestinationUnreachableCodes, IcmpType, IcmpTypes, TimeExceededCodes};
+use rips::ipv4::{BasicIpv4Listener, GlobalCounter, Ipv4Fields, Ipv4Rx, Ipv4Tx, IpNextHeaderProtocol,
+                 IpNextHeaderProtocols, DONT_FRAGMENT, MORE_FRAGMENTS, RawIpv4Socket,
+                 ReassemblyConfig};
 
 use std::collections::HashMap;
 use std::net::Ipv4Addr;
 use std::sync::{Arc, Mutex, mpsc};
-use std::sync::mpsc::Receiver;
+use std::io;
+use std::sync::mpsc::{Receiver, Sender};
 use std::thread;
 use std::time::Duration;
 
@@ -53,8 +58,10 @@ fn simple_send() {
 }
 
 struct DummyIpv4Tx {
-    _stack: NetworkStack,
+    stack: NetworkStack,
+    interface: Interface,
     tx: Ipv4Tx<EthernetTx<DatalinkTx>>,
+    inject_handle: Sender<io::Result<Box<[u8]>>>,
     read_handle: Receiver<Box<[u8]>>,
 }
 
@@ -67,8 +74,10 @@ fn dummy_ipv4_tx(dst_ip: Ipv4Addr, dst_mac: MacAddr) -> DummyIpv4Tx {
     let ipv4_tx = dummy.stack.ipv4_tx(dst_ip).unwrap();
 
     DummyIpv4Tx {
-        _stack: dummy.stack,
+        stack: dummy.stack,
+        interface: dummy.interface,
         tx: ipv4_tx,
+        inject_handle: dummy.inject_handle,
         read_handle: dummy.read_handle,
     }
 }
@@ -98,6 +107,7 @@ fn custom_igmp_recv() {
         let mut eth_pkg = MutableEthernetPacket::new(&mut buffer).unwrap();
         eth_pkg.set_ethertype(EtherTypes::Ipv4);
         let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+        ip_pkg.set_version(4);
         ip_pkg.set_header_length(5); // 5 is for no option fields
         ip_pkg.set_source(*SRC_IP);
         ip_pkg.set_destination(*LAN_DST_IP);
@@ -118,3 +128,314 @@ fn custom_igmp_recv() {
                IpNextHeaderProtocols::Igmp);
     assert_eq!(ip_pkg.payload(), [67, 99]);
 }
+
+#[test]
+fn reassembly_time_exceeded() {
+    let mut dummy = helper::dummy_stack();
+    let net = Ipv4Network::new(*SRC_IP, 24).unwrap();
+    dummy.stack.add_ipv4(&dummy.interface, net).unwrap();
+    dummy.stack.set_default_ttl(17);
+    dummy.stack.interface(&dummy.interface).unwrap().set_reassembly_config(ReassemblyConfig {
+        timeout: Duration::from_millis(50),
+        max_memory: 64 * 1024,
+        send_time_exceeded: true,
+    });
+
+    // Inject only the first fragment of a packet
+    let size = EthernetPacket::minimum_packet_size() + Ipv4Packet::minimum_packet_size() + 16;
+    let mut buffer = vec![0; size];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer).unwrap();
+        eth_pkg.set_source(*LAN_DST_MAC);
+        eth_pkg.set_ethertype(EtherTypes::Ipv4);
+        let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+        ip_pkg.set_version(4);
+        ip_pkg.set_header_length(5);
+        ip_pkg.set_source(*LAN_DST_IP);
+        ip_pkg.set_destination(*SRC_IP);
+        ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        ip_pkg.set_flags(MORE_FRAGMENTS);
+        ip_pkg.set_total_length(20 + 16);
+        ip_pkg.set_payload(&[7; 16]);
+        let csum = checksum(&ip_pkg.to_immutable());
+        ip_pkg.set_checksum(csum);
+    }
+    dummy.inject_handle.send(Ok(buffer.into_boxed_slice())).unwrap();
+    thread::sleep(Duration::from_millis(300));
+
+    let pkg = dummy.read_handle.try_recv().expect("Expected Icmp Time Exceeded");
+    let eth_pkg = EthernetPacket::new(&pkg).unwrap();
+    assert_eq!(*LAN_DST_MAC, eth_pkg.get_destination());
+    let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+    assert_eq!(*SRC_IP, ip_pkg.get_source());
+    assert_eq!(*LAN_DST_IP, ip_pkg.get_destination());
+    assert_eq!(17, ip_pkg.get_ttl());
+    assert_eq!(IpNextHeaderProtocols::Icmp, ip_pkg.get_next_level_protocol());
+    let icmp_pkg = IcmpPacket::new(ip_pkg.payload()).unwrap();
+    assert_eq!(IcmpTypes::TimeExceeded, icmp_pkg.get_icmp_type());
+    assert_eq!(TimeExceededCodes::FragmentReasemblyTimeExceeded,
+               icmp_pkg.get_icmp_code());
+    // Unused header field, then the Ip header and 8 bytes of the fragment
+    assert_eq!(4 + 20 + 8, icmp_pkg.payload().len());
+    assert!(dummy.read_handle.try_recv().is_err());
+
+    let stats = dummy.stack.interface(&dummy.interface).unwrap().reassembly_stats();
+    assert_eq!(1, stats.timeouts);
+}
+
+#[test]
+fn path_mtu_discovery() {
+    let mut dummy = dummy_ipv4_tx(*LAN_DST_IP, *LAN_DST_MAC);
+    let data = vec![9; 1000];
+
+    let mut payload = CustomPayload::new(Ipv4Fields(IpNextHeaderProtocols::Udp), &data);
+    dummy.tx.send(&mut payload).unwrap().unwrap();
+    let pkg = dummy.read_handle.try_recv().unwrap();
+    let eth_pkg = EthernetPacket::new(&pkg).unwrap();
+    let sent_ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+    assert_eq!(DONT_FRAGMENT, sent_ip_pkg.get_flags());
+
+    // A router reports that the packet was too large for its next hop
+    let icmp_size = 8 + 20 + 8;
+    let size = EthernetPacket::minimum_packet_size() + 20 + icmp_size;
+    let mut buffer = vec![0; size];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer).unwrap();
+        eth_pkg.set_source(*LAN_DST_MAC);
+        eth_pkg.set_ethertype(EtherTypes::Ipv4);
+        let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+        ip_pkg.set_version(4);
+        ip_pkg.set_header_length(5);
+        ip_pkg.set_source(*LAN_DST_IP);
+        ip_pkg.set_destination(*SRC_IP);
+        ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Icmp);
+        ip_pkg.set_total_length((20 + icmp_size) as u16);
+        {
+            let icmp_data = ip_pkg.payload_mut();
+            icmp_data[0] = IcmpTypes::DestinationUnreachable.0;
+            icmp_data[1] = DestinationUnreachableCodes::FragmentationRequiredAndDFFlagSet.0;
+            icmp_data[6] = (576 >> 8) as u8;
+            icmp_data[7] = (576 & 0xff) as u8;
+            icmp_data[8..].copy_from_slice(&sent_ip_pkg.packet()[..20 + 8]);
+        }
+        let csum = checksum(&ip_pkg.to_immutable());
+        ip_pkg.set_checksum(csum);
+    }
+    dummy.inject_handle.send(Ok(buffer.into_boxed_slice())).unwrap();
+    thread::sleep(Duration::from_millis(100));
+
+    // Existing tx-objects are invalidated and new ones use the path MTU
+    assert!(dummy.tx.send(&mut payload).is_none());
+    assert_eq!(Some(576),
+               dummy.stack.interface(&dummy.interface).unwrap().path_mtu_cache().get(*LAN_DST_IP));
+    let mut tx = dummy.stack.ipv4_tx(*LAN_DST_IP).unwrap();
+    tx.send(&mut payload).unwrap().unwrap();
+    let pkg1 = dummy.read_handle.try_recv().unwrap();
+    let pkg2 = dummy.read_handle.try_recv().unwrap();
+    assert!(dummy.read_handle.try_recv().is_err());
+    let eth_pkg = EthernetPacket::new(&pkg1).unwrap();
+    let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+    assert_eq!(MORE_FRAGMENTS, ip_pkg.get_flags());
+    assert!(ip_pkg.get_total_length() <= 576);
+    let eth_pkg = EthernetPacket::new(&pkg2).unwrap();
+    let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+    assert!(ip_pkg.get_total_length() <= 576);
+}
+
+#[test]
+fn identification_generator() {
+    let mut dummy = dummy_ipv4_tx(*LAN_DST_IP, *LAN_DST_MAC);
+    // Replacing the generator also affects existing tx-objects
+    dummy.stack.set_identification_generator(GlobalCounter::new(1000));
+
+    for &expected in &[1000, 1001] {
+        let mut payload = CustomPayload::new(Ipv4Fields(IpNextHeaderProtocols::Udp), &[1, 2]);
+        dummy.tx.send(&mut payload).unwrap().expect("Error while sending");
+        let pkg = dummy.read_handle.try_recv().unwrap();
+        let eth_pkg = EthernetPacket::new(&pkg).unwrap();
+        let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+        assert_eq!(expected, ip_pkg.get_identification());
+    }
+
+    // Datagrams the stack sends itself use the same generator
+    dummy.stack.join_multicast_v4(Ipv4Addr::new(224, 0, 0, 251), *SRC_IP).unwrap();
+    let pkg = dummy.read_handle.try_recv().unwrap();
+    let eth_pkg = EthernetPacket::new(&pkg).unwrap();
+    let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+    assert_eq!(IpNextHeaderProtocols::Igmp, ip_pkg.get_next_level_protocol());
+    assert_eq!(1002, ip_pkg.get_identification());
+}
+
+#[test]
+fn forwarding() {
+    let mut dummy = helper::dummy_stack();
+    let net = Ipv4Network::new(*SRC_IP, 24).unwrap();
+    dummy.stack.add_ipv4(&dummy.interface, net).unwrap();
+    dummy.stack.interface(&dummy.interface).unwrap().arp_table().insert(*LAN_DST_IP, *LAN_DST_MAC);
+
+    let other_ethernet = helper::dummy_ethernet();
+    let other_interface = Interface {
+        name: "other0".to_owned(),
+        mac: MacAddr::new(1, 1, 1, 1, 1, 1),
+    };
+    let other_dst_ip = Ipv4Addr::new(192, 168, 0, 2);
+    let other_dst_mac = MacAddr::new(2, 2, 2, 2, 2, 2);
+    dummy.stack.add_interface(other_interface.clone(), other_ethernet.channel).unwrap();
+    let other_net = Ipv4Network::new(Ipv4Addr::new(192, 168, 0, 1), 24).unwrap();
+    dummy.stack.add_ipv4(&other_interface, other_net).unwrap();
+    {
+        let other = dummy.stack.interface(&other_interface).unwrap();
+        other.arp_table().insert(other_dst_ip, other_dst_mac);
+        assert!(other.set_mtu(67).is_err());
+        other.set_mtu(68).unwrap();
+    }
+
+    // Nothing is forwarded until forwarding is enabled
+    let mac = dummy.interface.mac;
+    let udp = IpNextHeaderProtocols::Udp;
+    let frame = create_ipv4_frame(mac, *LAN_DST_IP, other_dst_ip, 5, udp, &[7; 16]);
+    dummy.inject_handle.send(Ok(frame.clone().into_boxed_slice())).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert!(other_ethernet.read_handle.try_recv().is_err());
+
+    // Frames not sent to the MAC of the interface are not forwarded
+    dummy.stack.set_forwarding(true);
+    let broadcast_mac = MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff);
+    let broadcast_frame =
+        create_ipv4_frame(broadcast_mac, *LAN_DST_IP, other_dst_ip, 5, udp, &[7; 16]);
+    dummy.inject_handle.send(Ok(broadcast_frame.into_boxed_slice())).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert!(other_ethernet.read_handle.try_recv().is_err());
+
+    dummy.inject_handle.send(Ok(frame.into_boxed_slice())).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let pkg = other_ethernet.read_handle.try_recv().expect("Expected forwarded packet");
+    let eth_pkg = EthernetPacket::new(&pkg).unwrap();
+    assert_eq!(other_dst_mac, eth_pkg.get_destination());
+    assert_eq!(other_interface.mac, eth_pkg.get_source());
+    let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+    assert_eq!(*LAN_DST_IP, ip_pkg.get_source());
+    assert_eq!(other_dst_ip, ip_pkg.get_destination());
+    assert_eq!(4, ip_pkg.get_ttl());
+    assert_eq!(checksum(&ip_pkg), ip_pkg.get_checksum());
+    assert_eq!(&[7; 16], &ip_pkg.payload()[..16]);
+
+    // Packets larger than the MTU of the egress interface are fragmented
+    let frame = create_ipv4_frame(mac, *LAN_DST_IP, other_dst_ip, 5, udp, &[8; 56]);
+    dummy.inject_handle.send(Ok(frame.into_boxed_slice())).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let pkg1 = other_ethernet.read_handle.try_recv().expect("Expected first fragment");
+    let pkg2 = other_ethernet.read_handle.try_recv().expect("Expected second fragment");
+    let ip_pkg = Ipv4Packet::new(&pkg1[EthernetPacket::minimum_packet_size()..]).unwrap();
+    assert_eq!(MORE_FRAGMENTS, ip_pkg.get_flags());
+    assert_eq!(20 + 48, ip_pkg.get_total_length());
+    let ip_pkg = Ipv4Packet::new(&pkg2[EthernetPacket::minimum_packet_size()..]).unwrap();
+    assert_eq!(6, ip_pkg.get_fragment_offset());
+    assert_eq!(20 + 8, ip_pkg.get_total_length());
+
+    // Packets about to expire are answered with Time Exceeded
+    let frame = create_ipv4_frame(mac, *LAN_DST_IP, other_dst_ip, 1, udp, &[7; 16]);
+    dummy.inject_handle.send(Ok(frame.into_boxed_slice())).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    assert!(other_ethernet.read_handle.try_recv().is_err());
+    let icmp_type = read_icmp_error(&dummy.read_handle);
+    assert_eq!(IcmpTypes::TimeExceeded, icmp_type);
+
+    // Packets without a route are answered with Destination Unreachable
+    let unrouted_ip = Ipv4Addr::new(172, 16, 0, 1);
+    let frame = create_ipv4_frame(mac, *LAN_DST_IP, unrouted_ip, 5, udp, &[7; 16]);
+    dummy.inject_handle.send(Ok(frame.into_boxed_slice())).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let icmp_type = read_icmp_error(&dummy.read_handle);
+    assert_eq!(IcmpTypes::DestinationUnreachable, icmp_type);
+}
+
+#[test]
+fn raw_socket() {
+    let second_ip = Ipv4Addr::new(10, 0, 0, 4);
+    let mut dummy = helper::dummy_stack();
+    let net = Ipv4Network::new(*SRC_IP, 24).unwrap();
+    dummy.stack.add_ipv4(&dummy.interface, net).unwrap();
+    let second_net = Ipv4Network::new(second_ip, 24).unwrap();
+    dummy.stack.add_ipv4(&dummy.interface, second_net).unwrap();
+    dummy.stack.interface(&dummy.interface).unwrap().arp_table().insert(*LAN_DST_IP, *LAN_DST_MAC);
+    let stack = Arc::new(Mutex::new(dummy.stack));
+
+    let vrrp = IpNextHeaderProtocol(112);
+    let mut socket = RawIpv4Socket::bind(stack.clone(), *SRC_IP, vrrp).unwrap();
+    let e = RawIpv4Socket::bind(stack.clone(), *SRC_IP, vrrp).err().unwrap();
+    assert_eq!(io::ErrorKind::AddrInUse, e.kind());
+    let e = RawIpv4Socket::bind(stack.clone(), *SRC_IP, IpNextHeaderProtocols::Udp).err().unwrap();
+    assert_eq!(io::ErrorKind::AddrInUse, e.kind());
+
+    let mac = dummy.interface.mac;
+    let frame = create_ipv4_frame(mac, *LAN_DST_IP, *SRC_IP, 255, vrrp, &[1, 2, 3, 4]);
+    dummy.inject_handle.send(Ok(frame.into_boxed_slice())).unwrap();
+    let ip_pkg = socket.recv().unwrap();
+    assert_eq!(*LAN_DST_IP, ip_pkg.get_source());
+    assert_eq!(vrrp, ip_pkg.get_next_level_protocol());
+    assert_eq!(&[1, 2, 3, 4], &ip_pkg.payload()[..4]);
+
+    socket.set_ttl(255).unwrap();
+    assert_eq!(3, socket.send_to(&[5, 6, 7], *LAN_DST_IP).unwrap());
+    let pkg = dummy.read_handle.try_recv().unwrap();
+    let eth_pkg = EthernetPacket::new(&pkg).unwrap();
+    assert_eq!(*LAN_DST_MAC, eth_pkg.get_destination());
+    let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+    assert_eq!(*SRC_IP, ip_pkg.get_source());
+    assert_eq!(vrrp, ip_pkg.get_next_level_protocol());
+    assert_eq!(255, ip_pkg.get_ttl());
+    assert_eq!(&[5, 6, 7], ip_pkg.payload());
+
+    // The protocol is free to bind again when the socket is dropped
+    drop(socket);
+    RawIpv4Socket::bind(stack.clone(), *SRC_IP, vrrp).unwrap();
+
+    // Packets are sent from the address the socket is bound to
+    let mut socket = RawIpv4Socket::bind(stack, second_ip, vrrp).unwrap();
+    socket.send_to(&[5, 6, 7], *LAN_DST_IP).unwrap();
+    let pkg = dummy.read_handle.try_recv().unwrap();
+    let ip_pkg = Ipv4Packet::new(&pkg[EthernetPacket::minimum_packet_size()..]).unwrap();
+    assert_eq!(second_ip, ip_pkg.get_source());
+}
+
+fn create_ipv4_frame(dst_mac: MacAddr,
+                     src: Ipv4Addr,
+                     dst: Ipv4Addr,
+                     ttl: u8,
+                     protocol: IpNextHeaderProtocol,
+                     payload: &[u8])
+                     -> Vec<u8> {
+    let ip_size = Ipv4Packet::minimum_packet_size() + payload.len();
+    let mut buffer = vec![0; EthernetPacket::minimum_packet_size() + ip_size];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer).unwrap();
+        eth_pkg.set_destination(dst_mac);
+        eth_pkg.set_source(*LAN_DST_MAC);
+        eth_pkg.set_ethertype(EtherTypes::Ipv4);
+        let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+        ip_pkg.set_version(4);
+        ip_pkg.set_header_length(5);
+        ip_pkg.set_source(src);
+        ip_pkg.set_destination(dst);
+        ip_pkg.set_ttl(ttl);
+        ip_pkg.set_next_level_protocol(protocol);
+        ip_pkg.set_total_length(ip_size as u16);
+        ip_pkg.set_payload(payload);
+        let csum = checksum(&ip_pkg.to_immutable());
+        ip_pkg.set_checksum(csum);
+    }
+    buffer
+}
+
+fn read_icmp_error(read_handle: &Receiver<Box<[u8]>>) -> IcmpType {
+    let pkg = read_handle.try_recv().expect("Expected Icmp error");
+    let eth_pkg = EthernetPacket::new(&pkg).unwrap();
+    assert_eq!(*LAN_DST_MAC, eth_pkg.get_destination());
+    let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+    assert_eq!(*SRC_IP, ip_pkg.get_source());
+    assert_eq!(*LAN_DST_IP, ip_pkg.get_destination());
+    assert_eq!(IpNextHeaderProtocols::Icmp, ip_pkg.get_next_level_protocol());
+    let icmp_pkg = IcmpPacket::new(ip_pkg.payload()).unwrap();
+    icmp_pkg.get_icmp_type()
+}
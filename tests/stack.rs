@@ -34,3 +34,35 @@ fn routing_table_change_invalidate_tx() {
     }
     assert_matches!(tx.send(&mut payload), None);
 }
+
+#[test]
+fn source_address_selection() {
+    let primary = Ipv4Addr::new(10, 0, 0, 3);
+    let secondary = Ipv4Addr::new(10, 0, 0, 4);
+    let gw = Ipv4Addr::new(10, 0, 0, 1);
+    let dummy = helper::dummy_stack();
+    let mut testee = dummy.stack;
+    testee.add_ipv4(&dummy.interface, Ipv4Network::new(primary, 16).unwrap()).unwrap();
+    testee.add_ipv4(&dummy.interface, Ipv4Network::new(secondary, 24).unwrap()).unwrap();
+    assert_eq!(Some(primary), testee.interface(&dummy.interface).unwrap().primary_ipv4());
+    {
+        let mut routing_table = testee.routing_table();
+        routing_table.add_default_route(Some(gw), dummy.interface.clone());
+        let net = Ipv4Network::new(Ipv4Addr::new(192, 168, 0, 0), 16).unwrap();
+        routing_table.add_route_with_src(net, Some(gw), dummy.interface.clone(), primary);
+    }
+
+    // The address in the network with the longest prefix is preferred
+    assert_eq!(secondary, testee.ipv4_tx(Ipv4Addr::new(10, 0, 0, 9)).unwrap().src());
+    assert_eq!(primary, testee.ipv4_tx(Ipv4Addr::new(10, 0, 5, 9)).unwrap().src());
+    // Through a gateway the address on the same network as the gateway is used
+    assert_eq!(secondary, testee.ipv4_tx(Ipv4Addr::new(8, 8, 8, 8)).unwrap().src());
+    // Unless the route says otherwise
+    assert_eq!(primary, testee.ipv4_tx(Ipv4Addr::new(192, 168, 0, 1)).unwrap().src());
+    // The primary address is used when no network matches
+    assert_eq!(primary,
+               testee.ipv4_tx(Ipv4Addr::new(255, 255, 255, 255)).unwrap().src());
+    testee.interface(&dummy.interface).unwrap().set_primary_ipv4(secondary).unwrap();
+    assert_eq!(secondary,
+               testee.ipv4_tx(Ipv4Addr::new(255, 255, 255, 255)).unwrap().src());
+}
@@ -4,17 +4,24 @@ extern crate rips;
 
 use ipnetwork::Ipv4Network;
 
-use pnet::packet::MutablePacket;
-use pnet::packet::ethernet::MutableEthernetPacket;
-use pnet::packet::ipv4::{MutableIpv4Packet, checksum};
+use pnet::packet::{MutablePacket, Packet};
+use pnet::packet::ethernet::{EthernetPacket, MutableEthernetPacket};
+use pnet::packet::ipv4::{Ipv4Packet, MutableIpv4Packet, checksum};
 use pnet::packet::udp::MutableUdpPacket;
+use pnet::util;
 
-use rips::ethernet::EtherTypes;
-use rips::ipv4::IpNextHeaderProtocols;
-use rips::udp::UdpSocket;
+use rips::{CustomPayload, Payload};
+use rips::ethernet::{EtherTypes, EthernetBuilder, MacAddr};
+use rips::icmp::{DestinationUnreachableCodes, IcmpBuilder, IcmpFields};
+use rips::ipv4::{IpNextHeaderProtocols, Ipv4Builder};
+use rips::udp::{PacketInfo, UdpRecvHalf, UdpSendHalf, UdpSocket};
 
+use std::io;
 use std::net::{Ipv4Addr, SocketAddr, SocketAddrV4};
 use std::sync::{Arc, Mutex};
+use std::sync::mpsc::Receiver;
+use std::thread;
+use std::time::Duration;
 
 mod helper;
 
@@ -35,6 +42,7 @@ fn socket_listen() {
         let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
         eth_pkg.set_ethertype(EtherTypes::Ipv4);
         let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+        ip_pkg.set_version(4);
         ip_pkg.set_header_length(5); // 5 is for no option fields
         ip_pkg.set_total_length(20 + 8 + 4);
         ip_pkg.set_source(source_ip);
@@ -57,3 +65,566 @@ fn socket_listen() {
     assert_eq!(&buffer, &[5, 6, 7, 8]);
 
 }
+
+#[test]
+fn socket_send_ttl_tos() {
+    let local_ip = Ipv4Addr::new(10, 9, 0, 254);
+    let target_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let target_mac = MacAddr::new(9, 0, 0, 4, 0, 0);
+    let local_net = Ipv4Network::new(local_ip, 16).unwrap();
+
+    let mut dummy = helper::dummy_stack();
+    dummy.stack.add_ipv4(&dummy.interface, local_net).unwrap();
+    dummy.stack.interface(&dummy.interface).unwrap().arp_table().insert(target_ip, target_mac);
+    dummy.stack.set_default_ttl(10);
+    let stack = Arc::new(Mutex::new(dummy.stack));
+
+    let mut socket = UdpSocket::bind(stack, "10.9.0.254:1024").unwrap();
+    assert_eq!(10, socket.ttl().unwrap());
+    socket.send_to(&[1, 2], "10.9.0.1:9999").unwrap();
+    let pkg = dummy.read_handle.try_recv().unwrap();
+    let eth_pkg = EthernetPacket::new(&pkg).unwrap();
+    let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+    assert_eq!(10, ip_pkg.get_ttl());
+    assert_eq!(0, ip_pkg.get_dscp());
+    assert_eq!(0, ip_pkg.get_ecn());
+
+    socket.set_ttl(3).unwrap();
+    socket.set_tos((46 << 2) | 0b01).unwrap();
+    assert_eq!((46 << 2) | 0b01, socket.tos().unwrap());
+    assert!(socket.set_ttl(256).is_err());
+    socket.send_to(&[1, 2], "10.9.0.1:9999").unwrap();
+    let pkg = dummy.read_handle.try_recv().unwrap();
+    let eth_pkg = EthernetPacket::new(&pkg).unwrap();
+    let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+    assert_eq!(3, ip_pkg.get_ttl());
+    assert_eq!(46, ip_pkg.get_dscp());
+    assert_eq!(0b01, ip_pkg.get_ecn());
+}
+
+#[test]
+fn socket_broadcast() {
+    let local_ip = Ipv4Addr::new(10, 9, 0, 254);
+    let local_net = Ipv4Network::new(local_ip, 16).unwrap();
+
+    let mut dummy = helper::dummy_stack();
+    dummy.stack.add_ipv4(&dummy.interface, local_net).unwrap();
+    let stack = Arc::new(Mutex::new(dummy.stack));
+
+    let mut socket = UdpSocket::bind(stack, "10.9.0.254:1024").unwrap();
+    assert!(!socket.broadcast().unwrap());
+    for dst in &["10.9.255.255:9999", "255.255.255.255:9999"] {
+        let e = socket.send_to(&[1, 2], dst).unwrap_err();
+        assert_eq!(io::ErrorKind::PermissionDenied, e.kind());
+    }
+    assert!(dummy.read_handle.try_recv().is_err());
+
+    socket.set_broadcast(true).unwrap();
+    for &dst in &[Ipv4Addr::new(10, 9, 255, 255), Ipv4Addr::new(255, 255, 255, 255)] {
+        socket.send_to(&[1, 2], SocketAddrV4::new(dst, 9999)).unwrap();
+        let pkg = dummy.read_handle.try_recv().unwrap();
+        let eth_pkg = EthernetPacket::new(&pkg).unwrap();
+        assert_eq!(MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff),
+                   eth_pkg.get_destination());
+        let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+        assert_eq!(local_ip, ip_pkg.get_source());
+        assert_eq!(dst, ip_pkg.get_destination());
+    }
+
+    // Broadcasts to the network are received by the socket
+    let source_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let mut buffer = vec![0; 100];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+        eth_pkg.set_destination(MacAddr::new(0xff, 0xff, 0xff, 0xff, 0xff, 0xff));
+        eth_pkg.set_ethertype(EtherTypes::Ipv4);
+        let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+        ip_pkg.set_version(4);
+        ip_pkg.set_header_length(5);
+        ip_pkg.set_total_length(20 + 8 + 4);
+        ip_pkg.set_source(source_ip);
+        ip_pkg.set_destination(Ipv4Addr::new(10, 9, 255, 255));
+        ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        let csum = checksum(&ip_pkg.to_immutable());
+        ip_pkg.set_checksum(csum);
+        let mut udp_pkg = MutableUdpPacket::new(ip_pkg.payload_mut()).unwrap();
+        udp_pkg.set_source(9999);
+        udp_pkg.set_destination(1024);
+        udp_pkg.set_length(8 + 4);
+        udp_pkg.set_payload(&[5, 6, 7, 8]);
+    }
+    dummy.inject_handle.send(Ok(buffer.into_boxed_slice())).unwrap();
+
+    let mut buffer = vec![0; 4];
+    let (len, from) = socket.recv_from(&mut buffer[..]).unwrap();
+    assert_eq!(from, SocketAddr::V4(SocketAddrV4::new(source_ip, 9999)));
+    assert_eq!(len, 4);
+    assert_eq!(&buffer, &[5, 6, 7, 8]);
+}
+
+#[test]
+fn socket_multicast() {
+    let local_ip = Ipv4Addr::new(10, 9, 0, 254);
+    let local_net = Ipv4Network::new(local_ip, 16).unwrap();
+    let group = Ipv4Addr::new(224, 0, 0, 251);
+
+    let mut dummy = helper::dummy_stack();
+    dummy.stack.add_ipv4(&dummy.interface, local_net).unwrap();
+    let stack = Arc::new(Mutex::new(dummy.stack));
+
+    let mut socket = UdpSocket::bind(stack, "10.9.0.254:5353").unwrap();
+    socket.join_multicast_v4(&group, &local_ip).unwrap();
+    assert!(socket.join_multicast_v4(&group, &local_ip).is_err());
+
+    // Joining is reported with an IGMPv3 report
+    let igmp = read_igmp(&dummy.read_handle, Ipv4Addr::new(224, 0, 0, 22));
+    assert_eq!(&[0x22, 0], &igmp[..2]);
+    assert_eq!(&[0, 1, 4, 0, 0, 0, 224, 0, 0, 251], &igmp[6..16]);
+
+    // The report is sent once more within the Unsolicited Report Interval
+    thread::sleep(Duration::from_millis(1200));
+    assert_eq!(igmp, read_igmp(&dummy.read_handle, Ipv4Addr::new(224, 0, 0, 22)));
+    assert!(dummy.read_handle.try_recv().is_err());
+
+    // Multicasts to the group are received
+    let source_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let mut buffer = vec![0; 100];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+        eth_pkg.set_destination(MacAddr::new(0x01, 0x00, 0x5e, 0, 0, 0xfb));
+        eth_pkg.set_ethertype(EtherTypes::Ipv4);
+        let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+        ip_pkg.set_version(4);
+        ip_pkg.set_header_length(5);
+        ip_pkg.set_total_length(20 + 8 + 4);
+        ip_pkg.set_ttl(1);
+        ip_pkg.set_source(source_ip);
+        ip_pkg.set_destination(group);
+        ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        let csum = checksum(&ip_pkg.to_immutable());
+        ip_pkg.set_checksum(csum);
+        let mut udp_pkg = MutableUdpPacket::new(ip_pkg.payload_mut()).unwrap();
+        udp_pkg.set_source(5353);
+        udp_pkg.set_destination(5353);
+        udp_pkg.set_length(8 + 4);
+        udp_pkg.set_payload(&[5, 6, 7, 8]);
+    }
+    dummy.inject_handle.send(Ok(buffer.into_boxed_slice())).unwrap();
+    let mut buffer = vec![0; 4];
+    let (len, from) = socket.recv_from(&mut buffer[..]).unwrap();
+    assert_eq!(from, SocketAddr::V4(SocketAddrV4::new(source_ip, 5353)));
+    assert_eq!(len, 4);
+    assert_eq!(&buffer, &[5, 6, 7, 8]);
+
+    // Multicasts are sent to the mapped MAC with a TTL of one
+    socket.send_to(&[1, 2], SocketAddrV4::new(group, 5353)).unwrap();
+    let pkg = dummy.read_handle.try_recv().unwrap();
+    let eth_pkg = EthernetPacket::new(&pkg).unwrap();
+    assert_eq!(MacAddr::new(0x01, 0x00, 0x5e, 0, 0, 0xfb), eth_pkg.get_destination());
+    let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+    assert_eq!(group, ip_pkg.get_destination());
+    assert_eq!(1, ip_pkg.get_ttl());
+
+    // IGMPv2 queries are answered with IGMPv2 reports within the max
+    // response time of 100 ms
+    let mut query = [0x11, 1, 0, 0, 0, 0, 0, 0];
+    let csum = util::checksum(&query, 1);
+    query[2] = (csum >> 8) as u8;
+    query[3] = csum as u8;
+    let mut buffer = vec![0; 14 + 20 + 8];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+        eth_pkg.set_ethertype(EtherTypes::Ipv4);
+        let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+        ip_pkg.set_version(4);
+        ip_pkg.set_header_length(5);
+        ip_pkg.set_total_length(20 + 8);
+        ip_pkg.set_ttl(1);
+        ip_pkg.set_source(source_ip);
+        ip_pkg.set_destination(Ipv4Addr::new(224, 0, 0, 1));
+        ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Igmp);
+        ip_pkg.set_payload(&query);
+        let csum = checksum(&ip_pkg.to_immutable());
+        ip_pkg.set_checksum(csum);
+    }
+    dummy.inject_handle.send(Ok(buffer.into_boxed_slice())).unwrap();
+    thread::sleep(Duration::from_millis(300));
+    let igmp = read_igmp(&dummy.read_handle, group);
+    assert_eq!(0x16, igmp[0]);
+    assert_eq!(&[224, 0, 0, 251], &igmp[4..8]);
+
+    // The all-systems group can be joined and left, but is never reported
+    let all_systems = Ipv4Addr::new(224, 0, 0, 1);
+    socket.join_multicast_v4(&all_systems, &local_ip).unwrap();
+    socket.leave_multicast_v4(&all_systems, &local_ip).unwrap();
+    assert!(dummy.read_handle.try_recv().is_err());
+
+    // Leaving is reported with an IGMPv2 leave while an IGMPv2 querier is
+    // present
+    socket.leave_multicast_v4(&group, &local_ip).unwrap();
+    let igmp = read_igmp(&dummy.read_handle, Ipv4Addr::new(224, 0, 0, 2));
+    assert_eq!(0x17, igmp[0]);
+    assert_eq!(&[224, 0, 0, 251], &igmp[4..8]);
+    assert!(socket.leave_multicast_v4(&group, &local_ip).is_err());
+}
+
+#[test]
+fn socket_wildcard() {
+    let source_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let local_ip = Ipv4Addr::new(10, 9, 0, 254);
+    let later_ip = Ipv4Addr::new(10, 9, 0, 253);
+
+    let mut dummy = helper::dummy_stack();
+    dummy.stack.add_ipv4(&dummy.interface, Ipv4Network::new(local_ip, 16).unwrap()).unwrap();
+    let stack = Arc::new(Mutex::new(dummy.stack));
+
+    let socket = UdpSocket::bind(stack.clone(), "0.0.0.0:1024").unwrap();
+    assert_eq!(SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::new(0, 0, 0, 0), 1024)),
+               socket.local_addr().unwrap());
+    let e = UdpSocket::bind(stack.clone(), "10.9.0.254:1024").unwrap_err();
+    assert_eq!(io::ErrorKind::AddrInUse, e.kind());
+    let e = UdpSocket::bind(stack.clone(), "0.0.0.0:1024").unwrap_err();
+    assert_eq!(io::ErrorKind::AddrInUse, e.kind());
+
+    // Addresses added after the bind are also covered
+    {
+        let mut stack = stack.lock().unwrap();
+        stack.add_ipv4(&dummy.interface, Ipv4Network::new(later_ip, 16).unwrap()).unwrap();
+    }
+
+    for &dst in &[local_ip, later_ip] {
+        let frame = create_udp_frame(source_ip, dst, 1024, &[5, 6, 7, 8]);
+        dummy.inject_handle.send(Ok(frame)).unwrap();
+        let mut buffer = vec![0; 4];
+        let (len, from, info) = socket.recv_from_info(&mut buffer[..]).unwrap();
+        assert_eq!(from, SocketAddr::V4(SocketAddrV4::new(source_ip, 9999)));
+        assert_eq!(len, 4);
+        assert_eq!(&buffer, &[5, 6, 7, 8]);
+        let expected = PacketInfo {
+            local_ip: dst,
+            interface: dummy.interface.clone(),
+        };
+        assert_eq!(expected, info);
+    }
+
+    // Sockets bound to the address itself take precedence
+    let exact_socket = UdpSocket::bind(stack.clone(), "10.9.0.254:1025").unwrap();
+    let e = UdpSocket::bind(stack, "0.0.0.0:1025").unwrap_err();
+    assert_eq!(io::ErrorKind::AddrInUse, e.kind());
+    let frame = create_udp_frame(source_ip, local_ip, 1025, &[1, 2]);
+    dummy.inject_handle.send(Ok(frame)).unwrap();
+    let mut buffer = vec![0; 2];
+    assert_eq!(2, exact_socket.recv_from(&mut buffer[..]).unwrap().0);
+}
+
+#[test]
+fn socket_drop() {
+    let source_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let local_ip = Ipv4Addr::new(10, 9, 0, 254);
+    let local_net = Ipv4Network::new(local_ip, 16).unwrap();
+    let group = Ipv4Addr::new(224, 0, 0, 251);
+
+    let mut dummy = helper::dummy_stack();
+    dummy.stack.add_ipv4(&dummy.interface, local_net).unwrap();
+    let stack = Arc::new(Mutex::new(dummy.stack));
+
+    let mut socket = UdpSocket::bind(stack.clone(), "10.9.0.254:1024").unwrap();
+    socket.join_multicast_v4(&group, &local_ip).unwrap();
+    read_igmp(&dummy.read_handle, Ipv4Addr::new(224, 0, 0, 22));
+    thread::sleep(Duration::from_millis(1200));
+    read_igmp(&dummy.read_handle, Ipv4Addr::new(224, 0, 0, 22));
+    let clone = socket.try_clone().unwrap();
+
+    // Dropping the socket leaves its groups, but the clone keeps the port,
+    // also after datagrams arrive that nothing reads
+    drop(socket);
+    let igmp = read_igmp(&dummy.read_handle, Ipv4Addr::new(224, 0, 0, 22));
+    assert_eq!(&[0, 1, 3, 0, 0, 0, 224, 0, 0, 251], &igmp[6..16]);
+    let frame = create_udp_frame(source_ip, local_ip, 1024, &[1, 2, 3, 4]);
+    dummy.inject_handle.send(Ok(frame)).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let e = UdpSocket::bind(stack.clone(), "10.9.0.254:1024").unwrap_err();
+    assert_eq!(io::ErrorKind::AddrInUse, e.kind());
+
+    // The port is released with the last handle
+    drop(clone);
+    let socket = UdpSocket::bind(stack.clone(), "10.9.0.254:1024").unwrap();
+    let frame = create_udp_frame(source_ip, local_ip, 1024, &[5, 6, 7, 8]);
+    dummy.inject_handle.send(Ok(frame)).unwrap();
+    socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    let mut buffer = vec![0; 4];
+    assert_eq!(4, socket.recv(&mut buffer[..]).unwrap());
+    assert_eq!(&buffer, &[5, 6, 7, 8]);
+    let wildcard_socket = UdpSocket::bind(stack.clone(), "0.0.0.0:1025").unwrap();
+    drop(socket);
+    drop(wildcard_socket);
+    UdpSocket::bind(stack.clone(), "0.0.0.0:1024").unwrap();
+    UdpSocket::bind(stack, "0.0.0.0:1025").unwrap();
+}
+
+#[test]
+fn socket_read_timeout() {
+    let source_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let local_ip = Ipv4Addr::new(10, 9, 0, 254);
+    let local_net = Ipv4Network::new(local_ip, 16).unwrap();
+
+    let mut dummy = helper::dummy_stack();
+    dummy.stack.add_ipv4(&dummy.interface, local_net).unwrap();
+    let stack = Arc::new(Mutex::new(dummy.stack));
+
+    let socket = UdpSocket::bind(stack, "10.9.0.254:1024").unwrap();
+    let mut buffer = vec![0; 4];
+
+    socket.set_nonblocking(true).unwrap();
+    let e = socket.recv_from(&mut buffer[..]).unwrap_err();
+    assert_eq!(io::ErrorKind::WouldBlock, e.kind());
+
+    socket.set_nonblocking(false).unwrap();
+    let e = socket.set_read_timeout(Some(Duration::new(0, 0))).unwrap_err();
+    assert_eq!(io::ErrorKind::InvalidInput, e.kind());
+    socket.set_read_timeout(Some(Duration::from_millis(50))).unwrap();
+    assert_eq!(Some(Duration::from_millis(50)), socket.read_timeout().unwrap());
+    let e = socket.recv(&mut buffer[..]).unwrap_err();
+    assert_eq!(io::ErrorKind::TimedOut, e.kind());
+
+    // Peeked datagrams are returned again by the next read
+    let frame = create_udp_frame(source_ip, local_ip, 1024, &[5, 6, 7, 8]);
+    dummy.inject_handle.send(Ok(frame)).unwrap();
+    let from = SocketAddr::V4(SocketAddrV4::new(source_ip, 9999));
+    socket.set_read_timeout(None).unwrap();
+    assert_eq!((4, from), socket.peek_from(&mut buffer[..]).unwrap());
+    assert_eq!((4, from), socket.peek_from(&mut buffer[..]).unwrap());
+    assert_eq!(4, socket.recv(&mut buffer[..]).unwrap());
+    assert_eq!(&buffer, &[5, 6, 7, 8]);
+    socket.set_nonblocking(true).unwrap();
+    let e = socket.peek_from(&mut buffer[..]).unwrap_err();
+    assert_eq!(io::ErrorKind::WouldBlock, e.kind());
+}
+
+#[test]
+fn socket_connect() {
+    let local_ip = Ipv4Addr::new(10, 9, 0, 254);
+    let peer_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let peer_mac = MacAddr::new(9, 0, 0, 4, 0, 0);
+    let local_net = Ipv4Network::new(local_ip, 16).unwrap();
+
+    let mut dummy = helper::dummy_stack();
+    dummy.stack.add_ipv4(&dummy.interface, local_net).unwrap();
+    dummy.stack.interface(&dummy.interface).unwrap().arp_table().insert(peer_ip, peer_mac);
+    let stack = Arc::new(Mutex::new(dummy.stack));
+
+    let mut socket = UdpSocket::bind(stack, "10.9.0.254:1024").unwrap();
+    let e = socket.send(&[1, 2]).unwrap_err();
+    assert_eq!(io::ErrorKind::NotConnected, e.kind());
+    assert!(socket.peer_addr().is_err());
+
+    socket.connect("10.9.0.1:9999").unwrap();
+    let peer = SocketAddr::V4(SocketAddrV4::new(peer_ip, 9999));
+    assert_eq!(peer, socket.peer_addr().unwrap());
+    assert_eq!(2, socket.send(&[1, 2]).unwrap());
+    let pkg = dummy.read_handle.try_recv().unwrap();
+    let eth_pkg = EthernetPacket::new(&pkg).unwrap();
+    assert_eq!(peer_mac, eth_pkg.get_destination());
+    let sent_ip_pkg = eth_pkg.payload()[..20 + 8].to_vec();
+    let ip_pkg = Ipv4Packet::new(&sent_ip_pkg).unwrap();
+    assert_eq!(peer_ip, ip_pkg.get_destination());
+
+    // Only datagrams from the peer are received
+    let other_ip = Ipv4Addr::new(10, 9, 0, 2);
+    let frame = create_udp_frame(other_ip, local_ip, 1024, &[1, 1, 1, 1]);
+    dummy.inject_handle.send(Ok(frame)).unwrap();
+    let frame = create_udp_frame(peer_ip, local_ip, 1024, &[5, 6, 7, 8]);
+    dummy.inject_handle.send(Ok(frame)).unwrap();
+    let mut buffer = vec![0; 4];
+    assert_eq!((4, peer), socket.recv_from(&mut buffer[..]).unwrap());
+    assert_eq!(&buffer, &[5, 6, 7, 8]);
+
+    // Port unreachable about datagrams to other ports of the peer are ignored
+    let mut other_port_pkg = sent_ip_pkg.clone();
+    other_port_pkg[20 + 3] ^= 1;
+    let frame = create_port_unreachable_frame(peer_mac, peer_ip, local_ip, &other_port_pkg);
+    dummy.inject_handle.send(Ok(frame)).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    socket.send(&[1, 2]).unwrap();
+
+    // Port unreachable from the peer is reported once on the next call
+    let frame = create_port_unreachable_frame(peer_mac, peer_ip, local_ip, &sent_ip_pkg);
+    dummy.inject_handle.send(Ok(frame)).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let e = socket.send(&[1, 2]).unwrap_err();
+    assert_eq!(io::ErrorKind::ConnectionRefused, e.kind());
+    socket.send(&[1, 2]).unwrap();
+}
+
+#[test]
+fn socket_connect_wildcard() {
+    let local_ip = Ipv4Addr::new(10, 9, 0, 254);
+    let peer_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let peer_mac = MacAddr::new(9, 0, 0, 4, 0, 0);
+    let local_net = Ipv4Network::new(local_ip, 16).unwrap();
+
+    let mut dummy = helper::dummy_stack();
+    dummy.stack.add_ipv4(&dummy.interface, local_net).unwrap();
+    dummy.stack.interface(&dummy.interface).unwrap().arp_table().insert(peer_ip, peer_mac);
+    let stack = Arc::new(Mutex::new(dummy.stack));
+
+    let mut exact_socket = UdpSocket::bind(stack.clone(), "10.9.0.254:1024").unwrap();
+    let mut wildcard_socket = UdpSocket::bind(stack, "0.0.0.0:1025").unwrap();
+    exact_socket.connect("10.9.0.1:9999").unwrap();
+    wildcard_socket.connect("10.9.0.1:9998").unwrap();
+    exact_socket.send(&[1, 2]).unwrap();
+    let pkg = dummy.read_handle.try_recv().unwrap();
+    let exact_ip_pkg = EthernetPacket::new(&pkg).unwrap().payload()[..20 + 8].to_vec();
+    wildcard_socket.send(&[1, 2]).unwrap();
+    let pkg = dummy.read_handle.try_recv().unwrap();
+    let wildcard_ip_pkg = EthernetPacket::new(&pkg).unwrap().payload()[..20 + 8].to_vec();
+
+    // Each socket only gets the errors about its own datagrams
+    let frame = create_port_unreachable_frame(peer_mac, peer_ip, local_ip, &wildcard_ip_pkg);
+    dummy.inject_handle.send(Ok(frame)).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    exact_socket.send(&[1, 2]).unwrap();
+    let e = wildcard_socket.send(&[1, 2]).unwrap_err();
+    assert_eq!(io::ErrorKind::ConnectionRefused, e.kind());
+
+    let frame = create_port_unreachable_frame(peer_mac, peer_ip, local_ip, &exact_ip_pkg);
+    dummy.inject_handle.send(Ok(frame)).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    wildcard_socket.send(&[1, 2]).unwrap();
+    let e = exact_socket.send(&[1, 2]).unwrap_err();
+    assert_eq!(io::ErrorKind::ConnectionRefused, e.kind());
+
+    // The wildcard socket keeps getting errors after the other socket is gone
+    drop(exact_socket);
+    let frame = create_port_unreachable_frame(peer_mac, peer_ip, local_ip, &exact_ip_pkg);
+    dummy.inject_handle.send(Ok(frame)).unwrap();
+    let frame = create_port_unreachable_frame(peer_mac, peer_ip, local_ip, &wildcard_ip_pkg);
+    dummy.inject_handle.send(Ok(frame)).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let e = wildcard_socket.send(&[1, 2]).unwrap_err();
+    assert_eq!(io::ErrorKind::ConnectionRefused, e.kind());
+}
+
+#[test]
+fn socket_split() {
+    let local_ip = Ipv4Addr::new(10, 9, 0, 254);
+    let peer_ip = Ipv4Addr::new(10, 9, 0, 1);
+    let local_net = Ipv4Network::new(local_ip, 16).unwrap();
+
+    let mut dummy = helper::dummy_stack();
+    dummy.stack.add_ipv4(&dummy.interface, local_net).unwrap();
+    dummy.stack
+        .interface(&dummy.interface)
+        .unwrap()
+        .arp_table()
+        .insert(peer_ip, MacAddr::new(9, 0, 0, 4, 0, 0));
+    let stack = Arc::new(Mutex::new(dummy.stack));
+
+    let socket = UdpSocket::bind(stack.clone(), "10.9.0.254:1024").unwrap();
+    assert!(socket.try_clone().unwrap().split().is_err());
+    let (send_half, recv_half) = socket.split().unwrap();
+
+    let receiver = thread::spawn(move || {
+        let mut buffer = vec![0; 4];
+        let (len, _from) = recv_half.recv_from(&mut buffer[..]).unwrap();
+        (len, buffer)
+    });
+    let senders: Vec<_> = (0..2)
+        .map(|i| {
+            let mut send_half = send_half.clone();
+            thread::spawn(move || send_half.send_to(&[i], "10.9.0.1:9999").unwrap())
+        })
+        .collect();
+    for sender in senders {
+        assert_eq!(1, sender.join().unwrap());
+    }
+    assert!(dummy.read_handle.try_recv().is_ok());
+    assert!(dummy.read_handle.try_recv().is_ok());
+
+    let frame = create_udp_frame(peer_ip, local_ip, 1024, &[5, 6, 7, 8]);
+    dummy.inject_handle.send(Ok(frame)).unwrap();
+    let (len, buffer) = receiver.join().unwrap();
+    assert_eq!(4, len);
+    assert_eq!(&buffer, &[5, 6, 7, 8]);
+
+    // The port is released when both halves are dropped, not when a
+    // datagram arrives for the dropped receive half
+    let frame = create_udp_frame(peer_ip, local_ip, 1024, &[1, 2, 3, 4]);
+    dummy.inject_handle.send(Ok(frame)).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    let e = UdpSocket::bind(stack.clone(), "10.9.0.254:1024").unwrap_err();
+    assert_eq!(io::ErrorKind::AddrInUse, e.kind());
+    drop(send_half);
+    let socket = UdpSocket::bind(stack, "10.9.0.254:1024").unwrap();
+    let frame = create_udp_frame(peer_ip, local_ip, 1024, &[5, 6, 7, 8]);
+    dummy.inject_handle.send(Ok(frame)).unwrap();
+    socket.set_read_timeout(Some(Duration::from_millis(100))).unwrap();
+    let mut buffer = vec![0; 4];
+    assert_eq!(4, socket.recv(&mut buffer[..]).unwrap());
+    assert_eq!(&buffer, &[5, 6, 7, 8]);
+}
+
+#[test]
+fn socket_halves_are_send() {
+    fn assert_send<T: Send>() {}
+    assert_send::<UdpSendHalf>();
+    assert_send::<UdpRecvHalf>();
+}
+
+/// Creates an ethernet frame with a Udp datagram from port 9999 on `src` to
+/// `dst_port` on `dst`.
+fn create_udp_frame(src: Ipv4Addr, dst: Ipv4Addr, dst_port: u16, payload: &[u8]) -> Box<[u8]> {
+    let udp_len = 8 + payload.len();
+    let mut buffer = vec![0; 14 + 20 + udp_len];
+    {
+        let mut eth_pkg = MutableEthernetPacket::new(&mut buffer[..]).unwrap();
+        eth_pkg.set_ethertype(EtherTypes::Ipv4);
+        let mut ip_pkg = MutableIpv4Packet::new(eth_pkg.payload_mut()).unwrap();
+        ip_pkg.set_version(4);
+        ip_pkg.set_header_length(5);
+        ip_pkg.set_total_length((20 + udp_len) as u16);
+        ip_pkg.set_ttl(64);
+        ip_pkg.set_source(src);
+        ip_pkg.set_destination(dst);
+        ip_pkg.set_next_level_protocol(IpNextHeaderProtocols::Udp);
+        let csum = checksum(&ip_pkg.to_immutable());
+        ip_pkg.set_checksum(csum);
+        let mut udp_pkg = MutableUdpPacket::new(ip_pkg.payload_mut()).unwrap();
+        udp_pkg.set_source(9999);
+        udp_pkg.set_destination(dst_port);
+        udp_pkg.set_length(udp_len as u16);
+        udp_pkg.set_payload(payload);
+    }
+    buffer.into_boxed_slice()
+}
+
+/// Reads an IGMP message sent to `dst` and returns the IGMP part of it.
+fn read_igmp(read_handle: &Receiver<Box<[u8]>>, dst: Ipv4Addr) -> Vec<u8> {
+    let pkg = read_handle.try_recv().expect("Expected an IGMP message");
+    let eth_pkg = EthernetPacket::new(&pkg).unwrap();
+    let dst_octets = dst.octets();
+    let dst_mac = MacAddr::new(0x01, 0x00, 0x5e, dst_octets[1], dst_octets[2], dst_octets[3]);
+    assert_eq!(dst_mac, eth_pkg.get_destination());
+    let ip_pkg = Ipv4Packet::new(eth_pkg.payload()).unwrap();
+    assert_eq!(dst, ip_pkg.get_destination());
+    assert_eq!(IpNextHeaderProtocols::Igmp, ip_pkg.get_next_level_protocol());
+    assert_eq!(1, ip_pkg.get_ttl());
+    // The header contains the Router Alert option
+    assert_eq!(6, ip_pkg.get_header_length());
+    ip_pkg.payload().to_vec()
+}
+
+fn create_port_unreachable_frame(src_mac: MacAddr,
+                                 src: Ipv4Addr,
+                                 dst: Ipv4Addr,
+                                 orig_pkg: &[u8])
+                                 -> Box<[u8]> {
+    let code = DestinationUnreachableCodes::DestinationPortUnreachable;
+    let mut payload = CustomPayload::new(IcmpFields::destination_unreachable(code), orig_pkg);
+    let mut icmp_builder = IcmpBuilder::new(&mut payload);
+    let mut ipv4_builder = Ipv4Builder::new(src, dst, 1500, &mut icmp_builder);
+    let mut eth_builder = EthernetBuilder::new(src_mac, src_mac, &mut ipv4_builder);
+    let mut buffer = vec![0; eth_builder.packet_size()];
+    eth_builder.build(&mut buffer);
+    buffer.into_boxed_slice()
+}